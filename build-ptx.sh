@@ -0,0 +1,24 @@
+#!/bin/sh
+# Tor V3 Vanity Address Generator - rebuild ptx/kernel.ptx from core/
+# Run this after changing core/; build.rs refuses to embed a stale kernel
+# Prerequisites: Rust nightly with the rust-src component
+
+set -e
+cd "$(dirname "$0")"
+
+TARGET_DIR=target/ptx
+DEPS="$TARGET_DIR/nvptx64-nvidia-cuda/release/deps"
+
+rm -f "$DEPS"/tor_v3_vanity_core-*.s
+# Fat LTO pulls ed25519-compact and core into the one module, so the PTX
+# defines everything the kernel calls
+(cd core && cargo +nightly rustc --release --target nvptx64-nvidia-cuda -Zbuild-std=core \
+    --target-dir "../$TARGET_DIR" --crate-type staticlib -- --emit asm -C lto=fat)
+cp "$DEPS"/tor_v3_vanity_core-*.s ptx/kernel.ptx
+
+# Hash of the sources the kernel was built from, checked by build.rs
+for file in core/Cargo.toml $(ls core/src/*.rs | LC_ALL=C sort); do
+    tr -d '\r' < "$file"
+done | sha256sum | cut -d ' ' -f 1 > ptx/kernel.source-hash
+
+echo "Wrote ptx/kernel.ptx"
@@ -1,5 +1,3 @@
-use std::path::Path;
-
 fn main() {
     // Only run CUDA build steps if the cuda feature is enabled
     #[cfg(feature = "cuda")]
@@ -11,7 +9,7 @@ fn main() {
 #[cfg(feature = "cuda")]
 fn cuda_build() {
     use std::env;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
 
     // Platform-specific CUDA library paths
     #[cfg(target_os = "windows")]
@@ -79,6 +77,8 @@ fn cuda_build() {
     let ptx_path = PathBuf::from(&manifest_dir).join("ptx").join("kernel.ptx");
 
     if ptx_path.exists() {
+        check_ptx_source_hash(Path::new(&manifest_dir));
+
         // Set the environment variable that the CUDA backend expects
         println!("cargo:rustc-env=KERNEL_PTX_PATH={}", ptx_path.display());
         println!("cargo:rerun-if-changed={}", ptx_path.display());
@@ -109,9 +109,46 @@ fn cuda_build() {
             panic!(
                 "Pre-compiled PTX kernel not found at {}.\n\
                 Please copy the kernel.ptx file to the ptx/ directory.\n\
-                You can compile it on Linux with: cargo build -p tor-v3-vanity-core --target nvptx64-nvidia-cuda --release",
+                You can compile it with ./build-ptx.sh (needs Rust nightly with rust-src).",
                 ptx_path.display()
             );
         }
     }
 }
+
+/// Refuse to embed a kernel built from other core/ sources than the current
+/// ones; its parameter layout would not match the host structs
+#[cfg(feature = "cuda")]
+fn check_ptx_source_hash(manifest_dir: &std::path::Path) {
+    use sha2::{Digest, Sha256};
+
+    let core_dir = manifest_dir.join("core");
+    let hash_path = manifest_dir.join("ptx").join("kernel.source-hash");
+    println!("cargo:rerun-if-changed={}", core_dir.join("Cargo.toml").display());
+    println!("cargo:rerun-if-changed={}", core_dir.join("src").display());
+    println!("cargo:rerun-if-changed={}", hash_path.display());
+
+    // Same order and line endings as build-ptx.sh
+    let mut sources: Vec<_> = std::fs::read_dir(core_dir.join("src"))
+        .expect("core/src not found")
+        .map(|entry| entry.expect("failed to read core/src").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+        .collect();
+    sources.sort();
+    sources.insert(0, core_dir.join("Cargo.toml"));
+
+    let mut hasher = Sha256::new();
+    for path in &sources {
+        let contents = std::fs::read(path).unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e));
+        hasher.update(contents.iter().copied().filter(|&byte| byte != b'\r').collect::<Vec<u8>>());
+    }
+    let hash: String = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    let recorded = std::fs::read_to_string(&hash_path).unwrap_or_default();
+    if recorded.trim() != hash {
+        panic!(
+            "ptx/kernel.ptx was not built from the current core/ sources.\n\
+            Rebuild it with ./build-ptx.sh (needs Rust nightly with rust-src)."
+        );
+    }
+}
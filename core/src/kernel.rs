@@ -1,5 +1,6 @@
 use super::KernelParams;
 use byteorder::{ByteOrder, LittleEndian};
+use core::sync::atomic::{AtomicU32, Ordering};
 
 #[inline]
 fn add_u256(base: &[u8; 32], mut offset: u64) -> [u8; 32] {
@@ -34,14 +35,18 @@ pub extern "ptx-kernel" fn render(params_ptr: *mut KernelParams) {
     let s = ed25519_compact::Seed::new(cur_seed);
     let kp = ed25519_compact::KeyPair::from_seed(s);
 
-    let byte_prefixes =
-        unsafe { core::slice::from_raw_parts_mut(params.byte_prefixes.as_raw_mut(), params.byte_prefixes_len) };
-    for byte_prefix in byte_prefixes {
-        if byte_prefix.matches(&*kp.pk) {
-            let out = unsafe { core::slice::from_raw_parts_mut(byte_prefix.out.as_raw_mut(), 32) };
-            out.clone_from_slice(&cur_seed);
-            let success = unsafe { &mut *byte_prefix.success.as_raw_mut() };
-            *success = true;
+    let byte_patterns =
+        unsafe { core::slice::from_raw_parts_mut(params.byte_patterns.as_raw_mut(), params.byte_patterns_len) };
+    for byte_pattern in byte_patterns {
+        if byte_pattern.matches(&*kp.pk) {
+            let hit_count = unsafe { &*(byte_pattern.hit_count.as_raw_mut() as *const AtomicU32) };
+            let slot = hit_count.fetch_add(1, Ordering::Relaxed);
+            if slot < byte_pattern.hit_capacity {
+                let out = unsafe {
+                    core::slice::from_raw_parts_mut(byte_pattern.hits.as_raw_mut().add(slot as usize * 32), 32)
+                };
+                out.clone_from_slice(&cur_seed);
+            }
         }
     }
 }
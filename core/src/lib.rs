@@ -1,38 +1,47 @@
 #![no_std]
-#![cfg_attr(
-    any(target_arch = "nvptx", target_arch = "nvptx64"),
-    feature(abi_ptx, stdarch_nvptx)
-)]
+#![cfg_attr(target_arch = "nvptx64", feature(abi_ptx, stdarch_nvptx))]
 
 use rustacuda_core::DevicePointer;
 use rustacuda_derive::DeviceCopy;
 
-#[cfg(any(target_arch = "nvptx", target_arch = "nvptx64"))]
+#[cfg(target_arch = "nvptx64")]
 mod kernel;
 
 #[derive(DeviceCopy, Clone)]
 #[repr(C)]
 pub struct KernelParams {
     pub seed: DevicePointer<u8>,
-    pub byte_prefixes: DevicePointer<BytePrefix>,
-    pub byte_prefixes_len: usize,
+    pub byte_patterns: DevicePointer<BytePattern>,
+    pub byte_patterns_len: usize,
 }
 
+/// Masked comparison of a window of public key bytes, so patterns can be
+/// anchored anywhere in the address rather than only at its start
+///
+/// Every match of a launch takes the next slot of `hits` through an atomic
+/// increment of `hit_count`, so matches neither overwrite nor tear each
+/// other. `hit_count` keeps counting past `hit_capacity`; those seeds are
+/// dropped.
 #[derive(DeviceCopy, Clone)]
 #[repr(C)]
-pub struct BytePrefix {
-    pub byte_prefix: DevicePointer<u8>,
-    pub byte_prefix_len: usize,
-    pub last_byte_idx: usize,
-    pub last_byte_mask: u8,
-    pub out: DevicePointer<u8>,
-    pub success: DevicePointer<bool>,
+pub struct BytePattern {
+    pub byte_value: DevicePointer<u8>,
+    pub byte_mask: DevicePointer<u8>,
+    pub byte_offset: usize,
+    pub byte_len: usize,
+    /// Room for `hit_capacity` 32-byte seeds
+    pub hits: DevicePointer<u8>,
+    pub hit_capacity: u32,
+    pub hit_count: DevicePointer<u32>,
 }
-impl BytePrefix {
+impl BytePattern {
     pub fn matches(&self, data: &[u8]) -> bool {
-        let slice =
-            unsafe { core::slice::from_raw_parts(self.byte_prefix.as_raw(), self.byte_prefix_len) };
-        data.starts_with(&slice[..self.last_byte_idx])
-            && data[self.last_byte_idx] & self.last_byte_mask == slice[self.last_byte_idx]
+        let value = unsafe { core::slice::from_raw_parts(self.byte_value.as_raw(), self.byte_len) };
+        let mask = unsafe { core::slice::from_raw_parts(self.byte_mask.as_raw(), self.byte_len) };
+        data[self.byte_offset..self.byte_offset + self.byte_len]
+            .iter()
+            .zip(mask)
+            .zip(value)
+            .all(|((byte, mask), value)| byte & mask == *value)
     }
 }
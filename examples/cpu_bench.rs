@@ -0,0 +1,57 @@
+//! Single-thread throughput of the CPU key derivation strategies
+//!
+//! Run with `cargo run --release --no-default-features --example cpu_bench`.
+
+use std::time::{Duration, Instant};
+
+use tor_v3_vanity::backend::{BatchWalker, IncrementalWalker, NORMALIZE_BATCH_SIZE};
+
+const RUN_TIME: Duration = Duration::from_secs(3);
+
+fn report(name: &str, keys: u64, elapsed: Duration) {
+    println!(
+        "{:<12} {:>10.0} keys/sec",
+        name,
+        keys as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    // Seed: SHA-512 + fixed-base scalar multiplication + compression per key
+    let start = Instant::now();
+    let mut keys = 0u64;
+    let mut sink = 0u8;
+    while start.elapsed() < RUN_TIME {
+        for _ in 0..1_000 {
+            let seed: [u8; 32] = rand::random();
+            let pubkey = ed25519_dalek::SigningKey::from_bytes(&seed)
+                .verifying_key()
+                .to_bytes();
+            sink ^= pubkey[0];
+        }
+        keys += 1_000;
+    }
+    report("seed", keys, start.elapsed());
+
+    // Incremental: point addition + compression (one inversion) per key
+    let start = Instant::now();
+    let mut walker = IncrementalWalker::new();
+    while start.elapsed() < RUN_TIME {
+        for _ in 0..1_000 {
+            sink ^= walker.next_key().1[0];
+        }
+    }
+    report("incremental", walker.keys_walked(), start.elapsed());
+
+    // Batched: point addition per key, one inversion per batch
+    let start = Instant::now();
+    let mut walker = BatchWalker::new(NORMALIZE_BATCH_SIZE);
+    while start.elapsed() < RUN_TIME {
+        walker.next_batch();
+        sink ^= walker.pubkeys()[0][0];
+    }
+    report("batched", walker.keys_walked(), start.elapsed());
+
+    // Keep the results observable so nothing is optimised away
+    std::hint::black_box(sink);
+}
@@ -0,0 +1,65 @@
+//! Throughput of the CPU backend as the thread count grows
+//!
+//! Every run searches for a prefix that will never be found, so the numbers
+//! only reflect key derivation and match bookkeeping. With lock-free matching
+//! the per-thread rate should stay flat up to the number of physical cores.
+//!
+//! Run with `cargo run --release --no-default-features --example thread_scaling`.
+
+use std::time::Duration;
+
+use tor_v3_vanity::backend::{CpuBackend, SearchEvent};
+
+const RUN_TIME: Duration = Duration::from_secs(5);
+
+fn measure(threads: usize) -> f64 {
+    let (event_tx, event_rx) = crossbeam_channel::unbounded();
+    let (stop_tx, stop_rx) = crossbeam_channel::bounded(1);
+
+    let handle = std::thread::spawn(move || {
+        CpuBackend::with_threads(threads).generate(
+            vec!["a".repeat(52)],
+            std::env::temp_dir(),
+            event_tx,
+            stop_rx,
+        )
+    });
+
+    std::thread::sleep(RUN_TIME);
+    let _ = stop_tx.send(());
+    let _ = handle.join();
+
+    event_rx
+        .try_iter()
+        .filter_map(|event| match event {
+            SearchEvent::Progress(progress) => Some(progress.keys_per_sec),
+            _ => None,
+        })
+        .last()
+        .unwrap_or(0.0)
+}
+
+fn main() {
+    let max_threads = num_cpus::get();
+    let mut threads = 1;
+    let mut baseline = None;
+
+    println!("{:>8} {:>14} {:>14} {:>10}", "threads", "keys/sec", "per thread", "scaling");
+    while threads <= max_threads {
+        let rate = measure(threads);
+        let single = *baseline.get_or_insert(rate);
+        println!(
+            "{:>8} {:>14.0} {:>14.0} {:>9.0}%",
+            threads,
+            rate,
+            rate / threads as f64,
+            100.0 * rate / (single * threads as f64)
+        );
+
+        threads = if threads == max_threads {
+            break;
+        } else {
+            (threads * 2).min(max_threads)
+        };
+    }
+}
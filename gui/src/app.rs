@@ -1,24 +1,25 @@
 //! Main application state and UI
 
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{Receiver, TryRecvError};
 use eframe::egui;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::thread::JoinHandle;
 use std::time::Instant;
 
 use tor_v3_vanity::backend::{
-    select_backend, select_backend_with_config, BackendInfo, BackendMode, FoundKey,
-    Progress, SearchFilter,
+    select_backend, select_backend_with_config, BackendInfo, BackendMode, FoundKey, GeneratorError,
+    Progress, Quota, SearchEvent, SearchFilter, SearchOutcome, SearchSession,
 };
+use tor_v3_vanity::hs_dir::{KeyFormat, KeyProtection};
+use tor_v3_vanity::pattern::{BytePattern, Pattern, RegexPattern};
 
 /// Application state
 pub struct VanityApp {
     // Input fields
     prefix_input: String,
+    regex_input: String,
     contains_input: String,
     output_dir: String,
+    lookalikes: bool,
 
     // Backend selection
     selected_mode: BackendModeSelection,
@@ -30,13 +31,12 @@ pub struct VanityApp {
 
     // Generation state
     state: AppState,
-    worker_handle: Option<JoinHandle<()>>,
-    stop_flag: Arc<AtomicBool>,
+    session: Option<SearchSession>,
 
     // Channels
-    progress_rx: Option<Receiver<Progress>>,
-    result_rx: Option<Receiver<FoundKey>>,
-    error_rx: Option<Receiver<String>>,
+    event_rx: Option<Receiver<SearchEvent>>,
+    // The session being started, once its backend was chosen
+    session_rx: Option<Receiver<Result<SearchSession, GeneratorError>>>,
 
     // Progress display
     progress: Progress,
@@ -45,9 +45,20 @@ pub struct VanityApp {
 
     // Errors
     error_message: Option<String>,
+    // Backends that were not available and workers that failed
+    notices: Vec<String>,
 
-    // Pending prefixes
-    pending_prefixes: Vec<String>,
+    // Patterns of the current run and the keys found for each
+    run_patterns: Vec<String>,
+    found_counts: Vec<usize>,
+
+    // Keys wanted per pattern
+    keys_per_pattern: usize,
+    keep_going: bool,
+    quota: Quota,
+
+    // Expected keys to check before the rarest pattern fills its quota
+    expected_keys: f64,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -68,7 +79,7 @@ impl BackendModeSelection {
         }
     }
 
-    fn to_backend_mode(&self) -> BackendMode {
+    fn to_backend_mode(self) -> BackendMode {
         match self {
             Self::Auto => BackendMode::Auto,
             Self::Cpu => BackendMode::Cpu,
@@ -82,6 +93,7 @@ impl BackendModeSelection {
 enum AppState {
     Idle,
     Running,
+    Paused,
     Stopped,
     Finished,
 }
@@ -104,23 +116,29 @@ impl VanityApp {
 
         Self {
             prefix_input: String::new(),
+            regex_input: String::new(),
             contains_input: String::new(),
             output_dir,
+            lookalikes: false,
             selected_mode: BackendModeSelection::Auto,
             cpu_threads: max_threads,
             max_threads,
             backend_info,
             state: AppState::Idle,
-            worker_handle: None,
-            stop_flag: Arc::new(AtomicBool::new(false)),
-            progress_rx: None,
-            result_rx: None,
-            error_rx: None,
+            session: None,
+            event_rx: None,
+            session_rx: None,
             progress: Progress::default(),
             results: Vec::new(),
             start_time: None,
             error_message: None,
-            pending_prefixes: Vec::new(),
+            notices: Vec::new(),
+            run_patterns: Vec::new(),
+            found_counts: Vec::new(),
+            keys_per_pattern: 1,
+            keep_going: false,
+            quota: Quota::default(),
+            expected_keys: 0.0,
         }
     }
 
@@ -133,21 +151,41 @@ impl VanityApp {
             .filter(|s| !s.is_empty())
             .collect();
 
-        if prefixes.is_empty() {
-            self.error_message = Some("Please enter at least one prefix".to_string());
+        // Regular expressions may contain commas, so they are space-separated
+        let regex: Vec<String> = self
+            .regex_input
+            .split_whitespace()
+            .map(|s| s.to_lowercase())
+            .collect();
+
+        if prefixes.is_empty() && regex.is_empty() {
+            self.error_message = Some("Please enter at least one prefix or regex".to_string());
             return;
         }
 
-        // Validate each prefix
+        // Validate each pattern; the rarest one sets the expected amount of work
+        let mut min_probability = 1.0_f64;
         for prefix in &prefixes {
-            if base32::decode(
-                base32::Alphabet::Rfc4648Lower { padding: false },
-                &format!("{}aa", prefix),
-            )
-            .is_none()
-            {
-                self.error_message = Some(format!("Invalid base32 prefix: '{}'", prefix));
-                return;
+            let pattern = if self.lookalikes {
+                Pattern::with_lookalikes(prefix)
+            } else {
+                BytePattern::new(prefix).map(Pattern::Bytes)
+            };
+            match pattern {
+                Ok(pattern) => min_probability = min_probability.min(pattern.match_probability()),
+                Err(e) => {
+                    self.error_message = Some(e.to_string());
+                    return;
+                }
+            }
+        }
+        for source in &regex {
+            match RegexPattern::new(source) {
+                Ok(pattern) => min_probability = min_probability.min(pattern.match_probability()),
+                Err(e) => {
+                    self.error_message = Some(e.to_string());
+                    return;
+                }
             }
         }
 
@@ -162,19 +200,26 @@ impl VanityApp {
 
         // Clear state
         self.error_message = None;
+        self.notices.clear();
         self.results.clear();
         self.progress = Progress::default();
-        self.pending_prefixes = prefixes.clone();
-        self.stop_flag.store(false, Ordering::SeqCst);
+        self.run_patterns = prefixes.iter().chain(&regex).cloned().collect();
+        self.found_counts = vec![0; self.run_patterns.len()];
+        self.quota = if self.keep_going {
+            Quota::Unlimited
+        } else {
+            Quota::Count(self.keys_per_pattern.max(1))
+        };
+        // The rarest pattern has to be found once per key wanted
+        self.expected_keys = match self.quota {
+            Quota::Count(count) => count as f64 / min_probability,
+            Quota::Unlimited => 1.0 / min_probability,
+        };
         self.start_time = Some(Instant::now());
 
         // Create channels
-        let (progress_tx, progress_rx) = crossbeam_channel::unbounded();
-        let (result_tx, result_rx) = crossbeam_channel::unbounded();
-        let (stop_tx, stop_rx) = crossbeam_channel::bounded(1);
-
-        self.progress_rx = Some(progress_rx);
-        self.result_rx = Some(result_rx);
+        let (event_tx, event_rx) = crossbeam_channel::unbounded();
+        self.event_rx = Some(event_rx);
 
         // Parse contains filter
         let contains: Vec<String> = self
@@ -184,77 +229,146 @@ impl VanityApp {
             .filter(|s| !s.is_empty())
             .collect();
 
-        let filter = SearchFilter { contains };
-
-        // Spawn worker thread
-        let backend_mode = self.selected_mode.to_backend_mode();
+        // Regular expressions only run on the CPU, so don't pick a GPU for them
+        let backend_mode = match self.selected_mode.to_backend_mode() {
+            BackendMode::Auto if !regex.is_empty() => BackendMode::Cpu,
+            mode => mode,
+        };
+
+        let filter = SearchFilter {
+            contains,
+            regex,
+            lookalikes: self.lookalikes,
+            quota: self.quota,
+            protection: KeyProtection::default(),
+            format: KeyFormat::default(),
+            manifest: None,
+        };
+
+        // Opening a GPU backend can take seconds, so choose it off the UI
+        // thread; the choice comes back as a BackendChosen event
+        let (session_tx, session_rx) = crossbeam_channel::bounded(1);
         let cpu_threads = self.cpu_threads;
-        let stop_flag = self.stop_flag.clone();
-
-        // Channel to report backend errors back to GUI
-        let (error_tx, error_rx) = crossbeam_channel::bounded(1);
-        self.error_rx = Some(error_rx);
-
-        let handle = std::thread::spawn(move || {
-            let backend = select_backend_with_config(backend_mode, cpu_threads);
-
-            // Monitor stop flag
-            let monitor_stop_flag = stop_flag.clone();
-            std::thread::spawn(move || {
-                while !monitor_stop_flag.load(Ordering::Relaxed) {
-                    std::thread::sleep(std::time::Duration::from_millis(100));
-                }
-                let _ = stop_tx.send(());
+        let spawned = std::thread::Builder::new()
+            .name("backend selection".to_string())
+            .spawn(move || {
+                let backend = select_backend_with_config(backend_mode, cpu_threads, &event_tx);
+                // A session nobody waits for any more is stopped when dropped
+                let _ = session_tx.send(SearchSession::start(backend, prefixes, output_dir, filter, event_tx));
             });
-
-            if let Err(e) = backend.generate_with_filter(prefixes, output_dir, progress_tx, result_tx, stop_rx, filter) {
-                let _ = error_tx.send(format!("Generation error: {}", e));
+        match spawned {
+            Ok(_) => {
+                self.session_rx = Some(session_rx);
+                self.state = AppState::Running;
             }
-        });
-
-        self.worker_handle = Some(handle);
-        self.state = AppState::Running;
+            Err(e) => {
+                self.error_message = Some(format!("Generation error: {}", e));
+                self.state = AppState::Stopped;
+            }
+        }
     }
 
     fn stop_generation(&mut self) {
-        self.stop_flag.store(true, Ordering::SeqCst);
+        if let Some(session) = &self.session {
+            session.stop();
+        }
         self.state = AppState::Stopped;
     }
 
-    fn update_from_channels(&mut self) {
-        // Update progress
-        if let Some(rx) = &self.progress_rx {
-            while let Ok(progress) = rx.try_recv() {
-                self.progress = progress;
+    // A session still being started picks up the state once it arrives
+    fn toggle_pause(&mut self) {
+        match self.state {
+            AppState::Running => {
+                if let Some(session) = &self.session {
+                    session.pause();
+                }
+                self.state = AppState::Paused;
             }
-        }
-
-        // Update results
-        if let Some(rx) = &self.result_rx {
-            while let Ok(result) = rx.try_recv() {
-                // Remove from pending
-                self.pending_prefixes.retain(|p| p != &result.prefix);
-                self.results.push(result);
+            AppState::Paused => {
+                if let Some(session) = &self.session {
+                    session.resume();
+                }
+                self.state = AppState::Running;
             }
+            _ => {}
         }
+    }
 
-        // Check for errors from worker
-        if let Some(rx) = &self.error_rx {
-            if let Ok(error) = rx.try_recv() {
-                self.error_message = Some(error);
+    fn receive_session(&mut self) {
+        let received = match &self.session_rx {
+            Some(rx) => rx.try_recv(),
+            None => return,
+        };
+        let started = match received {
+            Err(TryRecvError::Empty) => return,
+            // The selection thread panicked
+            Err(TryRecvError::Disconnected) => Err("backend selection failed".to_string()),
+            Ok(started) => started.map_err(|e| e.to_string()),
+        };
+        self.session_rx = None;
+        match started {
+            Ok(session) => {
+                match self.state {
+                    AppState::Paused => session.pause(),
+                    AppState::Stopped => session.stop(),
+                    _ => {}
+                }
+                self.session = Some(session);
+            }
+            Err(e) => {
+                self.error_message = Some(format!("Generation error: {}", e));
                 self.state = AppState::Stopped;
             }
         }
+    }
+
+    fn update_from_channels(&mut self) {
+        self.receive_session();
+        let events: Vec<SearchEvent> = match &self.event_rx {
+            Some(rx) => rx.try_iter().collect(),
+            None => return,
+        };
+        for event in events {
+            self.handle_event(event);
+        }
 
         // Check if finished
-        if self.state == AppState::Running && self.pending_prefixes.is_empty() {
+        let all_filled = self.found_counts.iter().all(|&hits| self.quota.is_filled(hits));
+        if self.state == AppState::Running && all_filled {
             self.state = AppState::Finished;
         }
+    }
 
-        // Check if worker thread finished
-        if let Some(handle) = &self.worker_handle {
-            if handle.is_finished() && self.state == AppState::Running {
-                self.state = AppState::Stopped;
+    fn handle_event(&mut self, event: SearchEvent) {
+        match event {
+            SearchEvent::BackendChosen(info) => self.backend_info = Some(info),
+            SearchEvent::Fallback { backend, reason } => {
+                self.notices.push(format!("{} not available ({})", backend, reason));
+            }
+            SearchEvent::Progress(progress) => self.progress = progress,
+            SearchEvent::KeyFound(result) => {
+                // Count towards the pattern's quota
+                if let Some(index) = self.run_patterns.iter().position(|p| p == &result.prefix) {
+                    self.found_counts[index] += 1;
+                }
+                self.results.push(result);
+            }
+            SearchEvent::WorkerError { worker, error } => self.notices.push(format!("{} error: {}", worker, error)),
+            SearchEvent::Finished(outcome) => {
+                // The search thread is about to end; its result is in the event
+                if let Some(session) = self.session.take() {
+                    let _ = session.wait();
+                }
+                match outcome {
+                    SearchOutcome::Complete if self.state != AppState::Stopped => self.state = AppState::Finished,
+                    SearchOutcome::Complete => {}
+                    // Stopped from the GUI
+                    SearchOutcome::Stopped => self.state = AppState::Stopped,
+                    SearchOutcome::Failed(e) => {
+                        self.error_message = Some(format!("Generation error: {}", e));
+                        self.state = AppState::Stopped;
+                    }
+                }
             }
         }
     }
@@ -265,8 +379,8 @@ impl eframe::App for VanityApp {
         // Process channel updates
         self.update_from_channels();
 
-        // Request repaint while running
-        if self.state == AppState::Running {
+        // Request repaint while running or waiting for the session
+        if self.state == AppState::Running || self.session_rx.is_some() {
             ctx.request_repaint();
         }
 
@@ -286,6 +400,9 @@ impl eframe::App for VanityApp {
                 } else {
                     ui.label("  Detecting...");
                 }
+                for notice in &self.notices {
+                    ui.colored_label(egui::Color32::YELLOW, format!("  {}", notice));
+                }
             });
 
             ui.add_space(10.0);
@@ -329,7 +446,7 @@ impl eframe::App for VanityApp {
                     ui.label("CPU Threads:");
                     ui.add(
                         egui::Slider::new(&mut self.cpu_threads, 1..=self.max_threads)
-                            .clamp_to_range(true),
+                            .clamping(egui::SliderClamping::Always),
                     );
                 });
             }
@@ -339,13 +456,26 @@ impl eframe::App for VanityApp {
             ui.add_space(10.0);
 
             // Prefix input
-            ui.label("Prefixes (comma-separated):");
+            ui.label("Patterns (comma-separated):");
             ui.add(
                 egui::TextEdit::singleline(&mut self.prefix_input)
                     .hint_text("mysite,cool,anon")
                     .desired_width(f32::INFINITY),
             );
             ui.small("Tip: Use lowercase letters and numbers (base32). 5-6 chars recommended.");
+            ui.small("Use *shop to match the end, or ??shop* to match at a fixed position.");
+            ui.checkbox(&mut self.lookalikes, "Also accept look-alikes (a/4, e/3, s/5, t/7, z/2)");
+
+            ui.add_space(10.0);
+
+            // Regex input
+            ui.label("Regex patterns (optional, space-separated):");
+            ui.add(
+                egui::TextEdit::singleline(&mut self.regex_input)
+                    .hint_text("^(my|our)shop[2-7]")
+                    .desired_width(f32::INFINITY),
+            );
+            ui.small("Base32 only (a-z, 2-7). Regex patterns are searched on the CPU.");
 
             ui.add_space(10.0);
 
@@ -360,6 +490,18 @@ impl eframe::App for VanityApp {
 
             ui.add_space(10.0);
 
+            // Keys per pattern
+            ui.horizontal(|ui| {
+                ui.label("Keys per pattern:");
+                ui.add_enabled(
+                    !self.keep_going,
+                    egui::DragValue::new(&mut self.keys_per_pattern).range(1..=1000),
+                );
+                ui.checkbox(&mut self.keep_going, "Keep going until stopped");
+            });
+
+            ui.add_space(10.0);
+
             // Output directory
             ui.horizontal(|ui| {
                 ui.label("Output:");
@@ -383,12 +525,12 @@ impl eframe::App for VanityApp {
             ui.horizontal(|ui| {
                 let button_enabled = match self.state {
                     AppState::Idle | AppState::Stopped | AppState::Finished => true,
-                    AppState::Running => true,
+                    AppState::Running | AppState::Paused => true,
                 };
 
                 let button_text = match self.state {
                     AppState::Idle | AppState::Stopped | AppState::Finished => "Start Generation",
-                    AppState::Running => "Stop",
+                    AppState::Running | AppState::Paused => "Stop",
                 };
 
                 if ui
@@ -399,16 +541,25 @@ impl eframe::App for VanityApp {
                         AppState::Idle | AppState::Stopped | AppState::Finished => {
                             self.start_generation();
                         }
-                        AppState::Running => {
+                        AppState::Running | AppState::Paused => {
                             self.stop_generation();
                         }
                     }
                 }
 
+                // Paused workers keep their threads and GPU contexts
+                if matches!(self.state, AppState::Running | AppState::Paused) {
+                    let pause_text = if self.state == AppState::Paused { "Resume" } else { "Pause" };
+                    if ui.add(egui::Button::new(pause_text).min_size(egui::vec2(80.0, 30.0))).clicked() {
+                        self.toggle_pause();
+                    }
+                }
+
                 // Status indicator
                 let status_text = match self.state {
                     AppState::Idle => "Ready",
                     AppState::Running => "Running...",
+                    AppState::Paused => "Paused",
                     AppState::Stopped => "Stopped",
                     AppState::Finished => "Complete!",
                 };
@@ -426,26 +577,20 @@ impl eframe::App for VanityApp {
             ui.add_space(10.0);
 
             // Progress section
-            if self.state == AppState::Running || self.progress.keys_checked > 0 {
+            if matches!(self.state, AppState::Running | AppState::Paused) || self.progress.keys_checked > 0 {
                 ui.label("Progress:");
 
-                // Calculate progress percentage based on longest prefix
-                let max_prefix_len = self
-                    .pending_prefixes
-                    .iter()
-                    .chain(self.results.iter().map(|r: &FoundKey| &r.prefix))
-                    .map(|p: &String| p.len())
-                    .max()
-                    .unwrap_or(5);
-
-                let expected = 2_f64.powi(5 * max_prefix_len as i32);
-                let progress_pct = (self.progress.keys_checked as f64 / expected) as f32;
+                // Calculate progress percentage based on the rarest pattern;
+                // an unlimited run has no end to measure against
+                if self.quota != Quota::Unlimited {
+                    let progress_pct = (self.progress.keys_checked as f64 / self.expected_keys) as f32;
 
-                ui.add(
-                    egui::ProgressBar::new(progress_pct.min(1.0))
-                        .show_percentage()
-                        .animate(self.state == AppState::Running),
-                );
+                    ui.add(
+                        egui::ProgressBar::new(progress_pct.min(1.0))
+                            .show_percentage()
+                            .animate(self.state == AppState::Running),
+                    );
+                }
 
                 ui.horizontal(|ui| {
                     ui.label(format!(
@@ -468,7 +613,7 @@ impl eframe::App for VanityApp {
             }
 
             // Results section
-            if !self.results.is_empty() || !self.pending_prefixes.is_empty() {
+            if !self.results.is_empty() || !self.run_patterns.is_empty() {
                 ui.separator();
                 ui.add_space(10.0);
                 ui.label("Results:");
@@ -481,6 +626,9 @@ impl eframe::App for VanityApp {
                             ui.horizontal(|ui| {
                                 ui.colored_label(egui::Color32::GREEN, "✓");
                                 ui.label(&result.prefix);
+                                if result.variant != result.prefix {
+                                    ui.label(format!("({})", result.variant));
+                                }
                                 ui.label("→");
                                 ui.monospace(&result.onion_address[..30]);
                                 ui.label("...");
@@ -492,19 +640,29 @@ impl eframe::App for VanityApp {
                                 }
 
                                 if ui.button("Open folder").clicked() {
-                                    if let Some(parent) = result.key_path.parent() {
-                                        let _ = open::that(parent);
-                                    }
+                                    let _ = open::that(&result.key_path);
                                 }
                             });
                         }
 
-                        // Show pending prefixes
-                        for prefix in &self.pending_prefixes {
+                        // Show patterns still collecting keys
+                        for (prefix, &hits) in self.run_patterns.iter().zip(&self.found_counts) {
+                            if self.quota.is_filled(hits) {
+                                continue;
+                            }
                             ui.horizontal(|ui| {
                                 ui.colored_label(egui::Color32::YELLOW, "○");
                                 ui.label(prefix);
                                 ui.label("→ searching...");
+                                match self.quota {
+                                    Quota::Count(1) => {}
+                                    Quota::Count(count) => {
+                                        ui.label(format!("({}/{})", hits, count));
+                                    }
+                                    Quota::Unlimited => {
+                                        ui.label(format!("({} found)", hits));
+                                    }
+                                }
                             });
                         }
                     });
@@ -1,35 +1,86 @@
 //! CPU backend using Rayon for parallel processing
 
+use crate::hs_dir::{expand_scalar, expand_seed, public_key_of};
 use crate::onion::pubkey_to_onion;
-use crate::{FILE_PREFIX, PUBKEY_PREFIX};
+use crate::private_file::replace;
 use crossbeam_channel::{Receiver, Sender};
+use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+use curve25519_dalek::EdwardsPoint;
 use rayon::prelude::*;
-use std::collections::HashSet;
-use std::io::Write;
+use std::cell::Cell;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
-use super::{BackendInfo, FoundKey, GeneratorError, Progress, SearchFilter};
+use crate::wordlist::{format_ranking, Leaderboard, Offer, RankedAddress, Wordlist, RANKING_FILE};
+
+use super::curve::{compress_batch, AffineNielsPoint, ExtendedPoint, FieldElement};
+use super::{
+    BackendInfo, Capabilities, FoundKey, GeneratorError, Progress, SearchBackend, SearchChannels, SearchEvent,
+    SearchFilter, SearchState,
+};
+
+/// Points compressed per shared inversion in [`CpuSearchMode::Batched`]
+pub const NORMALIZE_BATCH_SIZE: usize = 4096;
+
+/// How CPU workers derive candidate keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CpuSearchMode {
+    /// Fresh random seed per candidate (SHA-512 + fixed-base scalar multiplication)
+    Seed,
+    /// One random scalar per worker, then one point addition per candidate
+    Incremental,
+    /// Incremental walk, with points compressed in batches sharing one field inversion
+    #[default]
+    Batched,
+}
+
+impl CpuSearchMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CpuSearchMode::Seed => "seed",
+            CpuSearchMode::Incremental => "incremental",
+            CpuSearchMode::Batched => "batched",
+        }
+    }
+}
 
 /// CPU backend using Rayon for parallel key generation
 #[derive(Debug, Clone)]
 pub struct CpuBackend {
     thread_count: usize,
+    search_mode: CpuSearchMode,
 }
 
 impl CpuBackend {
     /// Create a new CPU backend using all available cores
     pub fn new() -> Self {
-        Self {
-            thread_count: num_cpus::get(),
-        }
+        Self::with_threads(num_cpus::get())
     }
 
     /// Create a CPU backend with a specific thread count
     pub fn with_threads(thread_count: usize) -> Self {
-        Self { thread_count }
+        Self {
+            thread_count,
+            search_mode: CpuSearchMode::default(),
+        }
+    }
+
+    /// Use a different key derivation strategy
+    pub fn with_search_mode(mut self, search_mode: CpuSearchMode) -> Self {
+        self.search_mode = search_mode;
+        self
+    }
+
+    /// Key derivation strategy used by the workers
+    pub fn search_mode(&self) -> CpuSearchMode {
+        self.search_mode
+    }
+
+    /// Number of worker threads
+    pub fn thread_count(&self) -> usize {
+        self.thread_count
     }
 
     /// Get backend information
@@ -46,11 +97,10 @@ impl CpuBackend {
         &self,
         prefixes: Vec<String>,
         output_dir: PathBuf,
-        progress_tx: Sender<Progress>,
-        result_tx: Sender<FoundKey>,
+        events: Sender<SearchEvent>,
         stop_rx: Receiver<()>,
     ) -> Result<(), GeneratorError> {
-        self.generate_with_filter(prefixes, output_dir, progress_tx, result_tx, stop_rx, SearchFilter::default())
+        self.generate_with_filter(prefixes, output_dir, events, stop_rx, SearchFilter::default())
     }
 
     /// Start vanity address generation with additional filter
@@ -58,23 +108,141 @@ impl CpuBackend {
         &self,
         prefixes: Vec<String>,
         output_dir: PathBuf,
-        progress_tx: Sender<Progress>,
-        result_tx: Sender<FoundKey>,
+        events: Sender<SearchEvent>,
         stop_rx: Receiver<()>,
         filter: SearchFilter,
     ) -> Result<(), GeneratorError> {
-        // Validate prefixes
-        for prefix in &prefixes {
-            if base32::decode(
-                base32::Alphabet::Rfc4648Lower { padding: false },
-                &format!("{}aa", prefix),
-            )
-            .is_none()
+        // Validate and compile prefixes, plus the words every hit must contain
+        let state = SearchState::with_filter(&prefixes, &filter)?.with_backend(self.info().name);
+        self.run(Arc::new(state), output_dir, events, stop_rx)
+    }
+
+    /// Search for the patterns of `state`, which may be changed, paused or
+    /// stopped while the workers run
+    pub fn run(
+        &self,
+        state: Arc<SearchState>,
+        output_dir: PathBuf,
+        events: Sender<SearchEvent>,
+        stop_rx: Receiver<()>,
+    ) -> Result<(), GeneratorError> {
+        let has_seed = self.search_mode == CpuSearchMode::Seed;
+        let checked = AtomicU64::new(0);
+
+        self.run_workers(&events, &stop_rx, &state, &checked, |pubkey_bytes, secret_key| {
+            // The address is only encoded once the raw bytes already match
+            let Some((index, _)) = state.find_hit(pubkey_bytes) else {
+                return Ok(());
+            };
+            // Walks are far too short to leave the clamped range, but never
+            // write a key we cannot represent
+            let Some(expanded) = secret_key()? else {
+                return Ok(());
+            };
+            let Some(sequence) = state.claim(index) else {
+                // Other workers already filled this prefix's quota
+                return Ok(());
+            };
+
+            let found = state.save_hit(&output_dir, index, sequence, pubkey_bytes, &expanded, has_seed)?;
+            let _ = events.send(SearchEvent::KeyFound(found));
+            Ok(())
+        })
+    }
+
+    /// Rank candidates by the dictionary words they contain, keeping the
+    /// best ones on disk until stopped
+    ///
+    /// Every address good enough for the leaderboard is written out, then
+    /// offered and reported; the one it pushes out is deleted again, as is
+    /// one that lost its place to another thread meanwhile. The current
+    /// ranking is kept in [`RANKING_FILE`] in `output_dir`. Keys are saved in
+    /// the format and with the protection of `state`, which has no patterns
+    /// but can pause and stop the search like any other.
+    pub fn generate_wordlist(
+        &self,
+        wordlist: &Wordlist,
+        leaderboard: &Leaderboard,
+        state: &SearchState,
+        output_dir: PathBuf,
+        events: Sender<SearchEvent>,
+        stop_rx: Receiver<()>,
+    ) -> Result<(), GeneratorError> {
+        let sequence = AtomicU64::new(0);
+        let checked = AtomicU64::new(0);
+        // Keeps the ranking file from going back to an older ranking
+        let ranking_file = Mutex::new(output_dir.join(RANKING_FILE));
+        self.run_workers(&events, &stop_rx, state, &checked, |pubkey_bytes, secret_key| {
+            let Some(score) = wordlist.score(pubkey_bytes, leaderboard.threshold()) else {
+                return Ok(());
+            };
+            let Some(expanded) = secret_key()? else {
+                return Ok(());
+            };
+
+            let onion = pubkey_to_onion(pubkey_bytes);
+            let entry = RankedAddress {
+                score,
+                words: wordlist.words_in(&onion),
+                onion_address: onion,
+            };
+            // Saved first, so the ranking only ever lists keys on disk; the
+            // lock is not held for the disk writes
+            let key_path = state.save_key(&output_dir, pubkey_bytes, &expanded)?;
+            let evicted = match leaderboard.offer(entry.clone()) {
+                Offer::Admitted(evicted) => evicted,
+                Offer::Rejected => {
+                    std::fs::remove_dir_all(&key_path)?;
+                    return Ok(());
+                }
+            };
+            if let Some(evicted) = evicted {
+                std::fs::remove_dir_all(state.format().key_dir(&output_dir, &evicted.onion_address))?;
+            }
             {
-                return Err(GeneratorError::InvalidPrefix(prefix.clone()));
+                let ranking_file = ranking_file.lock().unwrap();
+                replace(&ranking_file, format_ranking(&leaderboard.entries()).as_bytes())?;
             }
-        }
 
+            let words = entry.words.join(",");
+            let _ = events.send(SearchEvent::KeyFound(FoundKey {
+                prefix: words.clone(),
+                variant: words,
+                onion_address: entry.onion_address,
+                public_key: *pubkey_bytes,
+                key_path,
+                sequence: sequence.fetch_add(1, Ordering::Relaxed) + 1,
+                keys_checked: checked.load(Ordering::Relaxed),
+                has_seed: self.search_mode == CpuSearchMode::Seed,
+            }));
+            Ok(())
+        })
+    }
+
+    /// Run the selected key derivation strategy on all threads until stopped
+    /// or every pattern of `state` is complete
+    ///
+    /// `check` sees every candidate public key together with a function that
+    /// derives its expanded secret key, which is only worth calling on a hit.
+    /// That key is checked against the public key with dalek first, so a
+    /// fault in the walk's own curve arithmetic fails the search with
+    /// [`GeneratorError::KeyMismatch`] instead of saving a key for an address
+    /// it does not belong to. A state without patterns, as for wordlist
+    /// searches, only ends when stopped. The first error `check` returns
+    /// stops all threads and is returned.
+    ///
+    /// Checked keys are counted in `counter`, in batches.
+    fn run_workers<C>(
+        &self,
+        events: &Sender<SearchEvent>,
+        stop_rx: &Receiver<()>,
+        state: &SearchState,
+        counter: &AtomicU64,
+        check: C,
+    ) -> Result<(), GeneratorError>
+    where
+        C: Fn(&[u8; 32], &dyn Fn() -> Result<Option<[u8; 64]>, GeneratorError>) -> Result<(), GeneratorError> + Sync,
+    {
         // Set up thread pool
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(self.thread_count)
@@ -82,24 +250,33 @@ impl CpuBackend {
             .map_err(|e| GeneratorError::Channel(e.to_string()))?;
 
         // Shared state
-        let remaining: Arc<Mutex<HashSet<String>>> =
-            Arc::new(Mutex::new(prefixes.into_iter().collect()));
-        let counter = Arc::new(AtomicU64::new(0));
-        let stopped = Arc::new(AtomicBool::new(false));
+        let stopped = AtomicBool::new(false);
+        let failure = Mutex::new(None);
         let start_time = Instant::now();
 
-        // Prepare contains words (lowercase for case-insensitive matching)
-        let contains_words: Arc<Vec<String>> = Arc::new(
-            filter.contains.iter().map(|w| w.to_lowercase()).collect()
-        );
+        let check = |pubkey_bytes: &[u8; 32], secret_key: &dyn Fn() -> Option<[u8; 64]>| {
+            let verified_key = || match secret_key() {
+                Some(expanded) if public_key_of(&expanded) != *pubkey_bytes => {
+                    Err(GeneratorError::KeyMismatch(pubkey_to_onion(pubkey_bytes)))
+                }
+                expanded => Ok(expanded),
+            };
+            if let Err(e) = check(pubkey_bytes, &verified_key) {
+                failure.lock().unwrap().get_or_insert(e);
+                stopped.store(true, Ordering::SeqCst);
+            }
+        };
 
-        // Batch size per iteration
+        // Keys checked per rayon task before its counter is flushed
         const BATCH_SIZE: usize = 10_000;
 
         pool.install(|| {
             loop {
+                // Park while paused; the pool and its threads stay up
+                state.wait_while_paused();
+
                 // Check stop signal
-                if stop_rx.try_recv().is_ok() {
+                if stop_rx.try_recv().is_ok() || state.is_stopped() {
                     stopped.store(true, Ordering::SeqCst);
                     break;
                 }
@@ -108,106 +285,97 @@ impl CpuBackend {
                     break;
                 }
 
-                // Check if all prefixes found
-                if remaining.lock().unwrap().is_empty() {
+                // Check if every quota is filled
+                if state.pattern_count() > 0 && state.is_done() {
                     break;
                 }
 
-                // Process batch in parallel
-                (0..BATCH_SIZE).into_par_iter().for_each(|_| {
-                    if stopped.load(Ordering::Relaxed) {
-                        return;
-                    }
+                match self.search_mode {
+                    CpuSearchMode::Seed => {
+                        // Seeds are slow to hash, so each thread takes a smaller share
+                        (0..self.thread_count).into_par_iter().for_each(|_| {
+                            let mut checked = 0;
 
-                    // Generate random seed
-                    let seed: [u8; 32] = rand::random();
-
-                    // Create keypair
-                    let signing_key = ed25519_dalek::SigningKey::from_bytes(&seed);
-                    let verifying_key = signing_key.verifying_key();
-                    let pubkey_bytes: [u8; 32] = verifying_key.to_bytes();
-
-                    // Generate onion address
-                    let onion = pubkey_to_onion(&pubkey_bytes);
-
-                    // Check against remaining prefixes
-                    let remaining_guard = remaining.lock().unwrap();
-                    let mut found_prefix = None;
-                    for prefix in remaining_guard.iter() {
-                        if onion.starts_with(prefix) {
-                            found_prefix = Some(prefix.clone());
-                            break;
-                        }
-                    }
-                    drop(remaining_guard);
-
-                    // Check if address also contains all required words
-                    if found_prefix.is_some() && !contains_words.is_empty() {
-                        let onion_lower = onion.to_lowercase();
-                        if !contains_words.iter().all(|word| onion_lower.contains(word)) {
-                            // Prefix matched but contains filter failed - skip this address
-                            found_prefix = None;
-                        }
-                    }
+                            for _ in 0..BATCH_SIZE / 10 {
+                                if stopped.load(Ordering::Relaxed) {
+                                    break;
+                                }
 
-                    // If found, save and notify
-                    if let Some(prefix) = found_prefix {
-                        // Remove from remaining
-                        remaining.lock().unwrap().remove(&prefix);
+                                // Generate random seed
+                                let seed: [u8; 32] = rand::random();
 
-                        // Create Tor hidden service directory structure
-                        let dir_name = onion.trim_end_matches(".onion");
-                        let hs_dir = output_dir.join(dir_name);
+                                // Create keypair
+                                let signing_key = ed25519_dalek::SigningKey::from_bytes(&seed);
+                                let verifying_key = signing_key.verifying_key();
+                                let pubkey_bytes: [u8; 32] = verifying_key.to_bytes();
 
-                        if std::fs::create_dir_all(&hs_dir).is_ok() {
-                            // 1. Write hostname file
-                            let hostname_path = hs_dir.join("hostname");
-                            if let Ok(mut f) = std::fs::File::create(&hostname_path) {
-                                let _ = writeln!(f, "{}", onion);
-                            }
+                                check(&pubkey_bytes, &|| Some(expand_seed(&seed)));
 
-                            // 2. Write hs_ed25519_public_key
-                            let pubkey_path = hs_dir.join("hs_ed25519_public_key");
-                            if let Ok(mut f) = std::fs::File::create(&pubkey_path) {
-                                let _ = f.write_all(PUBKEY_PREFIX);
-                                let _ = f.write_all(&pubkey_bytes);
+                                checked += 1;
                             }
 
-                            // 3. Write hs_ed25519_secret_key
-                            // For CPU backend, we have the seed, so create proper expanded key
-                            let secret_path = hs_dir.join("hs_ed25519_secret_key");
-                            if let Ok(mut f) = std::fs::File::create(&secret_path) {
-                                // Tor expects: scalar (clamped) || nonce_prefix
-                                // ed25519_dalek derives these from SHA512(seed)
-                                use sha2::{Sha512, Digest};
-                                let hash = Sha512::digest(&seed);
-                                let mut expanded = [0u8; 64];
-                                expanded.copy_from_slice(&hash);
-                                // Clamp the scalar part (first 32 bytes)
-                                expanded[0] &= 248;
-                                expanded[31] &= 127;
-                                expanded[31] |= 64;
-                                let _ = f.write_all(FILE_PREFIX);
-                                let _ = f.write_all(&expanded);
+                            counter.fetch_add(checked, Ordering::Relaxed);
+                        });
+                    }
+                    CpuSearchMode::Incremental => {
+                        // One walker per thread, each checking a full batch
+                        (0..self.thread_count).into_par_iter().for_each(|_| {
+                            let mut walker = IncrementalWalker::new();
+
+                            for _ in 0..BATCH_SIZE {
+                                if stopped.load(Ordering::Relaxed) {
+                                    break;
+                                }
+
+                                let (offset, pubkey_bytes) = walker.next_key();
+                                let handed_out = Cell::new(false);
+                                check(&pubkey_bytes, &|| {
+                                    handed_out.set(true);
+                                    walker.expanded_secret_key(offset)
+                                });
+                                if handed_out.get() {
+                                    walker.reseed();
+                                }
                             }
 
-                            // 4. Create authorized_clients directory
-                            let _ = std::fs::create_dir_all(hs_dir.join("authorized_clients"));
-
-                            // Send result
-                            let _ = result_tx.send(FoundKey {
-                                prefix,
-                                onion_address: onion,
-                                key_path: hs_dir,
-                            });
-                        }
+                            counter.fetch_add(walker.keys_walked(), Ordering::Relaxed);
+                        });
                     }
+                    CpuSearchMode::Batched => {
+                        (0..self.thread_count).into_par_iter().for_each(|_| {
+                            let mut walker = BatchWalker::new(NORMALIZE_BATCH_SIZE);
+
+                            while walker.keys_walked() < BATCH_SIZE as u64 {
+                                if stopped.load(Ordering::Relaxed) {
+                                    break;
+                                }
+
+                                let first_offset = walker.next_batch();
+                                let handed_out = Cell::new(false);
+                                for (i, pubkey_bytes) in walker.pubkeys().iter().enumerate() {
+                                    let offset = first_offset + i as u64;
+                                    check(pubkey_bytes, &|| {
+                                        handed_out.set(true);
+                                        walker.expanded_secret_key(offset)
+                                    });
+                                    // The rest of the batch is related to that key
+                                    if handed_out.get() {
+                                        break;
+                                    }
+                                }
+                                if handed_out.get() {
+                                    walker.reseed();
+                                }
+                            }
 
-                    counter.fetch_add(1, Ordering::Relaxed);
-                });
+                            counter.fetch_add(walker.keys_walked(), Ordering::Relaxed);
+                        });
+                    }
+                }
 
                 // Send progress update
                 let keys_checked = counter.load(Ordering::Relaxed);
+                state.report_checked(keys_checked);
                 let elapsed = start_time.elapsed().as_secs_f64();
                 let keys_per_sec = if elapsed > 0.0 {
                     keys_checked as f64 / elapsed
@@ -215,15 +383,18 @@ impl CpuBackend {
                     0.0
                 };
 
-                let _ = progress_tx.send(Progress {
+                let _ = events.send(SearchEvent::Progress(Progress {
                     keys_checked,
                     keys_per_sec,
                     elapsed_secs: elapsed,
-                });
+                    found: state.hit_counts(),
+                }));
             }
         });
 
-        if stopped.load(Ordering::SeqCst) {
+        if let Some(e) = failure.into_inner().unwrap() {
+            Err(e)
+        } else if stopped.load(Ordering::SeqCst) {
             Err(GeneratorError::Stopped)
         } else {
             Ok(())
@@ -231,8 +402,442 @@ impl CpuBackend {
     }
 }
 
+impl SearchBackend for CpuBackend {
+    fn info(&self) -> BackendInfo {
+        self.info()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            regex: true,
+            anchored_patterns: true,
+            seeds: self.search_mode == CpuSearchMode::Seed,
+            gpu: false,
+            live_patterns: true,
+        }
+    }
+
+    fn search(
+        &self,
+        state: Arc<SearchState>,
+        output_dir: PathBuf,
+        channels: SearchChannels,
+    ) -> Result<(), GeneratorError> {
+        self.run(state, output_dir, channels.events, channels.stop)
+    }
+}
+
 impl Default for CpuBackend {
     fn default() -> Self {
         Self::new()
     }
 }
+
+/// Walks `P, P + 8B, P + 16B, ...` from a single random scalar
+///
+/// Each step costs one point addition instead of a SHA-512 and a fixed-base
+/// scalar multiplication. The walk steps by `8B` rather than `B` so every
+/// visited scalar keeps the low three bits cleared: Tor (and ed25519-dalek)
+/// clamp the scalar half of an expanded key when loading it, and a clamped
+/// scalar survives that unchanged.
+///
+/// Keys of one walk differ by a small known multiple of `8B`, so anyone
+/// holding one of them could derive the others. Once a key was handed out,
+/// [`Self::reseed`] starts over from an unrelated scalar.
+pub struct IncrementalWalker {
+    /// Clamped starting scalar, little-endian
+    scalar: [u8; 32],
+    /// Point for the next candidate, `(scalar + 8 * offset) * B`
+    point: EdwardsPoint,
+    /// `8 * B`
+    step: EdwardsPoint,
+    offset: u64,
+    /// Candidates produced from earlier scalars
+    earlier: u64,
+}
+
+impl IncrementalWalker {
+    /// Start a walk from a fresh random scalar
+    pub fn new() -> Self {
+        Self::from_scalar(rand::random())
+    }
+
+    /// Start a walk from the given scalar bytes (clamped before use)
+    pub fn from_scalar(mut scalar: [u8; 32]) -> Self {
+        clamp_scalar(&mut scalar);
+        Self {
+            scalar,
+            point: EdwardsPoint::mul_base_clamped(scalar),
+            step: ED25519_BASEPOINT_POINT.mul_by_cofactor(),
+            offset: 0,
+            earlier: 0,
+        }
+    }
+
+    /// Continue from a fresh random scalar, so no key walked from now on is
+    /// related to an earlier one; offsets start again at zero
+    pub fn reseed(&mut self) {
+        let earlier = self.keys_walked();
+        *self = Self::new();
+        self.earlier = earlier;
+    }
+
+    /// Compressed public key of the next candidate, with its offset in the walk
+    pub fn next_key(&mut self) -> (u64, [u8; 32]) {
+        let offset = self.offset;
+        let pubkey = self.point.compress().to_bytes();
+        self.point += self.step;
+        self.offset += 1;
+        (offset, pubkey)
+    }
+
+    /// Number of candidates produced so far, from every scalar
+    pub fn keys_walked(&self) -> u64 {
+        self.earlier + self.offset
+    }
+
+    /// Clamped scalar for the candidate at `offset` of the current walk
+    ///
+    /// Returns `None` if adding `8 * offset` would carry into bit 254, which
+    /// would make the scalar unclamped.
+    pub fn scalar_at(&self, offset: u64) -> Option<[u8; 32]> {
+        walk_scalar(&self.scalar, offset)
+    }
+
+    /// Tor expanded secret key (`scalar || nonce_prefix`) for the candidate at `offset`
+    ///
+    /// No seed exists for this key, so the nonce half is drawn at random.
+    pub fn expanded_secret_key(&self, offset: u64) -> Option<[u8; 64]> {
+        self.scalar_at(offset).map(|scalar| expand_scalar(&scalar))
+    }
+}
+
+impl Default for IncrementalWalker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Incremental walk that compresses whole batches of points at once
+///
+/// Points stay in projective coordinates until a batch is full, then all of
+/// them are normalised with one shared field inversion (Montgomery's trick)
+/// instead of one inversion each. Yields exactly the same keys as
+/// [`IncrementalWalker`] started from the same scalar, and likewise has to
+/// be reseeded once a key was handed out.
+pub struct BatchWalker {
+    /// Clamped starting scalar, little-endian
+    scalar: [u8; 32],
+    /// Point for the next candidate
+    point: ExtendedPoint,
+    /// `8 * B`, precomputed for mixed addition
+    step: AffineNielsPoint,
+    offset: u64,
+    /// Candidates produced from earlier scalars
+    earlier: u64,
+    points: Vec<ExtendedPoint>,
+    scratch: Vec<FieldElement>,
+    pubkeys: Vec<[u8; 32]>,
+}
+
+impl BatchWalker {
+    /// Start a walk from a fresh random scalar
+    pub fn new(batch_size: usize) -> Self {
+        Self::from_scalar(rand::random(), batch_size)
+    }
+
+    /// Start a walk from the given scalar bytes (clamped before use)
+    pub fn from_scalar(mut scalar: [u8; 32], batch_size: usize) -> Self {
+        clamp_scalar(&mut scalar);
+        let start = EdwardsPoint::mul_base_clamped(scalar).compress().to_bytes();
+        let step = ED25519_BASEPOINT_POINT.mul_by_cofactor().compress().to_bytes();
+        let batch_size = batch_size.max(1);
+
+        Self {
+            scalar,
+            point: ExtendedPoint::from_compressed(&start).expect("valid point"),
+            step: ExtendedPoint::from_compressed(&step)
+                .expect("valid point")
+                .to_affine_niels(),
+            offset: 0,
+            earlier: 0,
+            points: Vec::with_capacity(batch_size),
+            scratch: Vec::with_capacity(batch_size),
+            pubkeys: vec![[0u8; 32]; batch_size],
+        }
+    }
+
+    /// Continue from a fresh random scalar, see [`IncrementalWalker::reseed`];
+    /// the rest of the current batch must not be used any more
+    pub fn reseed(&mut self) {
+        let earlier = self.keys_walked();
+        *self = Self::new(self.pubkeys.len());
+        self.earlier = earlier;
+    }
+
+    /// Walk and compress the next batch, returning the offset of its first key
+    ///
+    /// The compressed public keys are then available from [`Self::pubkeys`].
+    pub fn next_batch(&mut self) -> u64 {
+        let first_offset = self.offset;
+
+        self.points.clear();
+        for _ in 0..self.pubkeys.len() {
+            self.points.push(self.point);
+            self.point = self.point.add_niels(&self.step);
+        }
+        compress_batch(&self.points, &mut self.scratch, &mut self.pubkeys);
+
+        self.offset += self.pubkeys.len() as u64;
+        first_offset
+    }
+
+    /// Compressed public keys of the most recent batch
+    pub fn pubkeys(&self) -> &[[u8; 32]] {
+        &self.pubkeys
+    }
+
+    /// Number of candidates produced so far, from every scalar
+    pub fn keys_walked(&self) -> u64 {
+        self.earlier + self.offset
+    }
+
+    /// Clamped scalar for the candidate at `offset` of the current walk, see
+    /// [`IncrementalWalker::scalar_at`]
+    pub fn scalar_at(&self, offset: u64) -> Option<[u8; 32]> {
+        walk_scalar(&self.scalar, offset)
+    }
+
+    /// Tor expanded secret key for the candidate at `offset`, with a random nonce half
+    pub fn expanded_secret_key(&self, offset: u64) -> Option<[u8; 64]> {
+        self.scalar_at(offset).map(|scalar| expand_scalar(&scalar))
+    }
+}
+
+/// `start + 8 * offset` as a 256-bit integer, if it is still clamped
+fn walk_scalar(start: &[u8; 32], offset: u64) -> Option<[u8; 32]> {
+    let mut out = [0u8; 32];
+    let mut carry = (offset as u128) << 3;
+    for i in 0..4 {
+        let limb = u64::from_le_bytes(start[i * 8..i * 8 + 8].try_into().unwrap());
+        let sum = limb as u128 + carry;
+        out[i * 8..i * 8 + 8].copy_from_slice(&(sum as u64).to_le_bytes());
+        carry = sum >> 64;
+    }
+
+    if carry != 0 || out[31] & 0xc0 != 0x40 {
+        return None;
+    }
+    Some(out)
+}
+
+fn clamp_scalar(scalar: &mut [u8; 32]) {
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{select_backend_with_cpu, BackendMode, Quota};
+    use crate::hs_dir::read_hs_dir;
+    use ed25519_dalek::hazmat::ExpandedSecretKey;
+    use ed25519_dalek::VerifyingKey;
+
+    #[test]
+    fn test_incremental_walk_matches_expanded_key() {
+        let mut walker = IncrementalWalker::new();
+        for _ in 0..64 {
+            let (offset, pubkey) = walker.next_key();
+            let expanded = walker.expanded_secret_key(offset).unwrap();
+
+            // Loading clamps the scalar; a walked scalar must survive that unchanged
+            let esk = ExpandedSecretKey::from_bytes(&expanded);
+            assert_eq!(VerifyingKey::from(&esk).to_bytes(), pubkey);
+        }
+    }
+
+    #[test]
+    fn test_batch_walk_matches_incremental_walk() {
+        let scalar: [u8; 32] = rand::random();
+        let mut incremental = IncrementalWalker::from_scalar(scalar);
+        let mut batched = BatchWalker::from_scalar(scalar, 37);
+
+        for _ in 0..3 {
+            let first_offset = batched.next_batch();
+            for (i, pubkey) in batched.pubkeys().iter().enumerate() {
+                assert_eq!(incremental.next_key(), (first_offset + i as u64, *pubkey));
+            }
+        }
+        assert_eq!(batched.scalar_at(100), incremental.scalar_at(100));
+    }
+
+    #[test]
+    fn test_reseeded_walks_are_unrelated() {
+        // Whatever was handed out from the first walk, the next key must not
+        // be a short walk away from it
+        let unrelated = |base: [u8; 32], scalar: [u8; 32]| (0..10_000).all(|i| walk_scalar(&base, i) != Some(scalar));
+
+        let mut walker = IncrementalWalker::new();
+        let base = walker.scalar_at(0).unwrap();
+        walker.next_key();
+        walker.reseed();
+        let (offset, _) = walker.next_key();
+        assert_eq!(offset, 0);
+        assert_eq!(walker.keys_walked(), 2);
+        assert!(unrelated(base, walker.scalar_at(offset).unwrap()));
+
+        let mut batched = BatchWalker::new(8);
+        let base = batched.scalar_at(0).unwrap();
+        batched.next_batch();
+        batched.reseed();
+        assert_eq!(batched.next_batch(), 0);
+        assert_eq!(batched.keys_walked(), 16);
+        assert!(unrelated(base, batched.scalar_at(0).unwrap()));
+    }
+
+    #[test]
+    fn test_hits_of_one_worker_are_unrelated() {
+        for mode in [CpuSearchMode::Incremental, CpuSearchMode::Batched] {
+            let dir = tempfile::tempdir().unwrap();
+            let (event_tx, event_rx) = crossbeam_channel::unbounded();
+            let (_stop_tx, stop_rx) = crossbeam_channel::bounded(1);
+            // One thread, and a pattern it hits several times per batch
+            let filter = SearchFilter {
+                quota: Quota::Count(4),
+                ..SearchFilter::default()
+            };
+            CpuBackend::with_threads(1)
+                .with_search_mode(mode)
+                .generate_with_filter(vec!["a".to_string()], dir.path().to_path_buf(), event_tx, stop_rx, filter)
+                .unwrap();
+
+            let scalars: Vec<[u8; 32]> = event_rx
+                .try_iter()
+                .filter_map(|event| match event {
+                    SearchEvent::KeyFound(found) => Some(found),
+                    _ => None,
+                })
+                .map(|found| read_hs_dir(&found.key_path).unwrap().expanded_secret_key[..32].try_into().unwrap())
+                .collect();
+            assert_eq!(scalars.len(), 4);
+            for a in &scalars {
+                for b in scalars.iter().filter(|b| *b != a) {
+                    assert!((0..100_000).all(|i| walk_scalar(a, i) != Some(*b)), "{:?}: hits share a walk", mode);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_incremental_walk_rejects_carry_into_bit_254() {
+        let walker = IncrementalWalker::from_scalar([0xff; 32]);
+        assert!(walker.scalar_at(0).is_some());
+        assert!(walker.scalar_at(1).is_none());
+    }
+
+    #[test]
+    fn test_search_through_trait() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend: Box<dyn SearchBackend> = Box::new(CpuBackend::with_threads(1));
+        assert!(backend.capabilities().regex);
+        assert!(!backend.capabilities().seeds);
+
+        let (event_tx, event_rx) = crossbeam_channel::unbounded();
+        let (_stop_tx, stop_rx) = crossbeam_channel::bounded(1);
+        let channels = SearchChannels {
+            events: event_tx,
+            stop: stop_rx,
+        };
+        let filter = SearchFilter {
+            regex: vec!["^b".to_string()],
+            ..SearchFilter::default()
+        };
+        let state = Arc::new(SearchState::with_filter(&["a".to_string()], &filter).unwrap());
+        backend.search(state, dir.path().to_path_buf(), channels).unwrap();
+
+        let found: Vec<_> = event_rx
+            .try_iter()
+            .filter_map(|event| match event {
+                SearchEvent::KeyFound(found) => Some(found),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|key| key.key_path.is_dir() && !key.has_seed));
+    }
+
+    #[test]
+    fn test_wordlist_ranks_only_saved_keys() {
+        // A file where the output directory should be makes every save fail
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().join("keys");
+        std::fs::write(&output_dir, b"").unwrap();
+
+        let wordlist = Wordlist::new(["abc", "cde"]).unwrap();
+        let leaderboard = Leaderboard::new(3);
+        let (event_tx, event_rx) = crossbeam_channel::unbounded();
+        let (_stop_tx, stop_rx) = crossbeam_channel::bounded(1);
+        let state = SearchState::new(&[], &[]).unwrap();
+        let result =
+            CpuBackend::with_threads(1).generate_wordlist(&wordlist, &leaderboard, &state, output_dir, event_tx, stop_rx);
+
+        assert!(result.is_err());
+        assert!(leaderboard.entries().is_empty());
+        assert_eq!(leaderboard.threshold(), 0);
+        assert!(!event_rx.try_iter().any(|event| matches!(event, SearchEvent::KeyFound(_))));
+    }
+
+    #[test]
+    fn test_wordlist_stops_with_its_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let wordlist = Wordlist::new(["abc", "cde"]).unwrap();
+        let leaderboard = Leaderboard::new(3);
+        let (event_tx, _event_rx) = crossbeam_channel::unbounded();
+        let (_stop_tx, stop_rx) = crossbeam_channel::bounded(1);
+        let state = SearchState::new(&[], &[]).unwrap();
+        state.pause();
+        state.stop();
+
+        let result = CpuBackend::with_threads(1).generate_wordlist(
+            &wordlist,
+            &leaderboard,
+            &state,
+            dir.path().to_path_buf(),
+            event_tx,
+            stop_rx,
+        );
+        assert!(matches!(result, Err(GeneratorError::Stopped)));
+        assert_eq!(state.keys_checked(), 0);
+    }
+
+    #[test]
+    fn test_capabilities_check_regex() {
+        let filter = SearchFilter {
+            regex: vec!["^ab".to_string()],
+            ..SearchFilter::default()
+        };
+        let gpu = Capabilities {
+            anchored_patterns: true,
+            seeds: true,
+            gpu: true,
+            ..Capabilities::default()
+        };
+        assert!(matches!(gpu.check(&filter), Err(GeneratorError::Unsupported(_))));
+        assert!(gpu.check(&SearchFilter::default()).is_ok());
+        assert!(CpuBackend::with_threads(1).capabilities().check(&filter).is_ok());
+    }
+
+    #[test]
+    fn test_selection_reports_choice() {
+        let (event_tx, event_rx) = crossbeam_channel::unbounded();
+        let backend = select_backend_with_cpu(BackendMode::Cpu, CpuBackend::with_threads(2), &event_tx);
+        let events: Vec<_> = event_rx.try_iter().collect();
+        assert_eq!(events.len(), 1);
+        match &events[0] {
+            SearchEvent::BackendChosen(info) => assert_eq!(info.name, backend.info().name),
+            other => panic!("expected BackendChosen, got {:?}", other),
+        }
+    }
+}
@@ -1,22 +1,23 @@
 //! CUDA GPU backend for high-speed key generation
 
-use crate::onion::pubkey_to_onion;
-use crate::FILE_PREFIX;
+use crate::pattern::BytePattern;
 use crossbeam_channel::{Receiver, Sender};
-use std::collections::HashSet;
 use std::ffi::CString;
-use std::io::Write;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
 use rustacuda::launch;
-use rustacuda::memory::{DeviceBox, DeviceBuffer};
+use rustacuda::memory::{DeviceBox, DeviceBuffer, DevicePointer};
 use rustacuda::prelude::*;
 use tor_v3_vanity_core as core;
 
-use super::{BackendInfo, FoundKey, GeneratorError, Progress};
+use super::gpu_hits::{launch_hits, process_hits, GpuHit, HIT_CAPACITY};
+use super::{
+    BackendInfo, Capabilities, GeneratorError, Progress, SearchBackend, SearchChannels, SearchEvent, SearchFilter,
+    SearchState,
+};
 
 /// CUDA GPU backend for high-speed vanity address generation
 #[derive(Debug, Clone)]
@@ -88,25 +89,59 @@ impl CudaBackend {
         &self,
         prefixes: Vec<String>,
         output_dir: PathBuf,
-        progress_tx: Sender<Progress>,
-        result_tx: Sender<FoundKey>,
+        events: Sender<SearchEvent>,
         stop_rx: Receiver<()>,
     ) -> Result<(), GeneratorError> {
-        // Validate prefixes
-        for prefix in &prefixes {
-            if base32::decode(
-                base32::Alphabet::Rfc4648Lower { padding: false },
-                &format!("{}aa", prefix),
-            )
-            .is_none()
-            {
-                return Err(GeneratorError::InvalidPrefix(prefix.clone()));
-            }
+        self.generate_with_filter(prefixes, output_dir, events, stop_rx, SearchFilter::default())
+    }
+
+    /// Start vanity address generation on GPU with additional filter requirements
+    pub fn generate_with_filter(
+        &self,
+        prefixes: Vec<String>,
+        output_dir: PathBuf,
+        events: Sender<SearchEvent>,
+        stop_rx: Receiver<()>,
+        filter: SearchFilter,
+    ) -> Result<(), GeneratorError> {
+        // Validate and compile prefixes
+        let state = Arc::new(SearchState::with_filter(&prefixes, &filter)?.with_backend(self.info().name));
+        self.run(state, output_dir, events, stop_rx)
+    }
+
+    /// Run the GPU workers on a search state that may be changed, paused or
+    /// stopped while they run
+    pub fn run(
+        &self,
+        state: Arc<SearchState>,
+        output_dir: PathBuf,
+        events: Sender<SearchEvent>,
+        stop_rx: Receiver<()>,
+    ) -> Result<(), GeneratorError> {
+        self.run_shared(state.clone(), output_dir, events, stop_rx)?;
+        if let Some(e) = state.take_failure() {
+            return Err(e);
+        }
+        if state.is_done() {
+            Ok(())
+        } else {
+            Err(GeneratorError::Stopped)
         }
+    }
 
+    /// Run the GPU workers on a search state shared with other workers
+    ///
+    /// Only fails with GPU errors. A key that cannot be saved fails `state`,
+    /// which ends the search for every worker; collecting that failure is
+    /// left to the owner of the state.
+    pub(super) fn run_shared(
+        &self,
+        state: Arc<SearchState>,
+        output_dir: PathBuf,
+        events: Sender<SearchEvent>,
+        stop_rx: Receiver<()>,
+    ) -> Result<(), GeneratorError> {
         // Shared state
-        let remaining: Arc<Mutex<HashSet<String>>> =
-            Arc::new(Mutex::new(prefixes.clone().into_iter().collect()));
         let counter = Arc::new(AtomicU64::new(0));
         let stopped = Arc::new(AtomicBool::new(false));
         let start_time = Instant::now();
@@ -116,10 +151,9 @@ impl CudaBackend {
         let gpu_error: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
 
         for device_idx in 0..self.device_count {
-            let prefixes = prefixes.clone();
+            let state = state.clone();
             let output_dir = output_dir.clone();
-            let result_tx = result_tx.clone();
-            let remaining = remaining.clone();
+            let events = events.clone();
             let counter = counter.clone();
             let stopped = stopped.clone();
             let gpu_error = gpu_error.clone();
@@ -127,14 +161,16 @@ impl CudaBackend {
             let handle = std::thread::spawn(move || {
                 if let Err(e) = Self::gpu_worker(
                     device_idx as u32,
-                    prefixes,
+                    state,
                     output_dir,
-                    result_tx,
-                    remaining,
+                    &events,
                     counter,
                     stopped.clone(),
                 ) {
-                    eprintln!("GPU {} error: {}", device_idx, e);
+                    let _ = events.send(SearchEvent::WorkerError {
+                        worker: format!("GPU {}", device_idx),
+                        error: e.to_string(),
+                    });
                     *gpu_error.lock().unwrap() = Some(format!("GPU {} error: {}", device_idx, e));
                     stopped.store(true, Ordering::SeqCst);
                 }
@@ -146,10 +182,12 @@ impl CudaBackend {
         // Progress reporting thread
         let progress_stopped = stopped.clone();
         let progress_counter = counter.clone();
-        let progress_remaining = remaining.clone();
+        let progress_state = state.clone();
+        let progress_events = events.clone();
         let progress_handle = std::thread::spawn(move || {
             while !progress_stopped.load(Ordering::Relaxed) {
                 let keys_checked = progress_counter.load(Ordering::Relaxed);
+                progress_state.report_checked(keys_checked);
                 let elapsed = start_time.elapsed().as_secs_f64();
                 let keys_per_sec = if elapsed > 0.0 {
                     keys_checked as f64 / elapsed
@@ -157,14 +195,15 @@ impl CudaBackend {
                     0.0
                 };
 
-                let _ = progress_tx.send(Progress {
+                let _ = progress_events.send(SearchEvent::Progress(Progress {
                     keys_checked,
                     keys_per_sec,
                     elapsed_secs: elapsed,
-                });
+                    found: progress_state.hit_counts(),
+                }));
 
                 // Check if done
-                if progress_remaining.lock().unwrap().is_empty() {
+                if progress_state.is_done() {
                     break;
                 }
 
@@ -182,7 +221,7 @@ impl CudaBackend {
 
         // Wait for completion or all prefixes found
         loop {
-            if remaining.lock().unwrap().is_empty() || stopped.load(Ordering::SeqCst) {
+            if state.is_done() || stopped.load(Ordering::SeqCst) {
                 stopped.store(true, Ordering::SeqCst);
                 break;
             }
@@ -196,23 +235,18 @@ impl CudaBackend {
         let _ = progress_handle.join();
 
         // Check for GPU errors
-        if let Some(err) = gpu_error.lock().unwrap().take() {
-            return Err(GeneratorError::Cuda(err));
-        }
-
-        if stopped.load(Ordering::SeqCst) && !remaining.lock().unwrap().is_empty() {
-            Err(GeneratorError::Stopped)
-        } else {
-            Ok(())
+        let err = gpu_error.lock().unwrap().take();
+        match err {
+            Some(err) => Err(GeneratorError::Cuda(err)),
+            None => Ok(()),
         }
     }
 
     fn gpu_worker(
         device_idx: u32,
-        prefixes: Vec<String>,
+        state: Arc<SearchState>,
         output_dir: PathBuf,
-        result_tx: Sender<FoundKey>,
-        remaining: Arc<Mutex<HashSet<String>>>,
+        events: &Sender<SearchEvent>,
         counter: Arc<AtomicU64>,
         stopped: Arc<AtomicBool>,
     ) -> Result<(), GeneratorError> {
@@ -235,7 +269,7 @@ impl CudaBackend {
             .map_err(|e| GeneratorError::Cuda(format!("Failed to load module: {}", e)))?;
 
         let function = kernel
-            .get_function(std::ffi::CStr::from_bytes_with_nul(b"render\0").unwrap())
+            .get_function(c"render")
             .map_err(|e| GeneratorError::Cuda(format!("Failed to get function: {}", e)))?;
 
         let stream = Stream::new(StreamFlags::NON_BLOCKING, None)
@@ -246,25 +280,8 @@ impl CudaBackend {
         let mut gpu_seed = DeviceBuffer::from_slice(&seed)
             .map_err(|e| GeneratorError::Cuda(format!("Failed to allocate seed buffer: {}", e)))?;
 
-        let mut byte_prefixes_owned: Vec<_> = prefixes
-            .iter()
-            .map(|s| BytePrefixOwned::from_str(s))
-            .collect();
-
-        let mut byte_prefixes: Vec<_> = byte_prefixes_owned
-            .iter_mut()
-            .map(|bp| bp.as_byte_prefix())
-            .collect();
-
-        let mut gpu_byte_prefixes = DeviceBuffer::from_slice(&byte_prefixes)
-            .map_err(|e| GeneratorError::Cuda(format!("Failed to allocate prefix buffer: {}", e)))?;
-
-        let mut params = DeviceBox::new(&core::KernelParams {
-            seed: gpu_seed.as_device_ptr(),
-            byte_prefixes: gpu_byte_prefixes.as_device_ptr(),
-            byte_prefixes_len: gpu_byte_prefixes.len(),
-        })
-        .map_err(|e| GeneratorError::Cuda(format!("Failed to allocate params: {}", e)))?;
+        let mut patterns = GpuPatterns::upload(&state)?;
+        let mut params = patterns.kernel_params(&mut gpu_seed)?;
 
         // Calculate optimal thread/block configuration
         let fn_max_threads = function
@@ -290,23 +307,34 @@ impl CudaBackend {
         let threads = *[
             fn_max_threads,
             gpu_max_threads,
-            if fn_registers > 0 { gpu_max_registers / fn_registers } else { 256 },
+            gpu_max_registers.checked_div(fn_registers).unwrap_or(256),
         ]
         .iter()
         .min()
         .unwrap();
 
         let blocks = gpu_cores * gpu_max_threads / threads;
+        // Every thread checks one key per launch
+        let keys_per_launch = u64::from(threads) * u64::from(blocks);
 
         let mut rng = rand::thread_rng();
 
         // Main generation loop
         while !stopped.load(Ordering::Relaxed) {
+            // Park while paused; the context and buffers stay allocated
+            state.wait_while_paused();
+
             // Check if all prefixes found
-            if remaining.lock().unwrap().is_empty() {
+            if state.is_done() {
                 break;
             }
 
+            // Patterns were added or removed since the last upload
+            if state.patterns_version() != patterns.version {
+                patterns = GpuPatterns::upload(&state)?;
+                params = patterns.kernel_params(&mut gpu_seed)?;
+            }
+
             // Generate new random seed
             rng.fill_bytes(&mut seed);
             gpu_seed.copy_from(&seed)
@@ -321,117 +349,164 @@ impl CudaBackend {
             stream.synchronize()
                 .map_err(|e| GeneratorError::Cuda(format!("Stream sync failed: {}", e)))?;
 
-            // Check results
-            gpu_byte_prefixes.copy_to(&mut byte_prefixes)
-                .map_err(|e| GeneratorError::Cuda(format!("Failed to copy results: {}", e)))?;
-
-            for (i, prefix) in byte_prefixes_owned.iter_mut().enumerate() {
-                let mut success = false;
-                prefix.success.copy_to(&mut success).ok();
-
-                if success {
-                    prefix.success.copy_from(&false).ok();
-
-                    let mut out = [0u8; 32];
-                    prefix.out.copy_to(&mut out).ok();
-
-                    // Generate full keypair from seed
-                    let esk: ed25519_dalek::hazmat::ExpandedSecretKey =
-                        ed25519_dalek::hazmat::ExpandedSecretKey::from_bytes(&{
-                            let mut expanded = [0u8; 64];
-                            // The GPU outputs the scalar, we need to reconstruct
-                            expanded[..32].copy_from_slice(&out);
-                            expanded
-                        });
-
-                    // Actually we need to re-derive from the original approach
-                    // The GPU kernel outputs a modified seed that produces the match
-                    let signing_key = ed25519_dalek::SigningKey::from_bytes(&out);
-                    let verifying_key = signing_key.verifying_key();
-                    let onion = pubkey_to_onion(&verifying_key.to_bytes());
-
-                    let prefix_str = &prefixes[i];
-
-                    // Remove from remaining
-                    remaining.lock().unwrap().remove(prefix_str);
-
-                    // Save key file
-                    let key_path = output_dir.join(&onion);
-                    if let Ok(mut f) = std::fs::File::create(&key_path) {
-                        let expanded = signing_key.to_keypair_bytes();
-                        let _ = f.write_all(FILE_PREFIX);
-                        let _ = f.write_all(&expanded);
-                        let _ = f.flush();
-
-                        let _ = result_tx.send(FoundKey {
-                            prefix: prefix_str.clone(),
-                            onion_address: onion,
-                            key_path,
-                        });
-                    }
-                }
-            }
+            // Counted before the hits are saved, so they report the keys
+            // checked up to and including their own launch
+            let keys_checked = counter.fetch_add(keys_per_launch, Ordering::Relaxed) + keys_per_launch;
+            state.report_checked(keys_checked);
 
-            counter.fetch_add((threads * blocks) as u64, Ordering::Relaxed);
+            // The kernel cannot see checksum bits or the contains words, so
+            // hits are re-checked on the host
+            let hits = patterns.take_hits()?;
+            process_hits(&state, hits, &output_dir, events);
         }
 
         Ok(())
     }
 }
 
-/// GPU-side prefix matching structure
-struct BytePrefixOwned {
-    byte_prefix: DeviceBuffer<u8>,
-    last_byte_idx: usize,
-    last_byte_mask: u8,
-    out: DeviceBuffer<u8>,
-    success: DeviceBox<bool>,
+impl SearchBackend for CudaBackend {
+    fn info(&self) -> BackendInfo {
+        self.info()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            regex: false,
+            anchored_patterns: true,
+            seeds: true,
+            gpu: true,
+            live_patterns: true,
+        }
+    }
+
+    fn search(
+        &self,
+        state: Arc<SearchState>,
+        output_dir: PathBuf,
+        channels: SearchChannels,
+    ) -> Result<(), GeneratorError> {
+        self.run(state, output_dir, channels.events, channels.stop)
+    }
 }
 
-impl BytePrefixOwned {
-    fn from_str(s: &str) -> Self {
-        let byte_prefix = base32::decode(
-            base32::Alphabet::Rfc4648Lower { padding: false },
-            &format!("{}aa", s),
-        )
-        .expect("prefix must be base32");
+/// Patterns of a search as uploaded to one GPU
+struct GpuPatterns {
+    /// Index in the search state of the pattern each GPU slot belongs to
+    indices: Vec<usize>,
+    /// Keeps the pattern bytes the device patterns point to alive
+    _owned: Vec<BytePatternOwned>,
+    device: DeviceBuffer<core::BytePattern>,
+    /// Candidates per GPU slot found by the last launch
+    hit_counts: DeviceBuffer<u32>,
+    /// [`HIT_CAPACITY`] seeds per GPU slot
+    hit_seeds: DeviceBuffer<u8>,
+    host_counts: Vec<u32>,
+    host_seeds: Vec<u8>,
+    /// [`SearchState::patterns_version`] at the time of the upload
+    version: u64,
+}
 
-        let mut last_byte_idx = 5 * s.len() / 8;
-        if last_byte_idx > 0 {
-            last_byte_idx -= 1;
+impl GpuPatterns {
+    /// Upload the current patterns of `state`
+    fn upload(state: &SearchState) -> Result<Self, GeneratorError> {
+        let version = state.patterns_version();
+
+        // Look-alike patterns are uploaded once per spelling; removed
+        // patterns stay, as hits for them are dropped on the host anyway
+        let mut indices = Vec::new();
+        let mut owned = Vec::new();
+        for (index, pattern) in state.patterns().enumerate() {
+            for variant in pattern.byte_variants()? {
+                indices.push(index);
+                owned.push(BytePatternOwned::new(&variant)?);
+            }
         }
 
-        let n_bits = (5 * s.len()) % 8;
-        let last_byte_mask = ((1u16 << n_bits) - 1) as u8;
-        let last_byte_mask = last_byte_mask << (8 - n_bits);
+        let alloc_err = |e| GeneratorError::Cuda(format!("Failed to allocate hit buffers: {}", e));
+        let host_counts = vec![0u32; owned.len()];
+        let host_seeds = vec![0u8; owned.len() * HIT_CAPACITY * 32];
+        let mut hit_counts = DeviceBuffer::from_slice(&host_counts).map_err(alloc_err)?;
+        let mut hit_seeds = DeviceBuffer::from_slice(&host_seeds).map_err(alloc_err)?;
 
-        if last_byte_mask > 0 && last_byte_idx < byte_prefix.len() - 1 {
-            last_byte_idx += 1;
-        }
+        let host: Vec<_> = owned
+            .iter_mut()
+            .zip(hit_counts.chunks_mut(1))
+            .zip(hit_seeds.chunks_mut(HIT_CAPACITY * 32))
+            .map(|((bp, count), seeds)| bp.as_byte_pattern(count.as_device_ptr(), seeds.as_device_ptr()))
+            .collect();
+        let device = DeviceBuffer::from_slice(&host)
+            .map_err(|e| GeneratorError::Cuda(format!("Failed to allocate pattern buffer: {}", e)))?;
 
-        let gpu_byte_prefix = DeviceBuffer::from_slice(&byte_prefix).unwrap();
-        let out = [0u8; 32];
-        let gpu_out = DeviceBuffer::from_slice(&out).unwrap();
-        let success = false;
-        let gpu_success = DeviceBox::new(&success).unwrap();
-
-        BytePrefixOwned {
-            byte_prefix: gpu_byte_prefix,
-            last_byte_idx,
-            last_byte_mask,
-            out: gpu_out,
-            success: gpu_success,
+        Ok(Self {
+            indices,
+            _owned: owned,
+            device,
+            hit_counts,
+            hit_seeds,
+            host_counts,
+            host_seeds,
+            version,
+        })
+    }
+
+    /// Read the candidates of the last launch and clear the buffers for the
+    /// next one
+    fn take_hits(&mut self) -> Result<Vec<GpuHit>, GeneratorError> {
+        let copy_err = |e| GeneratorError::Cuda(format!("Failed to copy results: {}", e));
+        self.hit_counts.copy_to(&mut self.host_counts).map_err(copy_err)?;
+        if self.host_counts.iter().all(|&count| count == 0) {
+            return Ok(Vec::new());
         }
+        self.hit_seeds.copy_to(&mut self.host_seeds).map_err(copy_err)?;
+
+        let hits = launch_hits(&self.indices, &self.host_counts, &self.host_seeds);
+        self.host_counts.fill(0);
+        self.hit_counts.copy_from(&self.host_counts).map_err(copy_err)?;
+        Ok(hits)
+    }
+
+    /// Kernel parameters for these patterns and `seed`
+    fn kernel_params(&mut self, seed: &mut DeviceBuffer<u8>) -> Result<DeviceBox<core::KernelParams>, GeneratorError> {
+        DeviceBox::new(&core::KernelParams {
+            seed: seed.as_device_ptr(),
+            byte_patterns: self.device.as_device_ptr(),
+            byte_patterns_len: self.device.len(),
+        })
+        .map_err(|e| GeneratorError::Cuda(format!("Failed to allocate params: {}", e)))
+    }
+}
+
+/// GPU-side pattern matching structure
+struct BytePatternOwned {
+    byte_value: DeviceBuffer<u8>,
+    byte_mask: DeviceBuffer<u8>,
+    byte_offset: usize,
+}
+
+impl BytePatternOwned {
+    fn new(pattern: &BytePattern) -> Result<Self, GeneratorError> {
+        let alloc_err = |e| GeneratorError::Cuda(format!("Failed to allocate pattern: {}", e));
+
+        // The kernel compares the masked window of public key bytes the
+        // pattern covers; checksum bits are re-checked on the host
+        Ok(BytePatternOwned {
+            byte_value: DeviceBuffer::from_slice(pattern.pubkey_value()).map_err(alloc_err)?,
+            byte_mask: DeviceBuffer::from_slice(pattern.pubkey_mask()).map_err(alloc_err)?,
+            byte_offset: pattern.pubkey_offset(),
+        })
     }
 
-    fn as_byte_prefix(&mut self) -> core::BytePrefix {
-        core::BytePrefix {
-            byte_prefix: self.byte_prefix.as_device_ptr(),
-            byte_prefix_len: self.byte_prefix.len(),
-            last_byte_idx: self.last_byte_idx,
-            last_byte_mask: self.last_byte_mask,
-            out: self.out.as_device_ptr(),
-            success: self.success.as_device_ptr(),
+    /// The kernel's view of this pattern, reporting candidates to the given
+    /// counter and seed slots
+    fn as_byte_pattern(&mut self, hit_count: DevicePointer<u32>, hits: DevicePointer<u8>) -> core::BytePattern {
+        core::BytePattern {
+            byte_value: self.byte_value.as_device_ptr(),
+            byte_mask: self.byte_mask.as_device_ptr(),
+            byte_offset: self.byte_offset,
+            byte_len: self.byte_value.len(),
+            hits,
+            hit_capacity: HIT_CAPACITY as u32,
+            hit_count,
         }
     }
 }
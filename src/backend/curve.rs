@@ -0,0 +1,478 @@
+//! Minimal GF(2^255 - 19) and Edwards point arithmetic for the batched CPU walk
+//!
+//! `curve25519-dalek` keeps projective coordinates private, so it cannot
+//! normalise many points with a single inversion. This module implements just
+//! enough of the curve to do that: mixed point addition against a fixed step
+//! point, and batch compression using Montgomery's trick.
+
+use std::sync::OnceLock;
+
+const MASK51: u64 = (1 << 51) - 1;
+
+/// Field element in radix 2^51, limbs kept below roughly 2^52
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FieldElement([u64; 5]);
+
+impl FieldElement {
+    pub const ZERO: Self = FieldElement([0; 5]);
+    pub const ONE: Self = FieldElement([1, 0, 0, 0, 0]);
+
+    fn from_u64(n: u64) -> Self {
+        FieldElement([n & MASK51, n >> 51, 0, 0, 0])
+    }
+
+    /// Load 255 bits little-endian, ignoring the top bit
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let load8 = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        FieldElement([
+            load8(0) & MASK51,
+            (load8(6) >> 3) & MASK51,
+            (load8(12) >> 6) & MASK51,
+            (load8(19) >> 1) & MASK51,
+            (load8(24) >> 12) & MASK51,
+        ])
+    }
+
+    /// Canonical little-endian encoding
+    pub fn to_bytes(self) -> [u8; 32] {
+        let mut l = self.carry().0;
+
+        // Subtract p if the value is >= p: q is 1 exactly when l + 19 >= 2^255
+        let mut q = (l[0] + 19) >> 51;
+        q = (l[1] + q) >> 51;
+        q = (l[2] + q) >> 51;
+        q = (l[3] + q) >> 51;
+        q = (l[4] + q) >> 51;
+
+        l[0] += 19 * q;
+        l[1] += l[0] >> 51;
+        l[0] &= MASK51;
+        l[2] += l[1] >> 51;
+        l[1] &= MASK51;
+        l[3] += l[2] >> 51;
+        l[2] &= MASK51;
+        l[4] += l[3] >> 51;
+        l[3] &= MASK51;
+        l[4] &= MASK51;
+
+        let mut bytes = [0u8; 32];
+        let mut acc: u128 = 0;
+        let mut acc_bits = 0;
+        let mut idx = 0;
+        for limb in l {
+            acc |= (limb as u128) << acc_bits;
+            acc_bits += 51;
+            while acc_bits >= 8 {
+                bytes[idx] = acc as u8;
+                acc >>= 8;
+                acc_bits -= 8;
+                idx += 1;
+            }
+        }
+        bytes[idx] = acc as u8;
+        bytes
+    }
+
+    /// Low bit of the canonical encoding (the "sign" of x in compressed points)
+    pub fn is_negative(self) -> bool {
+        self.to_bytes()[0] & 1 == 1
+    }
+
+    fn carry(self) -> Self {
+        let mut l = self.0;
+        l[1] += l[0] >> 51;
+        l[0] &= MASK51;
+        l[2] += l[1] >> 51;
+        l[1] &= MASK51;
+        l[3] += l[2] >> 51;
+        l[2] &= MASK51;
+        l[4] += l[3] >> 51;
+        l[3] &= MASK51;
+        l[0] += 19 * (l[4] >> 51);
+        l[4] &= MASK51;
+        FieldElement(l)
+    }
+
+    pub fn add(self, rhs: Self) -> Self {
+        let (a, b) = (self.0, rhs.0);
+        FieldElement([a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3], a[4] + b[4]]).carry()
+    }
+
+    pub fn sub(self, rhs: Self) -> Self {
+        // Add 4p first so no limb underflows
+        let (a, b) = (self.0, rhs.0);
+        FieldElement([
+            (a[0] + 0x1f_ffff_ffff_ffb4) - b[0],
+            (a[1] + 0x1f_ffff_ffff_fffc) - b[1],
+            (a[2] + 0x1f_ffff_ffff_fffc) - b[2],
+            (a[3] + 0x1f_ffff_ffff_fffc) - b[3],
+            (a[4] + 0x1f_ffff_ffff_fffc) - b[4],
+        ])
+        .carry()
+    }
+
+    pub fn neg(self) -> Self {
+        Self::ZERO.sub(self)
+    }
+
+    pub fn mul(self, rhs: Self) -> Self {
+        let (a, b) = (self.0, rhs.0);
+        let m = |x: u64, y: u64| (x as u128) * (y as u128);
+
+        let b1_19 = b[1] * 19;
+        let b2_19 = b[2] * 19;
+        let b3_19 = b[3] * 19;
+        let b4_19 = b[4] * 19;
+
+        let c0 = m(a[0], b[0]) + m(a[4], b1_19) + m(a[3], b2_19) + m(a[2], b3_19) + m(a[1], b4_19);
+        let mut c1 = m(a[1], b[0]) + m(a[0], b[1]) + m(a[4], b2_19) + m(a[3], b3_19) + m(a[2], b4_19);
+        let mut c2 = m(a[2], b[0]) + m(a[1], b[1]) + m(a[0], b[2]) + m(a[4], b3_19) + m(a[3], b4_19);
+        let mut c3 = m(a[3], b[0]) + m(a[2], b[1]) + m(a[1], b[2]) + m(a[0], b[3]) + m(a[4], b4_19);
+        let mut c4 = m(a[4], b[0]) + m(a[3], b[1]) + m(a[2], b[2]) + m(a[1], b[3]) + m(a[0], b[4]);
+
+        c1 += c0 >> 51;
+        c2 += c1 >> 51;
+        c3 += c2 >> 51;
+        c4 += c3 >> 51;
+
+        // Fold the carry out of the top limb back in: 2^255 = 19 (mod p)
+        let r0 = ((c0 as u64) & MASK51) as u128 + (c4 >> 51) * 19;
+        FieldElement([
+            (r0 as u64) & MASK51,
+            ((c1 as u64) & MASK51) + (r0 >> 51) as u64,
+            (c2 as u64) & MASK51,
+            (c3 as u64) & MASK51,
+            (c4 as u64) & MASK51,
+        ])
+    }
+
+    pub fn square(self) -> Self {
+        self.mul(self)
+    }
+
+    /// `self^exp` for a little-endian exponent
+    fn pow(self, exp: &[u8; 32]) -> Self {
+        let mut acc = Self::ONE;
+        for i in (0..256).rev() {
+            acc = acc.square();
+            if (exp[i / 8] >> (i % 8)) & 1 == 1 {
+                acc = acc.mul(self);
+            }
+        }
+        acc
+    }
+
+    /// Multiplicative inverse (`self^(p-2)`); zero maps to zero
+    pub fn invert(self) -> Self {
+        // p - 2 = 2^255 - 21
+        let mut exp = [0xff; 32];
+        exp[0] = 0xeb;
+        exp[31] = 0x7f;
+        self.pow(&exp)
+    }
+
+    /// Equality of the canonical encodings; not constant-time, so only for
+    /// public values such as the points being decoded
+    fn eq_canonical(self, rhs: Self) -> bool {
+        self.to_bytes() == rhs.to_bytes()
+    }
+}
+
+/// Curve constants derived once at runtime
+struct Constants {
+    /// Edwards `d = -121665 / 121666`
+    d: FieldElement,
+    /// `2 * d`
+    d2: FieldElement,
+    /// A square root of -1
+    sqrt_m1: FieldElement,
+}
+
+fn constants() -> &'static Constants {
+    static CONSTANTS: OnceLock<Constants> = OnceLock::new();
+    CONSTANTS.get_or_init(|| {
+        let d = FieldElement::from_u64(121665)
+            .neg()
+            .mul(FieldElement::from_u64(121666).invert());
+
+        // sqrt(-1) = 2^((p - 1) / 4), (p - 1) / 4 = 2^253 - 5
+        let mut exp = [0xff; 32];
+        exp[0] = 0xfb;
+        exp[31] = 0x1f;
+        let sqrt_m1 = FieldElement::from_u64(2).pow(&exp);
+
+        Constants {
+            d,
+            d2: d.add(d),
+            sqrt_m1,
+        }
+    })
+}
+
+/// Point in extended twisted Edwards coordinates `(X : Y : Z : T)`, `x = X/Z`, `y = Y/Z`, `xy = T/Z`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ExtendedPoint {
+    x: FieldElement,
+    y: FieldElement,
+    z: FieldElement,
+    t: FieldElement,
+}
+
+/// Affine point precomputed for mixed addition: `(y + x, y - x, 2dxy)`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AffineNielsPoint {
+    y_plus_x: FieldElement,
+    y_minus_x: FieldElement,
+    xy2d: FieldElement,
+}
+
+impl ExtendedPoint {
+    /// Decode a compressed Edwards point (RFC 8032 section 5.1.3)
+    pub fn from_compressed(bytes: &[u8; 32]) -> Option<Self> {
+        let c = constants();
+        let y = FieldElement::from_bytes(bytes);
+        let sign = bytes[31] >> 7 == 1;
+
+        let yy = y.square();
+        let u = yy.sub(FieldElement::ONE);
+        let v = c.d.mul(yy).add(FieldElement::ONE);
+
+        // x = u v^3 (u v^7)^((p - 5) / 8), (p - 5) / 8 = 2^252 - 3
+        let mut exp = [0xff; 32];
+        exp[0] = 0xfd;
+        exp[31] = 0x0f;
+        let v3 = v.square().mul(v);
+        let v7 = v3.square().mul(v);
+        let mut x = u.mul(v3).mul(u.mul(v7).pow(&exp));
+
+        let check = v.mul(x.square());
+        if check.eq_canonical(u) {
+            // x is already a root
+        } else if check.eq_canonical(u.neg()) {
+            x = x.mul(c.sqrt_m1);
+        } else {
+            return None;
+        }
+
+        if x.is_negative() != sign {
+            x = x.neg();
+        }
+
+        Some(ExtendedPoint {
+            x,
+            y,
+            z: FieldElement::ONE,
+            t: x.mul(y),
+        })
+    }
+
+    /// Normalise to affine coordinates for use as the fixed operand of [`Self::add_niels`]
+    pub fn to_affine_niels(self) -> AffineNielsPoint {
+        let zinv = self.z.invert();
+        let x = self.x.mul(zinv);
+        let y = self.y.mul(zinv);
+        AffineNielsPoint {
+            y_plus_x: y.add(x),
+            y_minus_x: y.sub(x),
+            xy2d: x.mul(y).mul(constants().d2),
+        }
+    }
+
+    /// `self + q` (7 field multiplications)
+    pub fn add_niels(&self, q: &AffineNielsPoint) -> Self {
+        let a = self.y.sub(self.x).mul(q.y_minus_x);
+        let b = self.y.add(self.x).mul(q.y_plus_x);
+        let c = self.t.mul(q.xy2d);
+        let d = self.z.add(self.z);
+
+        let e = b.sub(a);
+        let f = d.sub(c);
+        let g = d.add(c);
+        let h = b.add(a);
+
+        ExtendedPoint {
+            x: e.mul(f),
+            y: g.mul(h),
+            z: f.mul(g),
+            t: e.mul(h),
+        }
+    }
+}
+
+/// Compress `points` into `out` using a single field inversion
+///
+/// Montgomery's trick: invert the product of all `Z`, then peel individual
+/// inverses off with running prefix products, at three multiplications per
+/// point. `scratch` is reused between calls to avoid reallocating.
+pub(crate) fn compress_batch(
+    points: &[ExtendedPoint],
+    scratch: &mut Vec<FieldElement>,
+    out: &mut [[u8; 32]],
+) {
+    debug_assert_eq!(points.len(), out.len());
+    if points.is_empty() {
+        return;
+    }
+
+    // scratch[i] = z_0 * z_1 * ... * z_i
+    scratch.clear();
+    let mut acc = FieldElement::ONE;
+    for p in points {
+        acc = acc.mul(p.z);
+        scratch.push(acc);
+    }
+
+    let mut inv = acc.invert();
+    for i in (0..points.len()).rev() {
+        let zinv = if i > 0 { inv.mul(scratch[i - 1]) } else { inv };
+        inv = inv.mul(points[i].z);
+
+        let x = points[i].x.mul(zinv);
+        let y = points[i].y.mul(zinv);
+        let mut bytes = y.to_bytes();
+        bytes[31] ^= (x.is_negative() as u8) << 7;
+        out[i] = bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+    use curve25519_dalek::edwards::CompressedEdwardsY;
+    use curve25519_dalek::Scalar;
+    use proptest::prelude::*;
+
+    /// The same value with p added limb by limb, so every limb sits near the
+    /// 2^52 bound instead of below 2^51
+    fn unreduced(a: FieldElement) -> FieldElement {
+        let p = [MASK51 - 18, MASK51, MASK51, MASK51, MASK51];
+        FieldElement(std::array::from_fn(|i| a.0[i] + p[i]))
+    }
+
+    fn compress(point: ExtendedPoint) -> [u8; 32] {
+        let mut out = [[0u8; 32]];
+        compress_batch(&[point], &mut Vec::new(), &mut out);
+        out[0]
+    }
+
+    #[test]
+    fn test_decompress_roundtrip() {
+        for i in 1..32u64 {
+            let p = ED25519_BASEPOINT_POINT * Scalar::from(i * 7919);
+            let bytes = p.compress().to_bytes();
+            let ours = ExtendedPoint::from_compressed(&bytes).unwrap();
+
+            let mut out = [[0u8; 32]];
+            compress_batch(&[ours], &mut Vec::new(), &mut out);
+            assert_eq!(out[0], bytes);
+        }
+    }
+
+    #[test]
+    fn test_add_and_batch_compress_match_dalek() {
+        let start = ED25519_BASEPOINT_POINT * Scalar::from(123_456_789u64);
+        let step = ED25519_BASEPOINT_POINT.mul_by_cofactor();
+
+        let step_niels = ExtendedPoint::from_compressed(&step.compress().to_bytes())
+            .unwrap()
+            .to_affine_niels();
+        let mut ours = ExtendedPoint::from_compressed(&start.compress().to_bytes()).unwrap();
+        let mut theirs = start;
+
+        let mut points = Vec::new();
+        let mut expected = Vec::new();
+        for _ in 0..100 {
+            points.push(ours);
+            expected.push(theirs.compress().to_bytes());
+            ours = ours.add_niels(&step_niels);
+            theirs += step;
+        }
+
+        let mut out = vec![[0u8; 32]; points.len()];
+        compress_batch(&points, &mut Vec::new(), &mut out);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_values_from_p_up_encode_canonically() {
+        // p = 2^255 - 19; 2^255 - 1 is the largest value 255 bits hold
+        for k in 0..19u8 {
+            let mut bytes = [0xff; 32];
+            bytes[0] = 0xed + k;
+            bytes[31] = 0x7f;
+            let mut expected = [0u8; 32];
+            expected[0] = k;
+            assert_eq!(FieldElement::from_bytes(&bytes).to_bytes(), expected);
+
+            // Non-canonical y coordinates decode like dalek decodes them
+            for sign in [0, 0x80] {
+                bytes[31] = 0x7f | sign;
+                let theirs = CompressedEdwardsY(bytes).decompress().map(|p| p.compress().to_bytes());
+                assert_eq!(ExtendedPoint::from_compressed(&bytes).map(compress), theirs, "y = p + {}", k);
+            }
+        }
+    }
+
+    #[test]
+    fn test_identity_compresses_like_dalek() {
+        let identity = curve25519_dalek::EdwardsPoint::default().compress().to_bytes();
+        let ours = ExtendedPoint::from_compressed(&identity).unwrap();
+
+        let mut out = [[0u8; 32]];
+        compress_batch(&[ours], &mut Vec::new(), &mut out);
+        assert_eq!(out[0], identity);
+    }
+
+    proptest! {
+        // dalek's scalar multiplication is slow in test builds
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_mul_and_invert_match_dalek(scalar in any::<[u8; 32]>(), edge_limbs in any::<bool>()) {
+            // dalek keeps its field private, but maps y to the Montgomery
+            // u = (1 + y) / (1 - y) with the same multiplication and inversion
+            let point = ED25519_BASEPOINT_POINT * Scalar::from_bytes_mod_order(scalar);
+            let y = FieldElement::from_bytes(&point.compress().to_bytes());
+            let (mut num, mut den) = (FieldElement::ONE.add(y), FieldElement::ONE.sub(y));
+            if edge_limbs {
+                num = unreduced(num);
+                den = unreduced(den);
+            }
+            let mut den_inv = den.invert();
+            if edge_limbs {
+                den_inv = unreduced(den_inv);
+            }
+            prop_assert_eq!(num.mul(den_inv).to_bytes(), point.to_montgomery().to_bytes());
+        }
+
+        #[test]
+        fn prop_decompress_matches_dalek(bytes in any::<[u8; 32]>()) {
+            // Random bytes are a point half the time, and not always canonical
+            let theirs = CompressedEdwardsY(bytes).decompress().map(|p| p.compress().to_bytes());
+            prop_assert_eq!(ExtendedPoint::from_compressed(&bytes).map(compress), theirs);
+        }
+
+        #[test]
+        fn prop_batch_compress_matches_dalek(start in any::<[u8; 32]>(), step in any::<[u8; 32]>(), len in 1..64usize) {
+            let start = ED25519_BASEPOINT_POINT * Scalar::from_bytes_mod_order(start);
+            let step = ED25519_BASEPOINT_POINT * Scalar::from_bytes_mod_order(step);
+            let step_niels = ExtendedPoint::from_compressed(&step.compress().to_bytes()).unwrap().to_affine_niels();
+
+            let mut ours = ExtendedPoint::from_compressed(&start.compress().to_bytes()).unwrap();
+            let mut theirs = start;
+            let mut points = Vec::new();
+            let mut expected = Vec::new();
+            for _ in 0..len {
+                points.push(ours);
+                expected.push(theirs.compress().to_bytes());
+                ours = ours.add_niels(&step_niels);
+                theirs += step;
+            }
+
+            let mut out = vec![[0u8; 32]; len];
+            compress_batch(&points, &mut Vec::new(), &mut out);
+            prop_assert_eq!(out, expected);
+        }
+    }
+}
@@ -2,20 +2,26 @@
 //!
 //! This backend spawns an external CUDA process for GPU-accelerated generation.
 
-use crate::onion::pubkey_to_onion;
-use crate::{FILE_PREFIX, PUBKEY_PREFIX};
+use crate::hs_dir::expand_scalar;
 use crossbeam_channel::{Receiver, Sender};
 use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
 use curve25519_dalek::Scalar;
-use std::collections::HashSet;
-use std::io::{BufRead, BufReader, Write};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
-use std::process::{Child, Command, Stdio};
+use std::process::{Child, ChildStderr, Command, Stdio};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 use std::time::Instant;
 
-use super::{BackendInfo, FoundKey, GeneratorError, Progress, SearchFilter};
+use super::{
+    BackendInfo, Capabilities, GeneratorError, Progress, SearchBackend, SearchChannels, SearchEvent, SearchFilter,
+    SearchState,
+};
+
+/// Lines of the tool's stderr kept for the error when it exits early
+const STDERR_TAIL_LINES: usize = 10;
 
 /// External CUDA backend that spawns vanity_torv3_cuda executable
 #[derive(Debug, Clone)]
@@ -42,13 +48,11 @@ impl ExternalCudaBackend {
             Some(PathBuf::from("dist/vanity_torv3_cuda.exe")),
         ];
 
-        for path_opt in possible_paths.iter() {
-            if let Some(path) = path_opt {
-                if path.exists() {
-                    return Ok(Self {
-                        exe_path: path.clone(),
-                    });
-                }
+        for path in possible_paths.iter().flatten() {
+            if path.exists() {
+                return Ok(Self {
+                    exe_path: path.clone(),
+                });
             }
         }
 
@@ -82,11 +86,10 @@ impl ExternalCudaBackend {
         &self,
         prefixes: Vec<String>,
         output_dir: PathBuf,
-        progress_tx: Sender<Progress>,
-        result_tx: Sender<FoundKey>,
+        events: Sender<SearchEvent>,
         stop_rx: Receiver<()>,
     ) -> Result<(), GeneratorError> {
-        self.generate_with_filter(prefixes, output_dir, progress_tx, result_tx, stop_rx, SearchFilter::default())
+        self.generate_with_filter(prefixes, output_dir, events, stop_rx, SearchFilter::default())
     }
 
     /// Start generation using external CUDA process with additional filter
@@ -94,35 +97,54 @@ impl ExternalCudaBackend {
         &self,
         prefixes: Vec<String>,
         output_dir: PathBuf,
-        progress_tx: Sender<Progress>,
-        result_tx: Sender<FoundKey>,
+        events: Sender<SearchEvent>,
         stop_rx: Receiver<()>,
         filter: SearchFilter,
     ) -> Result<(), GeneratorError> {
-        // Validate prefixes
-        for prefix in &prefixes {
-            if base32::decode(
-                base32::Alphabet::Rfc4648Lower { padding: false },
-                &format!("{}aa", prefix),
-            )
-            .is_none()
-            {
-                return Err(GeneratorError::InvalidPrefix(prefix.clone()));
+        // Validate and compile patterns
+        let state = Arc::new(SearchState::with_filter(&prefixes, &filter)?.with_backend(self.info().name));
+        self.run(state, output_dir, events, stop_rx)
+    }
+
+    /// Search for the patterns `state` starts with; patterns removed later
+    /// are ignored, but added ones never reach the tool
+    ///
+    /// While paused, its output is not read, so the tool blocks as soon as
+    /// the pipe is full.
+    pub fn run(
+        &self,
+        state: Arc<SearchState>,
+        output_dir: PathBuf,
+        events: Sender<SearchEvent>,
+        stop_rx: Receiver<()>,
+    ) -> Result<(), GeneratorError> {
+        // The external tool only understands plain prefixes; look-alike
+        // patterns are passed as every spelling
+        let mut tool_prefixes = Vec::new();
+        for pattern in state.patterns() {
+            for variant in pattern.byte_variants()? {
+                let prefix = variant.plain_prefix().ok_or_else(|| {
+                    GeneratorError::Cuda(format!(
+                        "vanity_torv3_cuda only supports prefixes, not '{}'; use the CPU or built-in CUDA backend",
+                        pattern.pattern()
+                    ))
+                })?;
+                tool_prefixes.push(prefix.to_uppercase());
             }
         }
 
         // Create output directory if needed
         if !output_dir.exists() {
             std::fs::create_dir_all(&output_dir)
-                .map_err(|e| GeneratorError::Io(e))?;
+                .map_err(GeneratorError::Io)?;
         }
 
-        // Build command - convert prefixes to uppercase for the CUDA tool
+        // Build command - the CUDA tool expects uppercase prefixes
         let mut cmd = Command::new(&self.exe_path);
         cmd.arg("-i"); // Enable rate reporting
 
-        for prefix in &prefixes {
-            cmd.arg(prefix.to_uppercase());
+        for prefix in &tool_prefixes {
+            cmd.arg(prefix);
         }
 
         cmd.stdout(Stdio::piped());
@@ -135,18 +157,14 @@ impl ExternalCudaBackend {
 
         let stdout = child.stdout.take().unwrap();
         let reader = BufReader::new(stdout);
+        let stderr_tail = read_stderr_tail(child.stderr.take().unwrap());
 
         // Shared state
-        let remaining: Arc<Mutex<HashSet<String>>> =
-            Arc::new(Mutex::new(prefixes.iter().cloned().collect()));
         let counter = Arc::new(AtomicU64::new(0));
         let stopped = Arc::new(AtomicBool::new(false));
         let start_time = Instant::now();
         let child_arc: Arc<Mutex<Option<Child>>> = Arc::new(Mutex::new(Some(child)));
 
-        // Prepare contains words (lowercase for case-insensitive matching)
-        let contains_words: Vec<String> = filter.contains.iter().map(|w| w.to_lowercase()).collect();
-
         // Stop signal handler
         let stop_stopped = stopped.clone();
         let stop_child = child_arc.clone();
@@ -163,10 +181,12 @@ impl ExternalCudaBackend {
         // Progress reporting thread
         let progress_stopped = stopped.clone();
         let progress_counter = counter.clone();
-        let progress_remaining = remaining.clone();
+        let progress_state = state.clone();
+        let progress_events = events.clone();
         std::thread::spawn(move || {
             while !progress_stopped.load(Ordering::Relaxed) {
                 let keys_checked = progress_counter.load(Ordering::Relaxed);
+                progress_state.report_checked(keys_checked);
                 let elapsed = start_time.elapsed().as_secs_f64();
                 let keys_per_sec = if elapsed > 0.0 {
                     keys_checked as f64 / elapsed
@@ -174,13 +194,14 @@ impl ExternalCudaBackend {
                     0.0
                 };
 
-                let _ = progress_tx.send(Progress {
+                let _ = progress_events.send(SearchEvent::Progress(Progress {
                     keys_checked,
                     keys_per_sec,
                     elapsed_secs: elapsed,
-                });
+                    found: progress_state.hit_counts(),
+                }));
 
-                if progress_remaining.lock().unwrap().is_empty() {
+                if progress_state.is_done() {
                     break;
                 }
 
@@ -190,6 +211,7 @@ impl ExternalCudaBackend {
 
         // Read output from CUDA process
         for line in reader.lines() {
+            state.wait_while_paused();
             if stopped.load(Ordering::Relaxed) {
                 break;
             }
@@ -203,7 +225,6 @@ impl ExternalCudaBackend {
             if line.starts_with("KEYRATE:") {
                 if let Some(rate_str) = line.strip_prefix("KEYRATE:") {
                     if let Some(mkeys) = rate_str
-                        .trim()
                         .split_whitespace()
                         .next()
                         .and_then(|s| s.parse::<f64>().ok())
@@ -233,91 +254,105 @@ impl ExternalCudaBackend {
             let scalar = Scalar::from_bytes_mod_order(scalar_bytes);
             let public_key_point = scalar * ED25519_BASEPOINT_POINT;
             let public_key_bytes = public_key_point.compress().to_bytes();
-            let onion = pubkey_to_onion(&public_key_bytes);
 
-            // Check which prefix matched
-            let mut matched_prefix = None;
-            for prefix in remaining.lock().unwrap().iter() {
-                if onion.to_lowercase().starts_with(&prefix.to_lowercase()) {
-                    matched_prefix = Some(prefix.clone());
-                    break;
-                }
-            }
-
-            // Check if address also contains all required words
-            if matched_prefix.is_some() && !contains_words.is_empty() {
-                let onion_lower = onion.to_lowercase();
-                if !contains_words.iter().all(|word| onion_lower.contains(word)) {
-                    // Prefix matched but contains filter failed - skip this address
-                    matched_prefix = None;
-                }
-            }
-
-            if let Some(prefix) = matched_prefix {
-                // Remove from remaining
-                remaining.lock().unwrap().remove(&prefix);
-
-                // Create Tor hidden service directory structure
-                // Strip .onion suffix for directory name
-                let dir_name = onion.trim_end_matches(".onion");
-                let hs_dir = output_dir.join(dir_name);
+            // Check which prefix matched, and that the address also contains
+            // all required words
+            let matched_prefix = state.find_hit(&public_key_bytes);
 
-                if std::fs::create_dir_all(&hs_dir).is_ok() {
-                    // 1. Write hostname file
-                    let hostname_path = hs_dir.join("hostname");
-                    if let Ok(mut f) = std::fs::File::create(&hostname_path) {
-                        let _ = writeln!(f, "{}", onion);
-                    }
+            if let Some((index, _)) = matched_prefix {
+                let Some(sequence) = state.claim(index) else {
+                    continue;
+                };
 
-                    // 2. Write hs_ed25519_public_key (32-byte tag + 32-byte pubkey)
-                    let pubkey_path = hs_dir.join("hs_ed25519_public_key");
-                    if let Ok(mut f) = std::fs::File::create(&pubkey_path) {
-                        let _ = f.write_all(PUBKEY_PREFIX);
-                        let _ = f.write_all(&public_key_bytes);
-                    }
+                // The tool only reports the scalar, so there is no seed to
+                // derive the nonce half from
+                let expanded = expand_scalar(&scalar_bytes);
 
-                    // 3. Write hs_ed25519_secret_key (32-byte tag + 64-byte expanded key)
-                    // expanded_secret_key = scalar (32 bytes) || nonce_prefix (32 bytes)
-                    // Since we only have the scalar from CUDA, use pubkey as nonce placeholder
-                    let secret_path = hs_dir.join("hs_ed25519_secret_key");
-                    if let Ok(mut f) = std::fs::File::create(&secret_path) {
-                        let mut expanded = [0u8; 64];
-                        expanded[..32].copy_from_slice(&scalar_bytes);
-                        expanded[32..].copy_from_slice(&public_key_bytes);
-                        let _ = f.write_all(FILE_PREFIX);
-                        let _ = f.write_all(&expanded);
+                match state.save_hit(&output_dir, index, sequence, &public_key_bytes, &expanded, false) {
+                    Ok(found) => {
+                        let _ = events.send(SearchEvent::KeyFound(found));
                     }
-
-                    // 4. Create authorized_clients directory (required by Tor)
-                    let _ = std::fs::create_dir_all(hs_dir.join("authorized_clients"));
-
-                    let _ = result_tx.send(FoundKey {
-                        prefix,
-                        onion_address: onion,
-                        key_path: hs_dir,
-                    });
+                    Err(e) => state.fail(e),
                 }
 
                 // Check if all done
-                if remaining.lock().unwrap().is_empty() {
+                if state.is_done() {
                     stopped.store(true, Ordering::SeqCst);
                     break;
                 }
             }
         }
 
-        // Kill child process if still running
+        // Output ends without a stop or a full quota only if the tool exited
+        let exited = !stopped.swap(true, Ordering::SeqCst) && !state.is_done();
+        let mut status = None;
         if let Some(mut child) = child_arc.lock().unwrap().take() {
-            let _ = child.kill();
-            let _ = child.wait();
+            if !exited {
+                let _ = child.kill();
+            }
+            status = child.wait().ok();
         }
 
-        if stopped.load(Ordering::SeqCst) && !remaining.lock().unwrap().is_empty() {
-            Err(GeneratorError::Stopped)
-        } else {
+        if let Some(e) = state.take_failure() {
+            return Err(e);
+        }
+        if exited {
+            let status = status.map_or_else(|| "an unknown status".to_string(), |status| status.to_string());
+            let tail = stderr_tail.join().unwrap_or_default();
+            return Err(GeneratorError::Cuda(format!(
+                "vanity_torv3_cuda exited with {} before every pattern was found{}",
+                status,
+                if tail.is_empty() { String::new() } else { format!(": {}", tail) }
+            )));
+        }
+        if state.is_done() {
             Ok(())
+        } else {
+            Err(GeneratorError::Stopped)
+        }
+    }
+}
+
+/// Read the tool's stderr until it closes, keeping the last lines
+///
+/// The pipe has to be drained anyway, or the tool blocks once it is full.
+fn read_stderr_tail(stderr: ChildStderr) -> JoinHandle<String> {
+    std::thread::spawn(move || {
+        let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            if tail.len() == STDERR_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+        Vec::from(tail).join("\n")
+    })
+}
+
+impl SearchBackend for ExternalCudaBackend {
+    fn info(&self) -> BackendInfo {
+        self.info()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        // The tool only searches for prefixes and reports scalars
+        Capabilities {
+            regex: false,
+            anchored_patterns: false,
+            seeds: false,
+            gpu: true,
+            live_patterns: false,
         }
     }
+
+    fn search(
+        &self,
+        state: Arc<SearchState>,
+        output_dir: PathBuf,
+        channels: SearchChannels,
+    ) -> Result<(), GeneratorError> {
+        self.run(state, output_dir, channels.events, channels.stop)
+    }
 }
 
 fn hex_to_bytes(hex: &str) -> Option<[u8; 32]> {
@@ -331,3 +366,25 @@ fn hex_to_bytes(hex: &str) -> Option<[u8; 32]> {
     }
     Some(bytes)
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_early_exit_is_an_error() {
+        // `ls -i ABCDE` fails like a tool that gives up: with a status and a
+        // message on stderr, before any key is found
+        let backend = ExternalCudaBackend::with_path(PathBuf::from("/bin/ls")).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let (events, _events_rx) = crossbeam_channel::unbounded();
+        let (_stop, stop_rx) = crossbeam_channel::unbounded();
+
+        let result = backend.generate(vec!["abcde".to_string()], dir.path().to_path_buf(), events, stop_rx);
+        let Err(GeneratorError::Cuda(message)) = result else {
+            panic!("expected a CUDA error, got {:?}", result);
+        };
+        assert!(message.contains("exited with exit status"), "{}", message);
+        assert!(message.contains("ABCDE"), "{}", message);
+    }
+}
@@ -0,0 +1,230 @@
+//! Host-side handling of hits reported by a GPU kernel
+//!
+//! Kernels only compare the public key bytes a pattern covers and hand back
+//! the seed of the candidate. What they cannot see, the checksum bits of a
+//! pattern reaching the 52nd character and the `contains` words, is checked
+//! here before the pattern is claimed. A report that fails is dropped and
+//! its pattern stays pending, so the kernel keeps searching for it.
+//!
+//! A launch can report up to [`HIT_CAPACITY`] candidates per pattern, so a
+//! near miss does not hide the good candidates found alongside it, and a
+//! pattern collecting several keys gets all of them.
+
+use std::path::Path;
+
+use crossbeam_channel::Sender;
+
+use crate::hs_dir::expand_seed;
+
+use super::{SearchEvent, SearchState};
+
+/// A candidate a kernel reported for one of the search's patterns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct GpuHit {
+    /// Index of the pattern in the [`SearchState`]
+    pub pattern: usize,
+    /// Seed of the candidate key
+    pub seed: [u8; 32],
+}
+
+/// Candidates per pattern a kernel launch has room for; the kernel drops
+/// the rest of that launch's candidates
+pub(super) const HIT_CAPACITY: usize = 64;
+
+/// Hits a launch left in its hit buffers
+///
+/// GPU pattern `i` belongs to pattern `indices[i]` of the search state. The
+/// kernel counted `counts[i]` candidates for it and wrote the seeds of the
+/// first [`HIT_CAPACITY`] to `seeds`, 32 bytes each, starting at byte
+/// `i * HIT_CAPACITY * 32`.
+pub(super) fn launch_hits(indices: &[usize], counts: &[u32], seeds: &[u8]) -> Vec<GpuHit> {
+    let mut hits = Vec::new();
+    for ((&pattern, &count), slots) in indices.iter().zip(counts).zip(seeds.chunks_exact(HIT_CAPACITY * 32)) {
+        let written = (count as usize).min(HIT_CAPACITY);
+        for seed in slots.chunks_exact(32).take(written) {
+            hits.push(GpuHit {
+                pattern,
+                seed: seed.try_into().unwrap(),
+            });
+        }
+    }
+    hits
+}
+
+/// Verify reported hits, then save and report the ones that hold up
+///
+/// Returns the number of hits claimed. A key that cannot be saved fails the
+/// whole search through [`SearchState::fail`].
+pub(super) fn process_hits<I>(state: &SearchState, hits: I, output_dir: &Path, events: &Sender<SearchEvent>) -> usize
+where
+    I: IntoIterator<Item = GpuHit>,
+{
+    let mut claimed = 0;
+    for hit in hits {
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(&hit.seed);
+        let pubkey_bytes = signing_key.verifying_key().to_bytes();
+
+        if state.check_hit(hit.pattern, &pubkey_bytes).is_none() {
+            continue;
+        }
+        let Some(sequence) = state.claim(hit.pattern) else {
+            continue;
+        };
+        claimed += 1;
+
+        match state.save_hit(output_dir, hit.pattern, sequence, &pubkey_bytes, &expand_seed(&hit.seed), true) {
+            Ok(found) => {
+                let _ = events.send(SearchEvent::KeyFound(found));
+            }
+            Err(e) => {
+                state.fail(e);
+                break;
+            }
+        }
+    }
+    claimed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onion::pubkey_to_onion;
+    use crate::{FoundKey, GeneratorError, Quota, SearchFilter};
+
+    /// Random seeds whose address passes `keep`
+    fn seeds_where(count: usize, keep: impl Fn(&str) -> bool) -> Vec<[u8; 32]> {
+        std::iter::repeat_with(rand::random::<[u8; 32]>)
+            .filter(|seed| {
+                let pubkey = ed25519_dalek::SigningKey::from_bytes(seed).verifying_key().to_bytes();
+                keep(&pubkey_to_onion(&pubkey))
+            })
+            .take(count)
+            .collect()
+    }
+
+    fn key_found(event: SearchEvent) -> FoundKey {
+        match event {
+            SearchEvent::KeyFound(found) => found,
+            other => panic!("expected KeyFound, got {:?}", other),
+        }
+    }
+
+    /// Hit buffers as the kernel leaves them after one launch that found
+    /// `candidates` for each GPU pattern, in the order their threads got to
+    /// the atomic counter
+    fn mock_launch(candidates: &[Vec<[u8; 32]>]) -> (Vec<u32>, Vec<u8>) {
+        let mut counts = vec![0u32; candidates.len()];
+        let mut seeds = vec![0u8; candidates.len() * HIT_CAPACITY * 32];
+        for (i, found) in candidates.iter().enumerate() {
+            for seed in found {
+                let slot = counts[i] as usize;
+                counts[i] += 1;
+                if slot < HIT_CAPACITY {
+                    let start = (i * HIT_CAPACITY + slot) * 32;
+                    seeds[start..start + 32].copy_from_slice(seed);
+                }
+            }
+        }
+        (counts, seeds)
+    }
+
+    #[test]
+    fn test_several_hits_in_one_launch() {
+        let dir = tempfile::tempdir().unwrap();
+        let (result_tx, result_rx) = crossbeam_channel::unbounded();
+        let filter = SearchFilter {
+            contains: vec!["q".to_string()],
+            ..SearchFilter::default()
+        };
+        let state = SearchState::with_filter(&["a".to_string(), "b".to_string()], &filter)
+            .unwrap()
+            .with_quota(Quota::Count(3));
+
+        // A near miss first, then four good candidates for "a"; "b" overflows
+        // its buffer with candidates the host rejects
+        let near_miss = seeds_where(1, |onion| onion.starts_with('a') && !onion.contains('q'));
+        let good = seeds_where(4, |onion| onion.starts_with('a') && onion.contains('q'));
+        let overflow = vec![near_miss[0]; HIT_CAPACITY + 5];
+        let (counts, seeds) = mock_launch(&[near_miss.iter().chain(&good).copied().collect(), overflow]);
+        assert_eq!(counts, [5, HIT_CAPACITY as u32 + 5]);
+
+        let hits = launch_hits(&[0, 1], &counts, &seeds);
+        assert_eq!(hits.len(), 5 + HIT_CAPACITY);
+        assert_eq!(hits[1], GpuHit { pattern: 0, seed: good[0] });
+        assert!(hits[5..].iter().all(|hit| hit.pattern == 1));
+
+        assert_eq!(process_hits(&state, hits, dir.path(), &result_tx), 3);
+        assert!(state.is_complete(0));
+        assert!(!state.is_complete(1));
+        let found: Vec<_> = result_rx.try_iter().map(key_found).collect();
+        let address = |seed: &[u8; 32]| {
+            pubkey_to_onion(&ed25519_dalek::SigningKey::from_bytes(seed).verifying_key().to_bytes())
+        };
+        let expected: Vec<_> = good[..3].iter().map(address).collect();
+        assert_eq!(found.iter().map(|key| key.onion_address.clone()).collect::<Vec<_>>(), expected);
+        assert_eq!(found.iter().map(|key| key.sequence).collect::<Vec<_>>(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_failed_contains_keeps_pattern_pending() {
+        let dir = tempfile::tempdir().unwrap();
+        let (result_tx, result_rx) = crossbeam_channel::unbounded();
+        let filter = SearchFilter {
+            contains: vec!["Q".to_string()],
+            ..SearchFilter::default()
+        };
+        let state = SearchState::with_filter(&["a".to_string(), "b".to_string()], &filter).unwrap();
+
+        // The kernel reports prefix hits without looking at the rest of the
+        // address, and may report keys that match nothing at all
+        let near_misses = seeds_where(3, |onion| onion.starts_with('a') && !onion.contains('q'));
+        let wrong_prefix = seeds_where(1, |onion| onion.starts_with('c') && onion.contains('q'));
+        let good = seeds_where(2, |onion| onion.starts_with('a') && onion.contains('q'));
+
+        let stream = near_misses
+            .iter()
+            .chain(&wrong_prefix)
+            .map(|&seed| GpuHit { pattern: 0, seed });
+        assert_eq!(process_hits(&state, stream, dir.path(), &result_tx), 0);
+        assert_eq!(state.remaining(), 2);
+        assert!(!state.is_complete(0));
+        assert!(result_rx.try_recv().is_err());
+
+        // Only the first good report claims the pattern
+        let stream = good.iter().map(|&seed| GpuHit { pattern: 0, seed });
+        assert_eq!(process_hits(&state, stream, dir.path(), &result_tx), 1);
+        assert!(state.is_complete(0));
+        assert_eq!(state.remaining(), 1);
+
+        let found = key_found(result_rx.try_recv().unwrap());
+        assert_eq!(found.prefix, "a");
+        assert_eq!(found.sequence, 1);
+        assert!(found.onion_address.contains('q'));
+        assert!(found.key_path.is_dir());
+        let keys = crate::hs_dir::read_hs_dir(&found.key_path).unwrap();
+        assert_eq!(keys.hostname, found.onion_address);
+        assert!(result_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_save_failure_ends_search() {
+        let dir = tempfile::tempdir().unwrap();
+        let (result_tx, result_rx) = crossbeam_channel::unbounded();
+        let state = SearchState::new(&["a".to_string(), "b".to_string()], &[]).unwrap();
+
+        // An existing directory for the same address must not be replaced
+        let seed = seeds_where(1, |onion| onion.starts_with('a'))[0];
+        let hit = || GpuHit { pattern: 0, seed };
+        process_hits(&state, [hit()], dir.path(), &result_tx);
+        let saved = key_found(result_rx.try_recv().unwrap()).key_path;
+
+        let state = SearchState::new(&["a".to_string(), "b".to_string()], &[]).unwrap();
+        process_hits(&state, [hit()], dir.path(), &result_tx);
+        assert!(state.is_done());
+        assert!(result_rx.try_recv().is_err());
+        match state.take_failure() {
+            Some(GeneratorError::KeyExists(path)) => assert_eq!(path, saved),
+            other => panic!("expected KeyExists, got {:?}", other),
+        }
+    }
+}
@@ -2,19 +2,19 @@
 //!
 //! Runs both CPU and GPU backends simultaneously, combining their speeds.
 
-use crate::onion::pubkey_to_onion;
-use crate::FILE_PREFIX;
+use crate::hs_dir::expand_seed;
 use crossbeam_channel::{Receiver, Sender};
-use std::collections::HashSet;
-use std::io::Write;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::time::Instant;
 
 use super::cpu::CpuBackend;
 use super::cuda::CudaBackend;
-use super::{BackendInfo, FoundKey, GeneratorError, Progress};
+use super::{
+    BackendInfo, Capabilities, GeneratorError, Progress, SearchBackend, SearchChannels, SearchEvent, SearchFilter,
+    SearchState,
+};
 
 /// Hybrid backend that runs CPU and GPU in parallel
 #[derive(Debug, Clone)]
@@ -64,25 +64,34 @@ impl HybridBackend {
         &self,
         prefixes: Vec<String>,
         output_dir: PathBuf,
-        progress_tx: Sender<Progress>,
-        result_tx: Sender<FoundKey>,
+        events: Sender<SearchEvent>,
         stop_rx: Receiver<()>,
     ) -> Result<(), GeneratorError> {
-        // Validate prefixes
-        for prefix in &prefixes {
-            if base32::decode(
-                base32::Alphabet::Rfc4648Lower { padding: false },
-                &format!("{}aa", prefix),
-            )
-            .is_none()
-            {
-                return Err(GeneratorError::InvalidPrefix(prefix.clone()));
-            }
-        }
+        self.generate_with_filter(prefixes, output_dir, events, stop_rx, SearchFilter::default())
+    }
 
-        // Shared state across CPU and GPU
-        let remaining: Arc<Mutex<HashSet<String>>> =
-            Arc::new(Mutex::new(prefixes.clone().into_iter().collect()));
+    /// Start generation on both CPU and GPU with additional filter requirements
+    pub fn generate_with_filter(
+        &self,
+        prefixes: Vec<String>,
+        output_dir: PathBuf,
+        events: Sender<SearchEvent>,
+        stop_rx: Receiver<()>,
+        filter: SearchFilter,
+    ) -> Result<(), GeneratorError> {
+        // Validate and compile prefixes; the match state is shared by CPU and GPU
+        let state = Arc::new(SearchState::with_filter(&prefixes, &filter)?.with_backend(self.info().name));
+        self.run(state, output_dir, events, stop_rx)
+    }
+
+    /// Search for the patterns of `state` on both CPU and GPU
+    pub fn run(
+        &self,
+        state: Arc<SearchState>,
+        output_dir: PathBuf,
+        events: Sender<SearchEvent>,
+        stop_rx: Receiver<()>,
+    ) -> Result<(), GeneratorError> {
         let cpu_counter = Arc::new(AtomicU64::new(0));
         let gpu_counter = Arc::new(AtomicU64::new(0));
         let stopped = Arc::new(AtomicBool::new(false));
@@ -94,10 +103,9 @@ impl HybridBackend {
 
         // Spawn CPU worker threads
         let cpu_handles = self.spawn_cpu_workers(
-            prefixes.clone(),
             output_dir.clone(),
-            result_tx.clone(),
-            remaining.clone(),
+            events.clone(),
+            state.clone(),
             cpu_counter.clone(),
             stopped.clone(),
             cpu_stop_rx,
@@ -105,10 +113,9 @@ impl HybridBackend {
 
         // Spawn GPU worker threads
         let gpu_handle = self.spawn_gpu_workers(
-            prefixes,
             output_dir,
-            result_tx.clone(),
-            remaining.clone(),
+            events.clone(),
+            state.clone(),
             gpu_counter.clone(),
             stopped.clone(),
             gpu_stop_rx,
@@ -118,13 +125,14 @@ impl HybridBackend {
         let progress_stopped = stopped.clone();
         let progress_cpu_counter = cpu_counter.clone();
         let progress_gpu_counter = gpu_counter.clone();
-        let progress_remaining = remaining.clone();
+        let progress_state = state.clone();
 
         let progress_handle = std::thread::spawn(move || {
             while !progress_stopped.load(Ordering::Relaxed) {
                 let cpu_keys = progress_cpu_counter.load(Ordering::Relaxed);
                 let gpu_keys = progress_gpu_counter.load(Ordering::Relaxed);
                 let total_keys = cpu_keys + gpu_keys;
+                progress_state.report_checked(total_keys);
 
                 let elapsed = start_time.elapsed().as_secs_f64();
                 let keys_per_sec = if elapsed > 0.0 {
@@ -133,13 +141,14 @@ impl HybridBackend {
                     0.0
                 };
 
-                let _ = progress_tx.send(Progress {
+                let _ = events.send(SearchEvent::Progress(Progress {
                     keys_checked: total_keys,
                     keys_per_sec,
                     elapsed_secs: elapsed,
-                });
+                    found: progress_state.hit_counts(),
+                }));
 
-                if progress_remaining.lock().unwrap().is_empty() {
+                if progress_state.is_done() {
                     break;
                 }
 
@@ -159,7 +168,7 @@ impl HybridBackend {
 
         // Wait for completion
         loop {
-            if remaining.lock().unwrap().is_empty() || stopped.load(Ordering::SeqCst) {
+            if state.is_done() || stopped.load(Ordering::SeqCst) {
                 stopped.store(true, Ordering::SeqCst);
                 break;
             }
@@ -175,7 +184,10 @@ impl HybridBackend {
         }
         let _ = progress_handle.join();
 
-        if stopped.load(Ordering::SeqCst) && !remaining.lock().unwrap().is_empty() {
+        if let Some(e) = state.take_failure() {
+            return Err(e);
+        }
+        if stopped.load(Ordering::SeqCst) && !state.is_done() {
             Err(GeneratorError::Stopped)
         } else {
             Ok(())
@@ -184,10 +196,9 @@ impl HybridBackend {
 
     fn spawn_cpu_workers(
         &self,
-        prefixes: Vec<String>,
         output_dir: PathBuf,
-        result_tx: Sender<FoundKey>,
-        remaining: Arc<Mutex<HashSet<String>>>,
+        events: Sender<SearchEvent>,
+        state: Arc<SearchState>,
         counter: Arc<AtomicU64>,
         stopped: Arc<AtomicBool>,
         _stop_rx: Receiver<()>,
@@ -195,22 +206,14 @@ impl HybridBackend {
         let mut handles = Vec::new();
 
         for _ in 0..self.cpu_threads {
-            let prefixes = prefixes.clone();
             let output_dir = output_dir.clone();
-            let result_tx = result_tx.clone();
-            let remaining = remaining.clone();
+            let events = events.clone();
+            let state = state.clone();
             let counter = counter.clone();
             let stopped = stopped.clone();
 
             let handle = std::thread::spawn(move || {
-                Self::cpu_worker(
-                    prefixes,
-                    output_dir,
-                    result_tx,
-                    remaining,
-                    counter,
-                    stopped,
-                );
+                Self::cpu_worker(output_dir, events, state, counter, stopped);
             });
 
             handles.push(handle);
@@ -220,15 +223,15 @@ impl HybridBackend {
     }
 
     fn cpu_worker(
-        prefixes: Vec<String>,
         output_dir: PathBuf,
-        result_tx: Sender<FoundKey>,
-        remaining: Arc<Mutex<HashSet<String>>>,
+        events: Sender<SearchEvent>,
+        state: Arc<SearchState>,
         counter: Arc<AtomicU64>,
         stopped: Arc<AtomicBool>,
     ) {
         while !stopped.load(Ordering::Relaxed) {
-            if remaining.lock().unwrap().is_empty() {
+            state.wait_while_paused();
+            if state.is_done() {
                 break;
             }
 
@@ -240,36 +243,18 @@ impl HybridBackend {
             let verifying_key = signing_key.verifying_key();
             let pubkey_bytes: [u8; 32] = verifying_key.to_bytes();
 
-            // Generate onion address
-            let onion = pubkey_to_onion(&pubkey_bytes);
-
-            // Check against remaining prefixes
-            let remaining_guard = remaining.lock().unwrap();
-            let mut found_prefix = None;
-            for prefix in remaining_guard.iter() {
-                if onion.starts_with(prefix) {
-                    found_prefix = Some(prefix.clone());
-                    break;
-                }
-            }
-            drop(remaining_guard);
-
-            // If found, save and notify
-            if let Some(prefix) = found_prefix {
-                remaining.lock().unwrap().remove(&prefix);
-
-                let key_path = output_dir.join(&onion);
-                if let Ok(mut f) = std::fs::File::create(&key_path) {
-                    let expanded = signing_key.to_keypair_bytes();
-                    let _ = f.write_all(FILE_PREFIX);
-                    let _ = f.write_all(&expanded);
-                    let _ = f.flush();
-
-                    let _ = result_tx.send(FoundKey {
-                        prefix,
-                        onion_address: onion,
-                        key_path,
-                    });
+            // Check against remaining prefixes and the contains words; only
+            // matching keys are encoded, and claiming the hit is the only
+            // point where workers contend
+            let hit = state
+                .find_hit(&pubkey_bytes)
+                .and_then(|(index, _)| Some((index, state.claim(index)?)));
+            if let Some((index, sequence)) = hit {
+                match state.save_hit(&output_dir, index, sequence, &pubkey_bytes, &expand_seed(&seed), true) {
+                    Ok(found) => {
+                        let _ = events.send(SearchEvent::KeyFound(found));
+                    }
+                    Err(e) => state.fail(e),
                 }
             }
 
@@ -279,10 +264,9 @@ impl HybridBackend {
 
     fn spawn_gpu_workers(
         &self,
-        prefixes: Vec<String>,
         output_dir: PathBuf,
-        result_tx: Sender<FoundKey>,
-        remaining: Arc<Mutex<HashSet<String>>>,
+        events: Sender<SearchEvent>,
+        state: Arc<SearchState>,
         counter: Arc<AtomicU64>,
         stopped: Arc<AtomicBool>,
         _stop_rx: Receiver<()>,
@@ -290,36 +274,81 @@ impl HybridBackend {
         // Spawn GPU in a separate thread
         let handle = std::thread::spawn(move || {
             // Create internal channels for GPU backend
-            let (internal_progress_tx, _internal_progress_rx) = crossbeam_channel::unbounded();
+            let (internal_event_tx, internal_event_rx) = crossbeam_channel::unbounded::<SearchEvent>();
             let (internal_stop_tx, internal_stop_rx) = crossbeam_channel::bounded(1);
 
-            // Create a wrapper that updates our shared counter
-            let gpu_result_tx = result_tx;
-            let gpu_remaining = remaining;
-            let gpu_counter = counter;
             let gpu_stopped = stopped;
 
+            // Feed the GPU's key count into our shared counter, passing
+            // everything else on
+            let gpu_events = events.clone();
+            std::thread::spawn(move || {
+                for event in internal_event_rx {
+                    match event {
+                        SearchEvent::Progress(progress) => counter.store(progress.keys_checked, Ordering::Relaxed),
+                        event => {
+                            let _ = gpu_events.send(event);
+                        }
+                    }
+                }
+            });
+
             // We'll run the CUDA backend's internal logic here
-            if let Ok(cuda) = CudaBackend::new() {
-                // Monitor for stop
-                let monitor_stopped = gpu_stopped.clone();
-                std::thread::spawn(move || {
-                    while !monitor_stopped.load(Ordering::Relaxed) {
-                        std::thread::sleep(std::time::Duration::from_millis(100));
+            match CudaBackend::new() {
+                Ok(cuda) => {
+                    // Monitor for stop
+                    let monitor_stopped = gpu_stopped.clone();
+                    std::thread::spawn(move || {
+                        while !monitor_stopped.load(Ordering::Relaxed) {
+                            std::thread::sleep(std::time::Duration::from_millis(100));
+                        }
+                        let _ = internal_stop_tx.send(());
+                    });
+
+                    // Run against the shared state so GPU hits retire patterns
+                    // for the CPU workers too. A key the GPU cannot save fails
+                    // the shared state, which stops the CPU workers and is
+                    // collected by run; on GPU errors, already reported, the
+                    // CPU workers carry on alone.
+                    match cuda.run_shared(state.clone(), output_dir, internal_event_tx, internal_stop_rx) {
+                        Ok(()) | Err(GeneratorError::Cuda(_)) => {}
+                        Err(e) => state.fail(e),
                     }
-                    let _ = internal_stop_tx.send(());
-                });
-
-                let _ = cuda.generate(
-                    prefixes,
-                    output_dir,
-                    internal_progress_tx,
-                    gpu_result_tx,
-                    internal_stop_rx,
-                );
+                }
+                Err(e) => {
+                    let _ = events.send(SearchEvent::WorkerError {
+                        worker: "GPU".to_string(),
+                        error: e.to_string(),
+                    });
+                }
             }
         });
 
         Some(handle)
     }
 }
+
+impl SearchBackend for HybridBackend {
+    fn info(&self) -> BackendInfo {
+        self.info()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            regex: false,
+            anchored_patterns: true,
+            seeds: true,
+            gpu: true,
+            live_patterns: true,
+        }
+    }
+
+    fn search(
+        &self,
+        state: Arc<SearchState>,
+        output_dir: PathBuf,
+        channels: SearchChannels,
+    ) -> Result<(), GeneratorError> {
+        self.run(state, output_dir, channels.events, channels.stop)
+    }
+}
@@ -2,9 +2,25 @@
 //!
 //! This module provides a trait-based abstraction over different computation
 //! backends (CUDA GPU, CPU, and Hybrid CPU+GPU) for generating Tor v3 vanity addresses.
+//!
+//! Backend selection and searches report what happens as [`SearchEvent`]s on
+//! a single channel; nothing here writes to stderr.
 
 mod cpu;
+mod curve;
 mod external_cuda;
+mod search;
+mod select;
+mod session;
+
+// Futures and streams over sessions, for async callers
+#[cfg(feature = "async")]
+mod stream;
+
+// Host side of the CUDA kernel; always built for tests, which feed it a
+// mock result stream instead of a GPU
+#[cfg(any(feature = "cuda", test))]
+mod gpu_hits;
 
 #[cfg(feature = "cuda")]
 mod cuda;
@@ -12,12 +28,20 @@ mod cuda;
 #[cfg(feature = "cuda")]
 mod hybrid;
 
+use crate::hs_dir::{KeyFormat, KeyProtection};
 use crossbeam_channel::{Receiver, Sender};
 use std::path::PathBuf;
+use std::sync::Arc;
 use thiserror::Error;
 
-pub use cpu::CpuBackend;
+pub use cpu::{BatchWalker, CpuBackend, CpuSearchMode, IncrementalWalker, NORMALIZE_BATCH_SIZE};
 pub use external_cuda::ExternalCudaBackend;
+pub use search::{Quota, SearchState, MAX_ADDED_PATTERNS};
+pub use select::{BackendKind, BackendSelector, Selection, SkippedBackend};
+pub use session::{PatternStatus, SearchSession, SessionState};
+
+#[cfg(feature = "async")]
+pub use stream::{EventStream, SearchFuture};
 
 #[cfg(feature = "cuda")]
 pub use cuda::CudaBackend;
@@ -34,8 +58,44 @@ pub enum GeneratorError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
-    #[error("Invalid prefix: {0}")]
-    InvalidPrefix(String),
+    #[error("Invalid pattern '{pattern}': {reason}")]
+    InvalidPattern { pattern: String, reason: String },
+
+    #[error("Invalid wordlist: {0}")]
+    InvalidWordlist(String),
+
+    #[error("Refusing to overwrite existing key at {}", .0.display())]
+    KeyExists(PathBuf),
+
+    #[error("Invalid key file: {0}")]
+    InvalidKeyFile(String),
+
+    #[error("Could not decrypt key: wrong passphrase or identity, or damaged file")]
+    Decryption,
+
+    #[error("Invalid recipient: {0}")]
+    InvalidRecipient(String),
+
+    #[error("Invalid service nickname '{0}': use lowercase letters, digits, '-' and '_'")]
+    InvalidNickname(String),
+
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+
+    #[error("Invalid port mapping '{0}': expected VIRTPORT[,TARGET]")]
+    InvalidPortMapping(String),
+
+    #[error("Control port error: {0}")]
+    ControlPort(String),
+
+    #[error("No pattern with index {0}")]
+    UnknownPattern(usize),
+
+    #[error("No backend available: {}", skipped_list(.0))]
+    NoBackend(Vec<SkippedBackend>),
+
+    #[error("Secret key derived for {0} does not produce its public key")]
+    KeyMismatch(String),
 
     #[error("Generation stopped by user")]
     Stopped,
@@ -44,20 +104,80 @@ pub enum GeneratorError {
     Channel(String),
 }
 
+fn skipped_list(skipped: &[SkippedBackend]) -> String {
+    if skipped.is_empty() {
+        return "no backends to try".to_string();
+    }
+    skipped.iter().map(SkippedBackend::to_string).collect::<Vec<_>>().join(", ")
+}
+
 /// Progress update from the generator
 #[derive(Debug, Clone, Default)]
 pub struct Progress {
     pub keys_checked: u64,
     pub keys_per_sec: f64,
     pub elapsed_secs: f64,
+    /// Keys found so far per pattern, prefixes first, then regular expressions
+    pub found: Vec<usize>,
 }
 
 /// A successfully found vanity key
 #[derive(Debug, Clone)]
 pub struct FoundKey {
     pub prefix: String,
+    /// Spelling of `prefix` found in the address; differs from it only for
+    /// look-alike matches
+    pub variant: String,
     pub onion_address: String,
+    pub public_key: [u8; 32],
     pub key_path: PathBuf,
+    /// Position of this key among all keys the search found, starting at 1
+    pub sequence: u64,
+    /// Keys the search had checked when this one was found, as of the last
+    /// progress update
+    pub keys_checked: u64,
+    /// Whether the key came from an ed25519 seed; keys found by walking
+    /// scalars have none and only exist in Tor's expanded form
+    pub has_seed: bool,
+}
+
+/// How a search ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchOutcome {
+    /// Every pattern has its quota of keys
+    Complete,
+    /// Stopped before every quota was filled
+    Stopped,
+    /// The search failed with this error
+    Failed(String),
+}
+
+impl SearchOutcome {
+    pub fn of(result: &Result<(), GeneratorError>) -> Self {
+        match result {
+            Ok(()) => Self::Complete,
+            Err(GeneratorError::Stopped) => Self::Stopped,
+            Err(e) => Self::Failed(e.to_string()),
+        }
+    }
+}
+
+/// Something that happened while selecting a backend or searching
+#[derive(Debug, Clone)]
+pub enum SearchEvent {
+    /// Backend selection settled on this backend
+    BackendChosen(BackendInfo),
+    /// Backend selection could not use `backend` and moved on to the next
+    /// candidate
+    Fallback { backend: String, reason: String },
+    Progress(Progress),
+    /// A key was saved
+    KeyFound(FoundKey),
+    /// A worker failed; the search carries on without it where it can, and
+    /// otherwise ends with the same error
+    WorkerError { worker: String, error: String },
+    /// The search ended; sent by [`SearchSession`] once the backend returned
+    Finished(SearchOutcome),
 }
 
 /// Filter configuration for vanity address search
@@ -65,6 +185,20 @@ pub struct FoundKey {
 pub struct SearchFilter {
     /// Words that must appear somewhere in the onion address (case-insensitive)
     pub contains: Vec<String>,
+    /// Regular expressions to search for alongside the prefixes; only the
+    /// CPU backend can evaluate these
+    pub regex: Vec<String>,
+    /// Let letters of the prefixes also match their digit look-alikes
+    /// (`a`/`4`, `e`/`3`, `s`/`5`, `t`/`7`, `z`/`2`)
+    pub lookalikes: bool,
+    /// How many keys to find per pattern before it is retired
+    pub quota: Quota,
+    /// How to store the secret keys of found addresses
+    pub protection: KeyProtection,
+    /// Where to save found keys
+    pub format: KeyFormat,
+    /// File to append a record of every found key to; see [`crate::manifest`]
+    pub manifest: Option<PathBuf>,
 }
 
 /// Information about a computation backend
@@ -74,267 +208,171 @@ pub struct BackendInfo {
     pub estimated_speed: u64,
 }
 
-/// Available backend types
+/// What a backend can search for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities {
+    /// Evaluates the regular expressions of [`SearchFilter::regex`]
+    pub regex: bool,
+    /// Matches patterns anchored anywhere in the address, not only prefixes
+    pub anchored_patterns: bool,
+    /// Finds keys from ed25519 seeds; see [`FoundKey::has_seed`]
+    pub seeds: bool,
+    /// Runs on a GPU
+    pub gpu: bool,
+    /// Picks up patterns added while the search runs
+    pub live_patterns: bool,
+}
+
+impl Capabilities {
+    /// Check that a backend with these capabilities can run `filter`
+    pub fn check(&self, filter: &SearchFilter) -> Result<(), GeneratorError> {
+        if !self.regex && !filter.regex.is_empty() {
+            return Err(GeneratorError::Unsupported(
+                "regex patterns can only be evaluated by the CPU backend".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Channels between a running search and its caller
 #[derive(Debug, Clone)]
-pub enum Backend {
-    Cpu(CpuBackend),
-    ExternalCuda(ExternalCudaBackend),
-    #[cfg(feature = "cuda")]
-    Cuda(CudaBackend),
-    #[cfg(feature = "cuda")]
-    Hybrid(HybridBackend),
+pub struct SearchChannels {
+    /// Receives progress, every key once it is saved and worker errors
+    pub events: Sender<SearchEvent>,
+    /// A message stops the search
+    pub stop: Receiver<()>,
 }
 
-/// Backend mode selection
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum BackendMode {
-    /// CPU only (always available)
-    Cpu,
-    /// GPU only (CUDA - built-in or external)
-    Cuda,
-    /// CPU + GPU combined (CUDA)
-    Hybrid,
-    /// Automatically select best available
-    Auto,
+/// A computation backend that searches for vanity addresses
+///
+/// The built-in CPU, CUDA, external CUDA and hybrid backends implement this,
+/// and so can backends outside this crate. Everything that runs searches,
+/// including backend selection, the CLI and the GUI, only goes through it,
+/// usually by way of a [`SearchSession`].
+///
+/// The [`SearchState`] a backend is handed holds the patterns, quotas, key
+/// protection and manifest of the search, so they behave the same
+/// everywhere. Workers claim and save hits through it, park in
+/// [`SearchState::wait_while_paused`] between batches and, if the backend
+/// has [`Capabilities::live_patterns`], pick up patterns added while they
+/// run.
+pub trait SearchBackend: Send + Sync {
+    /// Name and estimated speed
+    fn info(&self) -> BackendInfo;
+
+    /// What this backend can search for
+    fn capabilities(&self) -> Capabilities;
+
+    /// Search for the patterns of `state`, saving keys to `output_dir`,
+    /// until every pattern has its quota of keys or the search is stopped
+    ///
+    /// Blocks until the search ends. Returns [`GeneratorError::Stopped`] if it
+    /// was stopped before every quota was filled.
+    fn search(
+        &self,
+        state: Arc<SearchState>,
+        output_dir: PathBuf,
+        channels: SearchChannels,
+    ) -> Result<(), GeneratorError>;
 }
 
-impl Default for BackendMode {
-    fn default() -> Self {
-        BackendMode::Auto
+impl<B: SearchBackend + ?Sized> SearchBackend for Box<B> {
+    fn info(&self) -> BackendInfo {
+        (**self).info()
     }
-}
 
-impl Backend {
-    /// Get information about this backend
-    pub fn info(&self) -> BackendInfo {
-        match self {
-            Backend::Cpu(b) => b.info(),
-            Backend::ExternalCuda(b) => b.info(),
-            #[cfg(feature = "cuda")]
-            Backend::Cuda(b) => b.info(),
-            #[cfg(feature = "cuda")]
-            Backend::Hybrid(b) => b.info(),
-        }
+    fn capabilities(&self) -> Capabilities {
+        (**self).capabilities()
     }
 
-    /// Start generation
-    pub fn generate(
+    fn search(
         &self,
-        prefixes: Vec<String>,
+        state: Arc<SearchState>,
         output_dir: PathBuf,
-        progress_tx: Sender<Progress>,
-        result_tx: Sender<FoundKey>,
-        stop_rx: Receiver<()>,
+        channels: SearchChannels,
     ) -> Result<(), GeneratorError> {
-        self.generate_with_filter(prefixes, output_dir, progress_tx, result_tx, stop_rx, SearchFilter::default())
+        (**self).search(state, output_dir, channels)
     }
+}
 
-    /// Start generation with additional filter requirements
-    ///
-    /// The filter allows specifying words that must appear somewhere in the
-    /// generated onion address (in addition to the prefix match).
-    pub fn generate_with_filter(
+impl<B: SearchBackend + ?Sized> SearchBackend for Arc<B> {
+    fn info(&self) -> BackendInfo {
+        (**self).info()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        (**self).capabilities()
+    }
+
+    fn search(
         &self,
-        prefixes: Vec<String>,
+        state: Arc<SearchState>,
         output_dir: PathBuf,
-        progress_tx: Sender<Progress>,
-        result_tx: Sender<FoundKey>,
-        stop_rx: Receiver<()>,
-        filter: SearchFilter,
+        channels: SearchChannels,
     ) -> Result<(), GeneratorError> {
-        match self {
-            Backend::Cpu(b) => b.generate_with_filter(prefixes, output_dir, progress_tx, result_tx, stop_rx, filter),
-            Backend::ExternalCuda(b) => b.generate_with_filter(prefixes, output_dir, progress_tx, result_tx, stop_rx, filter),
-            #[cfg(feature = "cuda")]
-            Backend::Cuda(b) => b.generate(prefixes, output_dir, progress_tx, result_tx, stop_rx),
-            #[cfg(feature = "cuda")]
-            Backend::Hybrid(b) => b.generate(prefixes, output_dir, progress_tx, result_tx, stop_rx),
-        }
+        (**self).search(state, output_dir, channels)
     }
 }
 
-/// Select backend based on mode
-pub fn select_backend_with_mode(mode: BackendMode) -> Backend {
-    select_backend_with_config(mode, num_cpus::get())
+/// Backend mode selection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackendMode {
+    /// CPU only (always available)
+    Cpu,
+    /// GPU only (CUDA - built-in or external)
+    Cuda,
+    /// CPU + GPU combined (CUDA)
+    Hybrid,
+    /// Automatically select best available
+    #[default]
+    Auto,
 }
 
-/// Select backend based on mode with specific CPU thread count
-pub fn select_backend_with_config(mode: BackendMode, cpu_threads: usize) -> Backend {
-    match mode {
-        BackendMode::Cpu => {
-            let cpu = CpuBackend::with_threads(cpu_threads);
-            print_backend_info(&cpu.info());
-            Backend::Cpu(cpu)
-        }
-        #[cfg(feature = "cuda")]
-        BackendMode::Cuda => {
-            // Try built-in CUDA first
-            match CudaBackend::new() {
-                Ok(cuda) => {
-                    print_backend_info(&cuda.info());
-                    Backend::Cuda(cuda)
-                }
-                Err(e) => {
-                    eprintln!("Built-in CUDA not available ({}), trying external...", e);
-                    // Fall back to external CUDA
-                    match ExternalCudaBackend::new() {
-                        Ok(ext) => {
-                            print_backend_info(&ext.info());
-                            Backend::ExternalCuda(ext)
-                        }
-                        Err(e2) => {
-                            eprintln!("External CUDA not available ({}), falling back to CPU", e2);
-                            let cpu = CpuBackend::with_threads(cpu_threads);
-                            print_backend_info(&cpu.info());
-                            Backend::Cpu(cpu)
-                        }
-                    }
-                }
-            }
-        }
-        #[cfg(not(feature = "cuda"))]
-        BackendMode::Cuda => {
-            // Try external CUDA when built-in CUDA feature is disabled
-            match ExternalCudaBackend::new() {
-                Ok(ext) => {
-                    print_backend_info(&ext.info());
-                    Backend::ExternalCuda(ext)
-                }
-                Err(e) => {
-                    eprintln!("External CUDA not available ({}), falling back to CPU", e);
-                    let cpu = CpuBackend::with_threads(cpu_threads);
-                    print_backend_info(&cpu.info());
-                    Backend::Cpu(cpu)
-                }
-            }
-        }
-        #[cfg(feature = "cuda")]
-        BackendMode::Hybrid => {
-            match HybridBackend::with_cpu_threads(cpu_threads) {
-                Ok(hybrid) => {
-                    print_backend_info(&hybrid.info());
-                    Backend::Hybrid(hybrid)
-                }
-                Err(e) => {
-                    eprintln!("Hybrid mode not available ({}), trying external CUDA...", e);
-                    // Fall back to external CUDA
-                    match ExternalCudaBackend::new() {
-                        Ok(ext) => {
-                            print_backend_info(&ext.info());
-                            Backend::ExternalCuda(ext)
-                        }
-                        Err(e2) => {
-                            eprintln!("External CUDA not available ({}), falling back to CPU", e2);
-                            let cpu = CpuBackend::with_threads(cpu_threads);
-                            print_backend_info(&cpu.info());
-                            Backend::Cpu(cpu)
-                        }
-                    }
-                }
-            }
-        }
-        #[cfg(not(feature = "cuda"))]
-        BackendMode::Hybrid => {
-            // Try external CUDA for hybrid mode when built-in CUDA is disabled
-            match ExternalCudaBackend::new() {
-                Ok(ext) => {
-                    print_backend_info(&ext.info());
-                    Backend::ExternalCuda(ext)
-                }
-                Err(e) => {
-                    eprintln!("External CUDA not available ({}), falling back to CPU", e);
-                    let cpu = CpuBackend::with_threads(cpu_threads);
-                    print_backend_info(&cpu.info());
-                    Backend::Cpu(cpu)
-                }
-            }
-        }
-        BackendMode::Auto => select_backend_auto(cpu_threads),
-    }
+/// Select backend based on mode, without reporting fallbacks
+pub fn select_backend_with_mode(mode: BackendMode) -> Box<dyn SearchBackend> {
+    // Nobody listens, so the events are dropped
+    let (events, _) = crossbeam_channel::unbounded();
+    select_backend_with_config(mode, num_cpus::get(), &events)
 }
 
-/// Select the best available backend with specific thread count
-fn select_backend_auto(cpu_threads: usize) -> Backend {
-    #[cfg(feature = "cuda")]
-    {
-        // Try hybrid first (CPU + GPU)
-        match HybridBackend::with_cpu_threads(cpu_threads) {
-            Ok(hybrid) => {
-                print_backend_info(&hybrid.info());
-                return Backend::Hybrid(hybrid);
-            }
-            Err(e) => {
-                eprintln!("Hybrid mode not available: {}", e);
-            }
-        }
-
-        // Try CUDA only
-        match CudaBackend::new() {
-            Ok(cuda) => {
-                print_backend_info(&cuda.info());
-                return Backend::Cuda(cuda);
-            }
-            Err(e) => {
-                eprintln!("Built-in CUDA not available: {}", e);
-            }
-        }
-    }
-
-    // Try external CUDA
-    match ExternalCudaBackend::new() {
-        Ok(ext) => {
-            print_backend_info(&ext.info());
-            return Backend::ExternalCuda(ext);
-        }
-        Err(e) => {
-            eprintln!("External CUDA not available: {}", e);
-        }
-    }
-
-    // Fall back to CPU
-    let cpu = CpuBackend::with_threads(cpu_threads);
-    print_backend_info(&cpu.info());
-    Backend::Cpu(cpu)
+/// Select backend based on mode with specific CPU thread count, reporting
+/// fallbacks and the choice on `events`
+pub fn select_backend_with_config(
+    mode: BackendMode,
+    cpu_threads: usize,
+    events: &Sender<SearchEvent>,
+) -> Box<dyn SearchBackend> {
+    select_backend_with_cpu(mode, CpuBackend::with_threads(cpu_threads), events)
 }
 
-/// Select the best available backend automatically
+/// Select backend based on mode, running CPU work like `cpu`
 ///
-/// Priority: Hybrid (CPU+GPU) > CUDA > CPU
-pub fn select_backend() -> Backend {
-    #[cfg(feature = "cuda")]
-    {
-        // Try hybrid first (CPU + GPU)
-        match HybridBackend::new() {
-            Ok(hybrid) => {
-                print_backend_info(&hybrid.info());
-                return Backend::Hybrid(hybrid);
-            }
-            Err(e) => {
-                eprintln!("Hybrid mode not available: {}", e);
-            }
-        }
-
-        // Try CUDA only
-        match CudaBackend::new() {
-            Ok(cuda) => {
-                print_backend_info(&cuda.info());
-                return Backend::Cuda(cuda);
-            }
-            Err(e) => {
-                eprintln!("CUDA not available: {}", e);
-            }
-        }
-    }
-
-    // Fall back to CPU
-    let cpu = CpuBackend::new();
-    print_backend_info(&cpu.info());
-    Backend::Cpu(cpu)
+/// `cpu` is used when the mode asks for the CPU or nothing better is
+/// available; its thread count also sizes the CPU half of a hybrid backend.
+/// Every backend that was tried and not available is reported on `events` as
+/// a [`SearchEvent::Fallback`], followed by [`SearchEvent::BackendChosen`].
+/// Use a [`BackendSelector`] to fail instead of falling back, or to choose
+/// the order.
+pub fn select_backend_with_cpu(
+    mode: BackendMode,
+    cpu: CpuBackend,
+    events: &Sender<SearchEvent>,
+) -> Box<dyn SearchBackend> {
+    let selection = BackendSelector::new(mode)
+        .with_cpu(cpu)
+        .select()
+        .expect("the fallback order of every mode ends with the CPU, which is always available");
+    selection.report(events);
+    selection.backend
 }
 
-fn print_backend_info(info: &BackendInfo) {
-    eprintln!("Backend: {}", info.name);
-    eprintln!("Estimated speed: ~{} keys/sec", format_speed(info.estimated_speed));
+/// Select the best available backend automatically, without reporting
+/// fallbacks
+///
+/// Priority: Hybrid (CPU+GPU) > CUDA > CPU
+pub fn select_backend() -> Box<dyn SearchBackend> {
+    select_backend_with_mode(BackendMode::Auto)
 }
 
 /// Format speed for display
@@ -0,0 +1,651 @@
+//! Shared bookkeeping for a running search
+//!
+//! Workers test every candidate against the pattern set, so the hot path must
+//! not take a lock. The compiled patterns are immutable and each pattern has
+//! its own atomic hit counter; a worker only touches shared mutable state when
+//! it claims a hit, and the claim itself is a single compare-and-swap.
+//!
+//! A pattern is complete once its [`Quota`] of hits is filled; with
+//! [`Quota::Unlimited`] no pattern ever completes and the search runs until
+//! stopped.
+//!
+//! The `contains` words of a [`SearchFilter`] are part of the state as well,
+//! so every backend applies them the same way: a candidate that matches a
+//! pattern but misses a word is simply not a hit, and the pattern stays
+//! pending.
+//!
+//! A worker that cannot save a claimed hit records the error with
+//! [`SearchState::fail`], which ends the search for everyone; the backend
+//! then returns that error instead of losing the key silently.
+//!
+//! Patterns can be added and removed while the search runs. They live in
+//! slots that are filled once and never move, so pattern indices stay valid
+//! and workers still read them without a lock; a removed pattern simply
+//! counts as complete. [`SearchState::patterns_version`] changes with every
+//! change, for backends that have to upload the patterns somewhere.
+//!
+//! A paused search parks its workers in [`SearchState::wait_while_paused`]
+//! until it is resumed or stopped; nothing is torn down in between.
+//!
+//! [`SearchState::save_hit`] saves a claimed hit and, if the search has a
+//! [`Manifest`], records it there before the backend reports it. Backends
+//! feed their running key count in with [`SearchState::report_checked`], so
+//! the record can say how far the search had come.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, OnceLock};
+
+use crate::hs_dir::{save_key, KeyFormat, KeyProtection};
+use crate::manifest::Manifest;
+use crate::onion::pubkey_to_onion;
+use crate::pattern::{BytePattern, Pattern, RegexPattern};
+
+use super::{FoundKey, GeneratorError, SearchFilter};
+
+/// How many hits to collect per pattern
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    /// Retire a pattern after this many hits
+    Count(usize),
+    /// Keep collecting hits until stopped
+    Unlimited,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Quota::Count(1)
+    }
+}
+
+impl Quota {
+    /// Is a pattern with `hits` hits complete?
+    #[inline]
+    pub fn is_filled(&self, hits: usize) -> bool {
+        match *self {
+            Quota::Count(count) => hits >= count,
+            Quota::Unlimited => false,
+        }
+    }
+}
+
+/// Number of patterns that can be added to a running search
+pub const MAX_ADDED_PATTERNS: usize = 64;
+
+/// A pattern of the search with its hits
+#[derive(Debug)]
+struct Slot {
+    pattern: Pattern,
+    hits: AtomicUsize,
+    /// Removed from the search while it ran
+    removed: AtomicBool,
+    /// Complete or removed, and no longer counted in `remaining`
+    retired: AtomicBool,
+}
+
+impl Slot {
+    fn new(pattern: Pattern) -> Self {
+        Self {
+            pattern,
+            hits: AtomicUsize::new(0),
+            removed: AtomicBool::new(false),
+            retired: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Compiled pattern set plus per-pattern match state, shared by all workers
+#[derive(Debug)]
+pub struct SearchState {
+    /// Pattern slots, of which the first `len` are filled
+    slots: Vec<OnceLock<Slot>>,
+    len: AtomicUsize,
+    /// Bumped whenever a pattern is added or removed
+    patterns_version: AtomicU64,
+    /// Serializes pattern changes
+    changes: Mutex<()>,
+    /// Whether added prefixes also match their look-alikes
+    lookalikes: bool,
+    /// Lowercase words every hit's address must contain
+    contains: Vec<String>,
+    quota: Quota,
+    protection: KeyProtection,
+    format: KeyFormat,
+    /// Name of the backend running the search
+    backend: String,
+    manifest: Option<Manifest>,
+    /// Largest key count a backend reported so far
+    keys_checked: AtomicU64,
+    /// Hits claimed so far across all patterns
+    sequence: AtomicU64,
+    remaining: AtomicUsize,
+    failed: AtomicBool,
+    failure: Mutex<Option<GeneratorError>>,
+    paused: AtomicBool,
+    stopped: AtomicBool,
+    /// Lock and condition paused workers wait on
+    park: Mutex<()>,
+    unparked: Condvar,
+}
+
+impl SearchState {
+    /// Compile and validate the prefixes and regular expressions of a search
+    pub fn new(prefixes: &[String], regexes: &[String]) -> Result<Self, GeneratorError> {
+        let filter = SearchFilter {
+            regex: regexes.to_vec(),
+            ..SearchFilter::default()
+        };
+        Self::with_filter(prefixes, &filter)
+    }
+
+    /// Compile the prefixes plus the regular expressions of `filter`, using
+    /// its pattern options
+    pub fn with_filter(prefixes: &[String], filter: &SearchFilter) -> Result<Self, GeneratorError> {
+        filter.format.check(&filter.protection)?;
+        let prefixes = prefixes.iter().map(|prefix| compile_prefix(prefix, filter.lookalikes));
+        let regexes = filter
+            .regex
+            .iter()
+            .map(|regex| RegexPattern::new(regex).map(Pattern::Regex));
+        let patterns = prefixes.chain(regexes).collect::<Result<Vec<_>, _>>()?;
+        let mut state = Self::from_patterns(patterns)
+            .with_contains(&filter.contains)
+            .with_quota(filter.quota)
+            .with_protection(filter.protection.clone())
+            .with_format(filter.format);
+        state.lookalikes = filter.lookalikes;
+        Ok(match &filter.manifest {
+            Some(path) => state.with_manifest(Manifest::new(path)),
+            None => state,
+        })
+    }
+
+    /// Build the state from already compiled patterns, one hit each
+    pub fn from_patterns(patterns: Vec<Pattern>) -> Self {
+        let len = patterns.len();
+        let slots = patterns
+            .into_iter()
+            .map(|pattern| OnceLock::from(Slot::new(pattern)))
+            .chain(std::iter::repeat_with(OnceLock::new).take(MAX_ADDED_PATTERNS))
+            .collect();
+        Self {
+            slots,
+            len: AtomicUsize::new(len),
+            patterns_version: AtomicU64::new(0),
+            changes: Mutex::new(()),
+            lookalikes: false,
+            contains: Vec::new(),
+            quota: Quota::default(),
+            protection: KeyProtection::default(),
+            format: KeyFormat::default(),
+            backend: String::new(),
+            manifest: None,
+            keys_checked: AtomicU64::new(0),
+            sequence: AtomicU64::new(0),
+            remaining: AtomicUsize::new(len),
+            failed: AtomicBool::new(false),
+            failure: Mutex::new(None),
+            paused: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            park: Mutex::new(()),
+            unparked: Condvar::new(),
+        }
+    }
+
+    /// Collect `quota` hits per pattern
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = quota;
+        // A zero count is complete from the start
+        let mut remaining = 0;
+        for slot in self.slots.iter().filter_map(OnceLock::get) {
+            let complete = quota.is_filled(slot.hits.load(Ordering::Relaxed));
+            slot.retired.store(complete, Ordering::Relaxed);
+            remaining += usize::from(!complete);
+        }
+        self.remaining = AtomicUsize::new(remaining);
+        self
+    }
+
+    /// Also require every hit's address to contain all of `words`
+    /// (case-insensitive)
+    pub fn with_contains(mut self, words: &[String]) -> Self {
+        self.contains = words.iter().map(|word| word.to_lowercase()).collect();
+        self
+    }
+
+    /// Store the secret keys of hits as `protection` says
+    pub fn with_protection(mut self, protection: KeyProtection) -> Self {
+        self.protection = protection;
+        self
+    }
+
+    /// How the secret keys of hits are stored
+    pub fn protection(&self) -> &KeyProtection {
+        &self.protection
+    }
+
+    /// Save hits in `format`
+    pub fn with_format(mut self, format: KeyFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Format hits are saved in
+    pub fn format(&self) -> KeyFormat {
+        self.format
+    }
+
+    /// Save the key of a hit to `output_dir` in the format and with the
+    /// protection of this search; see [`save_key`]
+    pub fn save_key(&self, output_dir: &Path, pubkey: &[u8; 32], expanded: &[u8; 64]) -> Result<PathBuf, GeneratorError> {
+        save_key(output_dir, pubkey, expanded, self.format, &self.protection)
+    }
+
+    /// Name the backend running this search, as it appears in the manifest
+    pub fn with_backend(mut self, backend: impl Into<String>) -> Self {
+        self.backend = backend.into();
+        self
+    }
+
+    /// Name of the backend running this search
+    pub fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    /// Record every saved hit in `manifest`
+    pub fn with_manifest(mut self, manifest: Manifest) -> Self {
+        self.manifest = Some(manifest);
+        self
+    }
+
+    /// Take note of the backend's running count of checked keys
+    ///
+    /// Only the largest report counts, so a combined backend can report its
+    /// total while the backends it combines report their share.
+    pub fn report_checked(&self, keys_checked: u64) {
+        self.keys_checked.fetch_max(keys_checked, Ordering::Relaxed);
+    }
+
+    /// Keys checked as of the last report
+    pub fn keys_checked(&self) -> u64 {
+        self.keys_checked.load(Ordering::Relaxed)
+    }
+
+    /// Save hit number `sequence`, claimed for pattern `index`, and record it
+    /// in the manifest
+    ///
+    /// Returns the key to report. A key that was saved but could not be
+    /// recorded is an error as well, like one that could not be saved.
+    pub fn save_hit(
+        &self,
+        output_dir: &Path,
+        index: usize,
+        sequence: u64,
+        pubkey: &[u8; 32],
+        expanded: &[u8; 64],
+        has_seed: bool,
+    ) -> Result<FoundKey, GeneratorError> {
+        let key_path = self.save_key(output_dir, pubkey, expanded)?;
+        let pattern = self.pattern(index);
+        let found = FoundKey {
+            prefix: pattern.pattern().to_string(),
+            variant: pattern.matched_variant(pubkey),
+            onion_address: pubkey_to_onion(pubkey),
+            public_key: *pubkey,
+            key_path,
+            sequence,
+            keys_checked: self.keys_checked(),
+            has_seed,
+        };
+        if let Some(manifest) = &self.manifest {
+            manifest.append(&found, &self.backend)?;
+        }
+        Ok(found)
+    }
+
+    /// Filled pattern slots, in index order
+    fn slots(&self) -> impl Iterator<Item = &Slot> {
+        self.slots[..self.len.load(Ordering::Acquire)].iter().filter_map(OnceLock::get)
+    }
+
+    fn slot(&self, index: usize) -> &Slot {
+        self.slots[index].get().expect("pattern index out of range")
+    }
+
+    /// All patterns of this search, complete, removed or not, in index order
+    pub fn patterns(&self) -> impl Iterator<Item = &Pattern> {
+        self.slots().map(|slot| &slot.pattern)
+    }
+
+    /// Pattern `index`
+    pub fn pattern(&self, index: usize) -> &Pattern {
+        &self.slot(index).pattern
+    }
+
+    /// Number of patterns, including removed ones
+    pub fn pattern_count(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// Changes whenever a pattern is added or removed
+    pub fn patterns_version(&self) -> u64 {
+        self.patterns_version.load(Ordering::Acquire)
+    }
+
+    /// Compile `prefix` like the prefixes the search started with and add it
+    pub fn add_prefix(&self, prefix: &str) -> Result<usize, GeneratorError> {
+        self.add_pattern(compile_prefix(prefix, self.lookalikes)?)
+    }
+
+    /// Add a pattern to the running search
+    ///
+    /// Returns its index. Fails once [`MAX_ADDED_PATTERNS`] patterns were
+    /// added, or if the search is already over.
+    pub fn add_pattern(&self, pattern: Pattern) -> Result<usize, GeneratorError> {
+        let _changes = self.changes.lock().unwrap();
+        if self.is_done() {
+            return Err(GeneratorError::Unsupported(
+                "patterns cannot be added to a finished search".to_string(),
+            ));
+        }
+        let index = self.len.load(Ordering::Acquire);
+        let slot = self.slots.get(index).ok_or_else(|| {
+            GeneratorError::Unsupported(format!(
+                "at most {} patterns can be added to a running search",
+                MAX_ADDED_PATTERNS
+            ))
+        })?;
+
+        let slot = slot.get_or_init(|| Slot::new(pattern));
+        if self.quota.is_filled(0) {
+            slot.retired.store(true, Ordering::Relaxed);
+        } else {
+            self.remaining.fetch_add(1, Ordering::AcqRel);
+        }
+        self.len.store(index + 1, Ordering::Release);
+        self.patterns_version.fetch_add(1, Ordering::AcqRel);
+        Ok(index)
+    }
+
+    /// Stop searching for pattern `index`; keys already found for it stay
+    ///
+    /// Removing the last pending pattern ends the search.
+    pub fn remove_pattern(&self, index: usize) -> Result<(), GeneratorError> {
+        let _changes = self.changes.lock().unwrap();
+        if index >= self.pattern_count() {
+            return Err(GeneratorError::UnknownPattern(index));
+        }
+        self.slot(index).removed.store(true, Ordering::Release);
+        self.retire(index);
+        self.patterns_version.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Was pattern `index` removed from the search?
+    pub fn is_removed(&self, index: usize) -> bool {
+        self.slot(index).removed.load(Ordering::Acquire)
+    }
+
+    /// Stop counting pattern `index` as pending; only the first call counts
+    fn retire(&self, index: usize) {
+        if !self.slot(index).retired.swap(true, Ordering::AcqRel) {
+            self.remaining.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    /// Hits wanted per pattern
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Index of the first still-pending pattern that `pubkey` satisfies
+    #[inline]
+    pub fn find_match(&self, pubkey: &[u8; 32]) -> Option<usize> {
+        self.slots()
+            .position(|slot| slot.pattern.matches(pubkey) && !self.slot_is_complete(slot))
+    }
+
+    /// First pending pattern `pubkey` satisfies, together with its address,
+    /// if that also contains every required word
+    ///
+    /// The address is only encoded once the raw bytes already match.
+    #[inline]
+    pub fn find_hit(&self, pubkey: &[u8; 32]) -> Option<(usize, String)> {
+        let index = self.find_match(pubkey)?;
+        let onion = pubkey_to_onion(pubkey);
+        self.contains_all(&onion).then_some((index, onion))
+    }
+
+    /// Address of `pubkey` if it is a hit for pending pattern `index`
+    ///
+    /// GPU kernels only compare public key bytes, so their reports are
+    /// re-checked with this before being claimed.
+    pub fn check_hit(&self, index: usize, pubkey: &[u8; 32]) -> Option<String> {
+        if self.is_complete(index) || !self.pattern(index).matches(pubkey) {
+            return None;
+        }
+        let onion = pubkey_to_onion(pubkey);
+        self.contains_all(&onion).then_some(onion)
+    }
+
+    /// Does `onion` contain every required word?
+    fn contains_all(&self, onion: &str) -> bool {
+        self.contains.iter().all(|word| onion.contains(word.as_str()))
+    }
+
+    /// Has pattern `index` filled its quota, or was it removed?
+    #[inline]
+    pub fn is_complete(&self, index: usize) -> bool {
+        self.slot_is_complete(self.slot(index))
+    }
+
+    #[inline]
+    fn slot_is_complete(&self, slot: &Slot) -> bool {
+        self.quota.is_filled(slot.hits.load(Ordering::Relaxed)) || slot.removed.load(Ordering::Relaxed)
+    }
+
+    /// Number of hits claimed for pattern `index`
+    pub fn hits(&self, index: usize) -> usize {
+        self.slot(index).hits.load(Ordering::Acquire)
+    }
+
+    /// Number of hits claimed for every pattern, in pattern order
+    pub fn hit_counts(&self) -> Vec<usize> {
+        self.slots().map(|slot| slot.hits.load(Ordering::Acquire)).collect()
+    }
+
+    /// Claim a hit for pattern `index`
+    ///
+    /// Returns the hit's sequence number within the whole search, starting
+    /// at 1, for as many callers as the quota allows; everyone else lost the
+    /// race and should drop their candidate.
+    pub fn claim(&self, index: usize) -> Option<u64> {
+        let slot = self.slot(index);
+        let hits = &slot.hits;
+        let mut current = hits.load(Ordering::Relaxed);
+        loop {
+            if self.quota.is_filled(current) || slot.removed.load(Ordering::Acquire) {
+                return None;
+            }
+            match hits.compare_exchange_weak(current, current + 1, Ordering::AcqRel, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+
+        if self.quota.is_filled(current + 1) {
+            self.retire(index);
+        }
+        Some(self.sequence.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    /// Number of patterns that have not filled their quota
+    pub fn remaining(&self) -> usize {
+        self.remaining.load(Ordering::Acquire)
+    }
+
+    /// Is the search over, because all patterns filled their quota or a
+    /// worker failed?
+    pub fn is_done(&self) -> bool {
+        self.remaining() == 0 || self.failed.load(Ordering::Acquire)
+    }
+
+    /// End the search with `error`; only the first failure is kept
+    pub fn fail(&self, error: GeneratorError) {
+        let mut failure = self.failure.lock().unwrap();
+        if failure.is_none() {
+            *failure = Some(error);
+        }
+        self.failed.store(true, Ordering::Release);
+        self.unpark();
+    }
+
+    /// Park workers in [`SearchState::wait_while_paused`] until resumed
+    pub fn pause(&self) {
+        let _park = self.park.lock().unwrap();
+        self.paused.store(true, Ordering::Release);
+    }
+
+    /// Let parked workers continue
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+        self.unpark();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Release parked workers for good, so they can see the backend's stop
+    /// signal
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+        self.unpark();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Acquire)
+    }
+
+    /// Block the calling worker while the search is paused
+    ///
+    /// Returns right away unless paused, and otherwise once the search is
+    /// resumed, stopped or has failed. Workers call this between batches.
+    #[inline]
+    pub fn wait_while_paused(&self) {
+        if self.paused.load(Ordering::Relaxed) {
+            self.park();
+        }
+    }
+
+    fn park(&self) {
+        let mut park = self.park.lock().unwrap();
+        while self.is_paused() && !self.is_stopped() && !self.failed.load(Ordering::Acquire) {
+            park = self.unparked.wait(park).unwrap();
+        }
+    }
+
+    fn unpark(&self) {
+        let _park = self.park.lock().unwrap();
+        self.unparked.notify_all();
+    }
+
+    /// The error that ended the search, if any
+    pub fn take_failure(&self) -> Option<GeneratorError> {
+        self.failure.lock().unwrap().take()
+    }
+}
+
+/// Compile a prefix pattern, with or without look-alikes
+fn compile_prefix(prefix: &str, lookalikes: bool) -> Result<Pattern, GeneratorError> {
+    if lookalikes {
+        Pattern::with_lookalikes(prefix)
+    } else {
+        BytePattern::new(prefix).map(Pattern::Bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_claim_is_won_once() {
+        let state = Arc::new(SearchState::new(&["ab".to_string(), "cd".to_string()], &[]).unwrap());
+
+        let winners: usize = (0..8)
+            .map(|_| {
+                let state = state.clone();
+                std::thread::spawn(move || state.claim(1).is_some() as usize)
+            })
+            .map(|handle| handle.join().unwrap())
+            .sum();
+
+        assert_eq!(winners, 1);
+        assert!(state.is_complete(1));
+        assert_eq!(state.remaining(), 1);
+        assert_eq!(state.claim(0), Some(2));
+        assert!(state.is_done());
+    }
+
+    #[test]
+    fn test_quota() {
+        let patterns = ["ab".to_string(), "cd".to_string()];
+        let state = SearchState::new(&patterns, &[]).unwrap().with_quota(Quota::Count(3));
+
+        let sequence: Vec<_> = (0..4).map(|_| state.claim(0)).collect();
+        assert_eq!(sequence, [Some(1), Some(2), Some(3), None]);
+        assert!(state.is_complete(0));
+        assert_eq!(state.claim(1), Some(4));
+        assert_eq!(state.hit_counts(), [3, 1]);
+        assert_eq!(state.remaining(), 1);
+
+        let state = SearchState::new(&patterns, &[]).unwrap().with_quota(Quota::Unlimited);
+        assert!((0..100).all(|_| state.claim(0).is_some()));
+        assert!(!state.is_complete(0));
+        assert!(!state.is_done());
+    }
+
+    #[test]
+    fn test_find_match_skips_complete_patterns() {
+        let state = SearchState::new(&["a".to_string()], &["^aa".to_string()]).unwrap();
+        let pubkey = [0u8; 32]; // encodes to "aaaa..."
+
+        assert_eq!(state.find_match(&pubkey), Some(0));
+        state.claim(0);
+        assert_eq!(state.find_match(&pubkey), Some(1));
+        state.claim(1);
+        assert_eq!(state.find_match(&pubkey), None);
+    }
+
+    #[test]
+    fn test_save_hit_records_manifest() {
+        use crate::hs_dir::expand_seed;
+        use crate::manifest::read_manifest;
+
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("manifest.ndjson");
+        let filter = SearchFilter {
+            manifest: Some(manifest.clone()),
+            ..SearchFilter::default()
+        };
+        let state = SearchState::with_filter(&["a".to_string()], &filter).unwrap().with_backend("test");
+        state.report_checked(500);
+        state.report_checked(200);
+
+        let seed = [7u8; 32];
+        let pubkey = ed25519_dalek::SigningKey::from_bytes(&seed).verifying_key().to_bytes();
+        let sequence = state.claim(0).unwrap();
+        let found = state.save_hit(dir.path(), 0, sequence, &pubkey, &expand_seed(&seed), true).unwrap();
+        assert_eq!(found.keys_checked, 500);
+        assert!(found.key_path.is_dir());
+
+        let records = read_manifest(&manifest).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].onion_address, found.onion_address);
+        assert_eq!(records[0].path, found.key_path);
+        assert_eq!(records[0].backend, "test");
+        assert_eq!(records[0].keys_checked, 500);
+    }
+}
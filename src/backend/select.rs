@@ -0,0 +1,260 @@
+//! Choosing a backend
+//!
+//! A [`BackendSelector`] tries candidate backends in order and takes the first
+//! one that is available. The order comes from a [`BackendMode`], which ends
+//! with the CPU so selection always succeeds, or from the caller. A strict
+//! selector only tries what the mode asks for and fails otherwise, so a
+//! broken GPU driver is an error instead of a search running on the CPU.
+//!
+//! The [`Selection`] says which backend was chosen and why every candidate
+//! before it was skipped.
+
+use std::fmt;
+
+use crossbeam_channel::Sender;
+
+use super::{
+    BackendInfo, BackendMode, CpuBackend, ExternalCudaBackend, GeneratorError, SearchBackend, SearchEvent,
+};
+
+#[cfg(feature = "cuda")]
+use super::{CudaBackend, HybridBackend};
+
+/// A backend selection can try
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BackendKind {
+    /// CPU and built-in CUDA together
+    Hybrid,
+    /// Built-in CUDA
+    Cuda,
+    /// The external `vanity_torv3_cuda` executable
+    ExternalCuda,
+    Cpu,
+}
+
+impl BackendKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BackendKind::Hybrid => "Hybrid",
+            BackendKind::Cuda => "Built-in CUDA",
+            BackendKind::ExternalCuda => "External CUDA",
+            BackendKind::Cpu => "CPU",
+        }
+    }
+
+    /// Whether this build includes the backend
+    pub fn is_built(self) -> bool {
+        cfg!(feature = "cuda") || !matches!(self, BackendKind::Hybrid | BackendKind::Cuda)
+    }
+
+    /// Set up this backend, running CPU work like `cpu`
+    fn open(self, cpu: &CpuBackend) -> Result<Box<dyn SearchBackend>, GeneratorError> {
+        match self {
+            #[cfg(feature = "cuda")]
+            BackendKind::Hybrid => Ok(Box::new(HybridBackend::with_cpu_threads(cpu.thread_count())?)),
+            #[cfg(feature = "cuda")]
+            BackendKind::Cuda => Ok(Box::new(CudaBackend::new()?)),
+            #[cfg(not(feature = "cuda"))]
+            BackendKind::Hybrid | BackendKind::Cuda => {
+                Err(GeneratorError::Unsupported("built without the cuda feature".to_string()))
+            }
+            BackendKind::ExternalCuda => Ok(Box::new(ExternalCudaBackend::new()?)),
+            BackendKind::Cpu => Ok(Box::new(cpu.clone())),
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl BackendMode {
+    /// Backends to try for this mode, best first, ending with the CPU;
+    /// backends missing from this build are left out
+    pub fn fallback_order(self) -> Vec<BackendKind> {
+        let order = match self {
+            BackendMode::Cpu => vec![BackendKind::Cpu],
+            BackendMode::Cuda => vec![BackendKind::Cuda, BackendKind::ExternalCuda, BackendKind::Cpu],
+            BackendMode::Hybrid => vec![BackendKind::Hybrid, BackendKind::ExternalCuda, BackendKind::Cpu],
+            BackendMode::Auto => vec![
+                BackendKind::Hybrid,
+                BackendKind::Cuda,
+                BackendKind::ExternalCuda,
+                BackendKind::Cpu,
+            ],
+        };
+        order.into_iter().filter(|kind| kind.is_built()).collect()
+    }
+
+    /// Only the backends this mode asks for, even if missing from this build;
+    /// automatic selection may use any of them
+    pub fn strict_order(self) -> Vec<BackendKind> {
+        match self {
+            BackendMode::Cpu => vec![BackendKind::Cpu],
+            BackendMode::Cuda => vec![BackendKind::Cuda, BackendKind::ExternalCuda],
+            BackendMode::Hybrid => vec![BackendKind::Hybrid],
+            BackendMode::Auto => self.fallback_order(),
+        }
+    }
+}
+
+/// A backend selection tried and could not use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedBackend {
+    pub kind: BackendKind,
+    pub reason: String,
+}
+
+impl fmt::Display for SkippedBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.kind, self.reason)
+    }
+}
+
+/// The chosen backend and the candidates skipped before it
+pub struct Selection {
+    pub backend: Box<dyn SearchBackend>,
+    pub kind: BackendKind,
+    pub skipped: Vec<SkippedBackend>,
+}
+
+impl fmt::Debug for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Selection")
+            .field("backend", &self.info())
+            .field("kind", &self.kind)
+            .field("skipped", &self.skipped)
+            .finish()
+    }
+}
+
+impl Selection {
+    pub fn info(&self) -> BackendInfo {
+        self.backend.info()
+    }
+
+    /// Send a [`SearchEvent::Fallback`] for every skipped candidate, then
+    /// [`SearchEvent::BackendChosen`]
+    pub fn report(&self, events: &Sender<SearchEvent>) {
+        for skipped in &self.skipped {
+            let _ = events.send(SearchEvent::Fallback {
+                backend: skipped.kind.to_string(),
+                reason: skipped.reason.clone(),
+            });
+        }
+        let _ = events.send(SearchEvent::BackendChosen(self.info()));
+    }
+}
+
+/// Picks the first available backend out of a list of candidates
+#[derive(Debug, Clone)]
+pub struct BackendSelector {
+    order: Vec<BackendKind>,
+    cpu: CpuBackend,
+}
+
+impl BackendSelector {
+    /// Try the backends of `mode`, falling back as far as the CPU
+    pub fn new(mode: BackendMode) -> Self {
+        Self {
+            order: mode.fallback_order(),
+            cpu: CpuBackend::new(),
+        }
+    }
+
+    /// Only try the backends `mode` asks for, and fail if none is available
+    pub fn strict(mode: BackendMode) -> Self {
+        Self {
+            order: mode.strict_order(),
+            ..Self::new(mode)
+        }
+    }
+
+    /// Try exactly `order`, first to last
+    pub fn with_order(mut self, order: Vec<BackendKind>) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Run CPU work like `cpu`, whose thread count also sizes the CPU half of
+    /// a hybrid backend
+    pub fn with_cpu(mut self, cpu: CpuBackend) -> Self {
+        self.cpu = cpu;
+        self
+    }
+
+    pub fn order(&self) -> &[BackendKind] {
+        &self.order
+    }
+
+    /// The first available backend of the order
+    ///
+    /// Fails with [`GeneratorError::NoBackend`], listing why each candidate
+    /// was skipped, if none is available.
+    pub fn select(&self) -> Result<Selection, GeneratorError> {
+        let mut skipped = Vec::new();
+        for &kind in &self.order {
+            match kind.open(&self.cpu) {
+                Ok(backend) => return Ok(Selection { backend, kind, skipped }),
+                Err(e) => skipped.push(SkippedBackend {
+                    kind,
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        Err(GeneratorError::NoBackend(skipped))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_custom_order() {
+        let cpu = CpuBackend::with_threads(2);
+        let selection = BackendSelector::new(BackendMode::Auto)
+            .with_order(vec![BackendKind::Cpu, BackendKind::ExternalCuda])
+            .with_cpu(cpu.clone())
+            .select()
+            .unwrap();
+        assert_eq!(selection.kind, BackendKind::Cpu);
+        assert!(selection.skipped.is_empty());
+        assert_eq!(selection.info().name, cpu.info().name);
+
+        let (event_tx, event_rx) = crossbeam_channel::unbounded();
+        selection.report(&event_tx);
+        assert!(matches!(event_rx.try_recv(), Ok(SearchEvent::BackendChosen(_))));
+        assert!(event_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_strict_selection_fails_without_fallback() {
+        assert_eq!(BackendMode::Cpu.strict_order(), vec![BackendKind::Cpu]);
+        assert!(!BackendMode::Cuda.strict_order().contains(&BackendKind::Cpu));
+        assert_eq!(BackendMode::Auto.strict_order(), BackendMode::Auto.fallback_order());
+
+        match BackendSelector::strict(BackendMode::Cpu).with_order(Vec::new()).select() {
+            Err(GeneratorError::NoBackend(skipped)) => assert!(skipped.is_empty()),
+            other => panic!("expected NoBackend, got {:?}", other),
+        }
+    }
+
+    #[cfg(not(feature = "cuda"))]
+    #[test]
+    fn test_skipped_backends_are_reported() {
+        let selection = BackendSelector::new(BackendMode::Hybrid)
+            .with_order(vec![BackendKind::Hybrid, BackendKind::Cuda, BackendKind::Cpu])
+            .select()
+            .unwrap();
+        assert_eq!(selection.kind, BackendKind::Cpu);
+        let kinds: Vec<_> = selection.skipped.iter().map(|skipped| skipped.kind).collect();
+        assert_eq!(kinds, vec![BackendKind::Hybrid, BackendKind::Cuda]);
+        assert!(selection.skipped[0].reason.contains("cuda feature"));
+
+        let error = BackendSelector::strict(BackendMode::Hybrid).select().unwrap_err();
+        assert!(error.to_string().contains("Hybrid"), "{}", error);
+    }
+}
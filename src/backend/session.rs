@@ -0,0 +1,265 @@
+//! Searches running in the background
+//!
+//! [`SearchSession::start`] runs a backend on a thread of its own and returns
+//! right away. The session shares the [`SearchState`] with the backend's
+//! workers, which is how it reaches them while they run: pausing parks the
+//! workers in place, and patterns added or removed show up in their next
+//! batch. Progress and found keys arrive as events on the caller's channel,
+//! ending with [`SearchEvent::Finished`].
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use crossbeam_channel::Sender;
+
+use crate::pattern::{Pattern, RegexPattern};
+
+use super::{
+    Capabilities, GeneratorError, SearchBackend, SearchChannels, SearchEvent, SearchFilter, SearchOutcome, SearchState,
+};
+
+/// What a session is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    Running,
+    /// Workers are parked until resumed
+    Paused,
+    /// Stop was requested, but the backend has not returned yet
+    Stopping,
+    /// The backend returned; [`SearchSession::wait`] has its result
+    Finished,
+}
+
+/// A pattern of a session and how far along it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternStatus {
+    /// Index of the pattern, as used by [`SearchSession::remove_pattern`] and
+    /// [`Progress::found`]
+    pub index: usize,
+    pub pattern: String,
+    pub hits: usize,
+    /// The pattern has its quota of keys
+    pub complete: bool,
+    /// The pattern was removed while the search ran
+    pub removed: bool,
+}
+
+/// Handle of a search running in the background
+///
+/// Dropping the session stops the search without waiting for it.
+#[derive(Debug)]
+pub struct SearchSession {
+    state: Arc<SearchState>,
+    capabilities: Capabilities,
+    stop_tx: Sender<()>,
+    handle: Option<JoinHandle<Result<(), GeneratorError>>>,
+}
+
+impl SearchSession {
+    /// Start searching for `prefixes` plus the patterns of `filter` on
+    /// `backend`, saving keys to `output_dir` and reporting on `events`
+    ///
+    /// Fails right away if the patterns are invalid or the backend cannot
+    /// run them; errors of the search itself come from [`SearchSession::wait`]
+    /// and the final [`SearchEvent::Finished`].
+    pub fn start<B>(
+        backend: B,
+        prefixes: Vec<String>,
+        output_dir: PathBuf,
+        filter: SearchFilter,
+        events: Sender<SearchEvent>,
+    ) -> Result<Self, GeneratorError>
+    where
+        B: SearchBackend + 'static,
+    {
+        let capabilities = backend.capabilities();
+        capabilities.check(&filter)?;
+        let state = Arc::new(SearchState::with_filter(&prefixes, &filter)?.with_backend(backend.info().name));
+
+        let (stop_tx, stop_rx) = crossbeam_channel::bounded(1);
+        let channels = SearchChannels {
+            events: events.clone(),
+            stop: stop_rx,
+        };
+        let worker_state = state.clone();
+        let handle = std::thread::Builder::new().name("search".to_string()).spawn(move || {
+            let result = backend.search(worker_state, output_dir, channels);
+            let _ = events.send(SearchEvent::Finished(SearchOutcome::of(&result)));
+            result
+        })?;
+
+        Ok(Self {
+            state,
+            capabilities,
+            stop_tx,
+            handle: Some(handle),
+        })
+    }
+
+    /// What the session is doing right now
+    pub fn state(&self) -> SessionState {
+        if self.handle.as_ref().is_none_or(JoinHandle::is_finished) {
+            SessionState::Finished
+        } else if self.state.is_stopped() {
+            SessionState::Stopping
+        } else if self.state.is_paused() {
+            SessionState::Paused
+        } else {
+            SessionState::Running
+        }
+    }
+
+    /// Park the workers until [`SearchSession::resume`]
+    pub fn pause(&self) {
+        self.state.pause();
+    }
+
+    pub fn resume(&self) {
+        self.state.resume();
+    }
+
+    /// Ask the backend to stop, also if paused; [`SearchSession::wait`] then
+    /// returns [`GeneratorError::Stopped`] unless every quota was filled
+    pub fn stop(&self) {
+        let _ = self.stop_tx.try_send(());
+        self.state.stop();
+    }
+
+    /// Also search for `prefix`, with the look-alike setting the search
+    /// started with
+    ///
+    /// Returns the index of the new pattern.
+    pub fn add_prefix(&self, prefix: &str) -> Result<usize, GeneratorError> {
+        self.check_live_patterns()?;
+        self.state.add_prefix(prefix)
+    }
+
+    /// Also search for the regular expression `regex`
+    ///
+    /// Returns the index of the new pattern.
+    pub fn add_regex(&self, regex: &str) -> Result<usize, GeneratorError> {
+        self.check_live_patterns()?;
+        let filter = SearchFilter {
+            regex: vec![regex.to_string()],
+            ..SearchFilter::default()
+        };
+        self.capabilities.check(&filter)?;
+        self.state.add_pattern(Pattern::Regex(RegexPattern::new(regex)?))
+    }
+
+    /// Stop searching for pattern `index`; keys already found for it stay
+    ///
+    /// Removing the last pending pattern ends the search.
+    pub fn remove_pattern(&self, index: usize) -> Result<(), GeneratorError> {
+        self.state.remove_pattern(index)
+    }
+
+    /// All patterns of the search, including removed ones, in index order
+    pub fn patterns(&self) -> Vec<PatternStatus> {
+        (0..self.state.pattern_count())
+            .map(|index| PatternStatus {
+                index,
+                pattern: self.state.pattern(index).pattern().to_string(),
+                hits: self.state.hits(index),
+                complete: self.state.quota().is_filled(self.state.hits(index)),
+                removed: self.state.is_removed(index),
+            })
+            .collect()
+    }
+
+    /// Keys checked as of the backend's last progress update
+    pub fn keys_checked(&self) -> u64 {
+        self.state.keys_checked()
+    }
+
+    /// Name of the backend running the search
+    pub fn backend(&self) -> &str {
+        self.state.backend()
+    }
+
+    /// Wait for the search to end and return its result
+    pub fn wait(mut self) -> Result<(), GeneratorError> {
+        let handle = self.handle.take().expect("session is only waited for once");
+        handle
+            .join()
+            .unwrap_or_else(|_| Err(GeneratorError::Channel("search thread panicked".to_string())))
+    }
+
+    fn check_live_patterns(&self) -> Result<(), GeneratorError> {
+        if !self.capabilities.live_patterns {
+            return Err(GeneratorError::Unsupported(format!(
+                "{} cannot add patterns to a running search",
+                self.state.backend()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SearchSession {
+    fn drop(&mut self) {
+        if self.handle.is_some() {
+            self.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::CpuBackend;
+
+    fn start(prefixes: &[&str], events: Sender<SearchEvent>) -> (SearchSession, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let session = SearchSession::start(
+            CpuBackend::with_threads(1),
+            prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+            dir.path().to_path_buf(),
+            SearchFilter::default(),
+            events,
+        )
+        .unwrap();
+        (session, dir)
+    }
+
+    #[test]
+    fn test_patterns_change_while_paused() {
+        let (event_tx, event_rx) = crossbeam_channel::unbounded();
+        // Ten fixed characters are never found in a test
+        let (session, _dir) = start(&["aaaaaaaaaa"], event_tx);
+        session.pause();
+        assert_eq!(session.state(), SessionState::Paused);
+
+        assert_eq!(session.add_prefix("b").unwrap(), 1);
+        session.remove_pattern(0).unwrap();
+        assert!(matches!(session.remove_pattern(5), Err(GeneratorError::UnknownPattern(5))));
+        session.resume();
+
+        let patterns = session.patterns();
+        session.wait().unwrap();
+        let events: Vec<_> = event_rx.try_iter().collect();
+        let found: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                SearchEvent::KeyFound(found) => Some(found),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].prefix, "b");
+        assert!(matches!(events.last(), Some(SearchEvent::Finished(SearchOutcome::Complete))));
+        assert!(patterns[0].removed);
+        assert_eq!(patterns[1].pattern, "b");
+    }
+
+    #[test]
+    fn test_stop_while_paused() {
+        let (event_tx, event_rx) = crossbeam_channel::unbounded();
+        let (session, _dir) = start(&["aaaaaaaaaa"], event_tx);
+        session.pause();
+        session.stop();
+        assert!(matches!(session.wait(), Err(GeneratorError::Stopped)));
+        assert!(matches!(event_rx.try_iter().last(), Some(SearchEvent::Finished(SearchOutcome::Stopped))));
+    }
+}
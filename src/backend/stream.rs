@@ -0,0 +1,149 @@
+//! Searches as futures and streams
+//!
+//! Only built with the `async` feature. [`SearchFuture::start`] starts a
+//! [`SearchSession`] like any other and hands back a future of its result
+//! together with an [`EventStream`] of its events. The search still runs on
+//! the backend's own worker threads; one more thread moves the events from
+//! the session's channel onto the stream and wakes the future once the
+//! search ended, so no particular async runtime is needed.
+//!
+//! Dropping the future stops the search. The stream ends after the
+//! [`SearchEvent::Finished`] event.
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_channel::{mpsc, oneshot};
+use futures_core::Stream;
+
+use super::{GeneratorError, SearchBackend, SearchEvent, SearchFilter, SearchSession};
+
+/// Result of a search started with [`SearchFuture::start`]
+///
+/// Resolves once the search ended, to what [`SearchSession::wait`] returns.
+/// Dropping it before then stops the search.
+#[derive(Debug)]
+pub struct SearchFuture {
+    session: Option<SearchSession>,
+    finished: oneshot::Receiver<()>,
+}
+
+impl SearchFuture {
+    /// Start searching for `prefixes` plus the patterns of `filter` on
+    /// `backend`, saving keys to `output_dir`
+    ///
+    /// Fails right away like [`SearchSession::start`].
+    pub fn start<B>(
+        backend: B,
+        prefixes: Vec<String>,
+        output_dir: PathBuf,
+        filter: SearchFilter,
+    ) -> Result<(Self, EventStream), GeneratorError>
+    where
+        B: SearchBackend + 'static,
+    {
+        let (event_tx, event_rx) = crossbeam_channel::unbounded();
+        let session = SearchSession::start(backend, prefixes, output_dir, filter, event_tx)?;
+
+        let (stream_tx, stream_rx) = mpsc::unbounded();
+        let (finished_tx, finished_rx) = oneshot::channel();
+        std::thread::Builder::new()
+            .name("search events".to_string())
+            .spawn(move || {
+                // Runs until the Finished event, or until the search thread
+                // is gone without sending it
+                for event in event_rx {
+                    let finished = matches!(event, SearchEvent::Finished(_));
+                    // Nobody may be reading the stream; the search goes on
+                    let _ = stream_tx.unbounded_send(event);
+                    if finished {
+                        break;
+                    }
+                }
+                let _ = finished_tx.send(());
+            })?;
+
+        let future = Self {
+            session: Some(session),
+            finished: finished_rx,
+        };
+        Ok((future, EventStream { events: stream_rx }))
+    }
+
+    /// The running search, to pause it or change its patterns; `None` once
+    /// the future resolved
+    pub fn session(&self) -> Option<&SearchSession> {
+        self.session.as_ref()
+    }
+}
+
+impl Future for SearchFuture {
+    type Output = Result<(), GeneratorError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Either way the relay thread ended, the search has ended too
+        if Pin::new(&mut self.finished).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let session = self.session.take().expect("SearchFuture polled after it resolved");
+        Poll::Ready(session.wait())
+    }
+}
+
+/// Events of a search started with [`SearchFuture::start`], ending with
+/// [`SearchEvent::Finished`]
+#[derive(Debug)]
+pub struct EventStream {
+    events: mpsc::UnboundedReceiver<SearchEvent>,
+}
+
+impl Stream for EventStream {
+    type Item = SearchEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<SearchEvent>> {
+        Pin::new(&mut self.events).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{CpuBackend, SearchOutcome};
+    use futures_executor::{block_on, block_on_stream};
+
+    fn start(prefixes: &[&str], dir: &tempfile::TempDir) -> (SearchFuture, EventStream) {
+        SearchFuture::start(
+            CpuBackend::with_threads(1),
+            prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+            dir.path().to_path_buf(),
+            SearchFilter::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_stream_ends_with_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        let (search, events) = start(&["a", "b"], &dir);
+        assert!(search.session().is_some());
+        block_on(search).unwrap();
+
+        let events: Vec<_> = block_on_stream(events).collect();
+        let found = events.iter().filter(|event| matches!(event, SearchEvent::KeyFound(_))).count();
+        assert_eq!(found, 2);
+        assert!(matches!(events.last(), Some(SearchEvent::Finished(SearchOutcome::Complete))));
+    }
+
+    #[test]
+    fn test_dropping_future_stops_search() {
+        let dir = tempfile::tempdir().unwrap();
+        // Ten fixed characters are never found in a test
+        let (search, events) = start(&["aaaaaaaaaa"], &dir);
+        drop(search);
+
+        let last = block_on_stream(events).last();
+        assert!(matches!(last, Some(SearchEvent::Finished(SearchOutcome::Stopped))));
+    }
+}
@@ -0,0 +1,404 @@
+//! Registering found keys with a running Tor through its control port
+//!
+//! Services created at runtime take their key as an `ADD_ONION` argument,
+//! `ED25519-V3:` followed by the base64 of the 64-byte expanded secret key
+//! (see [`add_onion_key`]). [`ControlPort`] speaks the small part of the
+//! control protocol needed to hand Tor such a key: `PROTOCOLINFO`,
+//! `AUTHENTICATE` with a cookie, a password or nothing, and `ADD_ONION`.
+//!
+//! Services are added with `Flags=Detach` unless asked otherwise, so they
+//! outlive the control connection; they still vanish when Tor restarts.
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use zeroize::Zeroizing;
+
+use crate::backend::GeneratorError;
+use crate::hs_dir::public_key_of;
+use crate::onion::pubkey_to_onion;
+
+/// Key type of v3 onion service keys in `ADD_ONION`
+pub const ADD_ONION_KEY_TYPE: &str = "ED25519-V3";
+
+/// How long to wait for Tor before giving up
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The `ED25519-V3:<base64>` key argument of `ADD_ONION` for an expanded key
+pub fn add_onion_key(expanded: &[u8; 64]) -> Zeroizing<String> {
+    Zeroizing::new(format!("{}:{}", ADD_ONION_KEY_TYPE, STANDARD.encode(expanded)))
+}
+
+/// How to authenticate to the control port
+#[derive(Clone)]
+pub enum ControlAuth {
+    /// No authentication, for a control port without `CookieAuthentication`
+    /// or `HashedControlPassword`
+    Null,
+    /// The contents of a cookie file; `None` uses the file Tor reports, or
+    /// no authentication if Tor requires none
+    Cookie(Option<PathBuf>),
+    /// A `HashedControlPassword` password
+    Password(Zeroizing<String>),
+}
+
+impl fmt::Debug for ControlAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlAuth::Null => f.write_str("Null"),
+            ControlAuth::Cookie(path) => f.debug_tuple("Cookie").field(path).finish(),
+            ControlAuth::Password(_) => f.write_str("Password(<redacted>)"),
+        }
+    }
+}
+
+/// A virtual port of a service and where Tor forwards it, as in the
+/// `Port=VIRTPORT[,TARGET]` argument of `ADD_ONION`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    pub virtual_port: u16,
+    /// `host:port`, a port on localhost or `unix:<path>`; the virtual port on
+    /// localhost if `None`
+    pub target: Option<String>,
+}
+
+impl FromStr for PortMapping {
+    type Err = GeneratorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || GeneratorError::InvalidPortMapping(s.to_string());
+        let (port, target) = match s.split_once(',') {
+            Some((port, target)) => (port, Some(target)),
+            None => (s, None),
+        };
+        let virtual_port = port.parse().ok().filter(|&port| port != 0).ok_or_else(invalid)?;
+        if target.is_some_and(|target| target.is_empty() || target.contains(char::is_whitespace)) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            virtual_port,
+            target: target.map(str::to_string),
+        })
+    }
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.target {
+            Some(target) => write!(f, "{},{}", self.virtual_port, target),
+            None => write!(f, "{}", self.virtual_port),
+        }
+    }
+}
+
+/// A connection to a Tor control port
+#[derive(Debug)]
+pub struct ControlPort {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl ControlPort {
+    /// Connect to the control port at `addr`, e.g. `127.0.0.1:9051`
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self, GeneratorError> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    /// Authenticate; Tor closes the connection if this fails
+    pub fn authenticate(&mut self, auth: &ControlAuth) -> Result<(), GeneratorError> {
+        let command = match auth {
+            ControlAuth::Null => Zeroizing::new("AUTHENTICATE".to_string()),
+            ControlAuth::Cookie(Some(path)) => cookie_command(path)?,
+            ControlAuth::Cookie(None) => {
+                let info = self.command("PROTOCOLINFO 1")?;
+                let auth_line = info
+                    .iter()
+                    .find_map(|line| line.strip_prefix("AUTH "))
+                    .ok_or_else(|| protocol_error("PROTOCOLINFO without AUTH line"))?;
+                match quoted_value(auth_line, "COOKIEFILE") {
+                    Some(path) => cookie_command(Path::new(&path))?,
+                    None if auth_methods(auth_line).any(|method| method == "NULL") => {
+                        Zeroizing::new("AUTHENTICATE".to_string())
+                    }
+                    None => return Err(protocol_error("Tor offers no cookie authentication; use a password")),
+                }
+            }
+            ControlAuth::Password(password) => Zeroizing::new(format!("AUTHENTICATE {}", quote(password))),
+        };
+        self.command(&command).map(|_| ())
+    }
+
+    /// Add an onion service with the expanded secret key `expanded`
+    ///
+    /// The service is detached from this connection if `detach` is set.
+    /// Returns its address, checked against the key.
+    pub fn add_onion(
+        &mut self,
+        expanded: &[u8; 64],
+        ports: &[PortMapping],
+        detach: bool,
+    ) -> Result<String, GeneratorError> {
+        if ports.is_empty() {
+            return Err(protocol_error("a service needs at least one port"));
+        }
+        let mut command = Zeroizing::new(format!("ADD_ONION {}", add_onion_key(expanded).as_str()));
+        if detach {
+            command.push_str(" Flags=Detach");
+        }
+        for port in ports {
+            command.push_str(&format!(" Port={}", port));
+        }
+
+        let reply = self.command(&command)?;
+        let onion = pubkey_to_onion(&public_key_of(expanded));
+        let service_id = reply
+            .iter()
+            .find_map(|line| line.strip_prefix("ServiceID="))
+            .ok_or_else(|| protocol_error("ADD_ONION reply without ServiceID"))?;
+        if format!("{}.onion", service_id) != onion {
+            return Err(protocol_error(&format!("Tor added {} instead of {}", service_id, onion)));
+        }
+        Ok(onion)
+    }
+
+    /// Send one command and return the lines of its reply, without status
+    /// codes; fails unless Tor answers `250`
+    fn command(&mut self, command: &str) -> Result<Vec<String>, GeneratorError> {
+        self.writer.write_all(Zeroizing::new(format!("{}\r\n", command)).as_bytes())?;
+        self.writer.flush()?;
+
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            let (status, separator, text) = match (line.get(..3), line.get(3..4), line.get(4..)) {
+                (Some(status), Some(separator), Some(text)) => (status, separator, text),
+                _ => return Err(protocol_error(&format!("malformed reply line '{}'", line))),
+            };
+            match separator {
+                "-" => lines.push(text.to_string()),
+                "+" => {
+                    // Data follows until a line holding a single dot
+                    let mut data = text.to_string();
+                    loop {
+                        let line = self.read_line()?;
+                        if line == "." {
+                            break;
+                        }
+                        data.push('\n');
+                        data.push_str(line.strip_prefix('.').unwrap_or(&line));
+                    }
+                    lines.push(data);
+                }
+                " " if status == "250" => {
+                    lines.push(text.to_string());
+                    return Ok(lines);
+                }
+                " " => return Err(GeneratorError::ControlPort(format!("{} {}", status, text))),
+                _ => return Err(protocol_error(&format!("malformed reply line '{}'", line))),
+            }
+        }
+    }
+
+    /// Next reply line without its line ending
+    fn read_line(&mut self) -> Result<String, GeneratorError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(protocol_error("Tor closed the connection"));
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+/// `AUTHENTICATE` with the cookie in `path`
+fn cookie_command(path: &Path) -> Result<Zeroizing<String>, GeneratorError> {
+    let cookie = Zeroizing::new(
+        std::fs::read(path).map_err(|e| GeneratorError::ControlPort(format!("{}: {}", path.display(), e)))?,
+    );
+    Ok(Zeroizing::new(format!("AUTHENTICATE {}", hex::encode(&*cookie))))
+}
+
+/// Methods of a PROTOCOLINFO `AUTH METHODS=...` line
+fn auth_methods(auth_line: &str) -> impl Iterator<Item = &str> {
+    auth_line
+        .split(' ')
+        .find_map(|field| field.strip_prefix("METHODS="))
+        .unwrap_or_default()
+        .split(',')
+}
+
+/// Value of `key="..."` in a reply line, unescaped
+fn quoted_value(line: &str, key: &str) -> Option<String> {
+    let start = line.find(&format!("{}=\"", key))? + key.len() + 2;
+    let mut value = String::new();
+    let mut chars = line[start..].chars();
+    loop {
+        match chars.next()? {
+            '"' => return Some(value),
+            '\\' => value.push(chars.next()?),
+            c => value.push(c),
+        }
+    }
+}
+
+/// A control protocol quoted string
+fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\r' => quoted.push_str("\\r"),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn protocol_error(reason: &str) -> GeneratorError {
+    GeneratorError::ControlPort(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hs_dir::expand_seed;
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    /// Control port answering the commands of this module like Tor does,
+    /// for one connection; returns the commands it received
+    fn mock_control_port(cookie_file: PathBuf, password: &'static str) -> (u16, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let cookie = std::fs::read(&cookie_file).unwrap();
+            let mut authenticated = false;
+            let mut commands = Vec::new();
+
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let command = line.trim_end().to_string();
+                line.clear();
+                commands.push(command.clone());
+
+                let reply = if command == "PROTOCOLINFO 1" {
+                    format!(
+                        "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=COOKIE,SAFECOOKIE,HASHEDPASSWORD COOKIEFILE={}\r\n\
+                         250-VERSION Tor=\"0.4.8.13\"\r\n250 OK\r\n",
+                        quote(&cookie_file.display().to_string())
+                    )
+                } else if let Some(secret) = command.strip_prefix("AUTHENTICATE ") {
+                    if secret == hex::encode(&cookie) || secret == quote(password) {
+                        authenticated = true;
+                        "250 OK\r\n".to_string()
+                    } else {
+                        let _ = writer.write_all(b"515 Authentication failed: Password did not match\r\n");
+                        break;
+                    }
+                } else if let Some(args) = command.strip_prefix("ADD_ONION ") {
+                    let key = args
+                        .split(' ')
+                        .find_map(|arg| arg.strip_prefix("ED25519-V3:"))
+                        .and_then(|key| STANDARD.decode(key).ok())
+                        .and_then(|key| <[u8; 64]>::try_from(key).ok());
+                    match key {
+                        _ if !authenticated => "514 Authentication required.\r\n".to_string(),
+                        Some(key) if args.contains(" Port=") => {
+                            let onion = pubkey_to_onion(&public_key_of(&key));
+                            format!("250-ServiceID={}\r\n250 OK\r\n", onion.trim_end_matches(".onion"))
+                        }
+                        _ => "512 Bad arguments to ADD_ONION\r\n".to_string(),
+                    }
+                } else {
+                    format!("510 Unrecognized command \"{}\"\r\n", command)
+                };
+                writer.write_all(reply.as_bytes()).unwrap();
+            }
+            commands
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn test_add_onion_key() {
+        let expanded = expand_seed(&[1; 32]);
+        let key = add_onion_key(&expanded);
+        let blob = key.strip_prefix("ED25519-V3:").unwrap();
+        assert_eq!(STANDARD.decode(blob).unwrap(), expanded);
+    }
+
+    #[test]
+    fn test_register_with_cookie() {
+        let dir = tempfile::tempdir().unwrap();
+        let cookie_file = dir.path().join("control \"auth\" cookie");
+        std::fs::write(&cookie_file, [7u8; 32]).unwrap();
+        let (port, tor) = mock_control_port(cookie_file, "unused");
+
+        let expanded = expand_seed(&[2; 32]);
+        let ports = ["80,127.0.0.1:8080".parse().unwrap(), "443".parse().unwrap()];
+        let mut control = ControlPort::connect(("127.0.0.1", port)).unwrap();
+        control.authenticate(&ControlAuth::Cookie(None)).unwrap();
+        let onion = control.add_onion(&expanded, &ports, true).unwrap();
+        assert_eq!(onion, pubkey_to_onion(&public_key_of(&expanded)));
+        drop(control);
+
+        let commands = tor.join().unwrap();
+        assert_eq!(commands[0], "PROTOCOLINFO 1");
+        assert_eq!(commands[1], format!("AUTHENTICATE {}", hex::encode([7u8; 32])));
+        assert_eq!(
+            commands[2],
+            format!("ADD_ONION {} Flags=Detach Port=80,127.0.0.1:8080 Port=443", add_onion_key(&expanded).as_str())
+        );
+    }
+
+    #[test]
+    fn test_password_authentication() {
+        let dir = tempfile::tempdir().unwrap();
+        let cookie_file = dir.path().join("cookie");
+        std::fs::write(&cookie_file, [7u8; 32]).unwrap();
+
+        let (port, tor) = mock_control_port(cookie_file.clone(), "pass \"word\"");
+        let mut control = ControlPort::connect(("127.0.0.1", port)).unwrap();
+        control.authenticate(&ControlAuth::Password(Zeroizing::new("pass \"word\"".to_string()))).unwrap();
+        drop(control);
+        tor.join().unwrap();
+
+        let (port, tor) = mock_control_port(cookie_file, "secret");
+        let mut control = ControlPort::connect(("127.0.0.1", port)).unwrap();
+        let err = control.authenticate(&ControlAuth::Password(Zeroizing::new("wrong".to_string()))).unwrap_err();
+        assert!(matches!(err, GeneratorError::ControlPort(reply) if reply.starts_with("515 ")));
+        // Nothing is added without authenticating
+        assert!(control.add_onion(&expand_seed(&[3; 32]), &["80".parse().unwrap()], true).is_err());
+        tor.join().unwrap();
+    }
+
+    #[test]
+    fn test_port_mapping() {
+        let mapping: PortMapping = "80,unix:/run/site.sock".parse().unwrap();
+        assert_eq!(mapping.virtual_port, 80);
+        assert_eq!(mapping.target.as_deref(), Some("unix:/run/site.sock"));
+        assert_eq!(mapping.to_string(), "80,unix:/run/site.sock");
+        for invalid in ["", "0", "http", "80,", "80,a b", "65536"] {
+            assert!(invalid.parse::<PortMapping>().is_err(), "{}", invalid);
+        }
+    }
+}
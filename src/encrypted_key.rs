@@ -0,0 +1,339 @@
+//! Passphrase-encrypted secret keys
+//!
+//! With a passphrase set, the expanded secret key is never written in
+//! plaintext. Instead the hidden service directory gets
+//! [`ENCRYPTED_SECRET_KEY_FILE`], a small text file:
+//!
+//! ```text
+//! t3v encrypted key v1
+//! onion: <address>.onion
+//! kdf: argon2id m=65536 t=3 p=1
+//! salt: <32 hex digits>
+//! cipher: xchacha20poly1305
+//! nonce: <48 hex digits>
+//! ciphertext: <hex>
+//! ```
+//!
+//! The key is derived from the passphrase with Argon2id and the 64-byte
+//! expanded secret key is sealed with XChaCha20-Poly1305. Every line above
+//! the ciphertext is authenticated as associated data, so neither the
+//! address nor the KDF parameters can be changed without decryption failing.
+//! The address stays readable to tell files apart without the passphrase.
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use zeroize::Zeroizing;
+
+use crate::backend::GeneratorError;
+
+/// File holding the encrypted secret key, in place of `hs_ed25519_secret_key`
+pub const ENCRYPTED_SECRET_KEY_FILE: &str = "hs_ed25519_secret_key.encrypted";
+
+/// First line of every encrypted key file
+const MAGIC: &str = "t3v encrypted key v1";
+
+/// Name of the AEAD in key files
+pub(crate) const CIPHER: &str = "xchacha20poly1305";
+
+/// Largest Argon2 memory cost accepted from a file, in KiB (4 GiB)
+const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+
+/// Passphrase protecting saved keys, with the KDF cost for keys sealed
+/// under it; wiped from memory when dropped
+#[derive(Clone)]
+pub struct Passphrase {
+    passphrase: Zeroizing<String>,
+    kdf: KdfParams,
+}
+
+impl Passphrase {
+    pub fn new(passphrase: String) -> Self {
+        Self {
+            passphrase: Zeroizing::new(passphrase),
+            kdf: KdfParams::default(),
+        }
+    }
+
+    /// Seal keys with different KDF costs; opening always uses the costs
+    /// recorded in the file
+    pub fn with_kdf_params(mut self, kdf: KdfParams) -> Self {
+        self.kdf = kdf;
+        self
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        self.passphrase.as_bytes()
+    }
+}
+
+impl std::fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Passphrase").field("kdf", &self.kdf).finish_non_exhaustive()
+    }
+}
+
+/// Argon2id cost parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory in KiB
+    pub memory_kib: u32,
+    /// Number of passes
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// 64 MiB and three passes, well under a second on current machines
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+/// An expanded secret key sealed under a passphrase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptedKey {
+    onion_address: String,
+    kdf: KdfParams,
+    salt: [u8; 16],
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
+impl EncryptedKey {
+    /// Encrypt the expanded secret key of `onion_address`
+    pub fn seal(onion_address: &str, expanded: &[u8; 64], passphrase: &Passphrase) -> Result<Self, GeneratorError> {
+        let mut key = Self {
+            onion_address: onion_address.to_string(),
+            kdf: passphrase.kdf,
+            salt: rand::random(),
+            nonce: rand::random(),
+            ciphertext: Vec::new(),
+        };
+        key.ciphertext = encrypt_expanded(&key.cipher(passphrase)?, &key.nonce, expanded, &key.header());
+        Ok(key)
+    }
+
+    /// Address of the sealed key, as recorded in the clear
+    pub fn onion_address(&self) -> &str {
+        &self.onion_address
+    }
+
+    /// Decrypt the expanded secret key
+    ///
+    /// Fails with [`GeneratorError::Decryption`] on a wrong passphrase or a
+    /// modified file.
+    pub fn open(&self, passphrase: &Passphrase) -> Result<Zeroizing<[u8; 64]>, GeneratorError> {
+        decrypt_expanded(&self.cipher(passphrase)?, &self.nonce, &self.ciphertext, &self.header())
+    }
+
+    /// The file contents
+    pub fn to_text(&self) -> String {
+        format!("{}ciphertext: {}\n", self.header(), hex::encode(&self.ciphertext))
+    }
+
+    /// Parse the file contents
+    pub fn from_text(text: &str) -> Result<Self, GeneratorError> {
+        let mut fields = KeyFileFields::new(text, MAGIC)?;
+        let onion_address = fields.next("onion")?.to_string();
+        let kdf = parse_kdf(fields.next("kdf")?)
+            .ok_or_else(|| GeneratorError::InvalidKeyFile("unsupported kdf".to_string()))?;
+        let salt = fields.next_hex("salt")?;
+        fields.expect("cipher", CIPHER)?;
+        let nonce = fields.next_hex("nonce")?;
+        let ciphertext = fields.next_ciphertext()?;
+
+        Ok(Self {
+            onion_address,
+            kdf,
+            salt,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Every line before the ciphertext, which is authenticated with it
+    fn header(&self) -> String {
+        format!(
+            "{}\nonion: {}\nkdf: argon2id m={} t={} p={}\nsalt: {}\ncipher: {}\nnonce: {}\n",
+            MAGIC,
+            self.onion_address,
+            self.kdf.memory_kib,
+            self.kdf.iterations,
+            self.kdf.parallelism,
+            hex::encode(self.salt),
+            CIPHER,
+            hex::encode(self.nonce),
+        )
+    }
+
+    /// Cipher keyed with the passphrase stretched by Argon2id
+    fn cipher(&self, passphrase: &Passphrase) -> Result<XChaCha20Poly1305, GeneratorError> {
+        let params = Params::new(self.kdf.memory_kib, self.kdf.iterations, self.kdf.parallelism, Some(32))
+            .map_err(|e| GeneratorError::InvalidKeyFile(format!("bad kdf parameters: {}", e)))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, key.as_mut())
+            .map_err(|e| GeneratorError::InvalidKeyFile(format!("key derivation failed: {}", e)))?;
+        Ok(XChaCha20Poly1305::new(Key::from_slice(key.as_ref())))
+    }
+}
+
+/// `argon2id m=<KiB> t=<passes> p=<lanes>`
+fn parse_kdf(text: &str) -> Option<KdfParams> {
+    let mut parts = text.strip_prefix("argon2id ")?.split(' ');
+    let mut param = |name: &str| parts.next()?.strip_prefix(name)?.parse::<u32>().ok();
+    let kdf = KdfParams {
+        memory_kib: param("m=")?,
+        iterations: param("t=")?,
+        parallelism: param("p=")?,
+    };
+    // A forged file must not make us allocate unbounded memory
+    (kdf.memory_kib <= MAX_MEMORY_KIB).then_some(kdf)
+}
+
+/// Encrypt a 64-byte expanded key, authenticating `header` along with it
+pub(crate) fn encrypt_expanded(
+    cipher: &XChaCha20Poly1305,
+    nonce: &[u8; 24],
+    expanded: &[u8; 64],
+    header: &str,
+) -> Vec<u8> {
+    cipher
+        .encrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: expanded,
+                aad: header.as_bytes(),
+            },
+        )
+        .expect("64 bytes are within the cipher's message limit")
+}
+
+/// Decrypt what [`encrypt_expanded`] produced
+pub(crate) fn decrypt_expanded(
+    cipher: &XChaCha20Poly1305,
+    nonce: &[u8; 24],
+    ciphertext: &[u8],
+    header: &str,
+) -> Result<Zeroizing<[u8; 64]>, GeneratorError> {
+    let plaintext = Zeroizing::new(
+        cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header.as_bytes(),
+                },
+            )
+            .map_err(|_| GeneratorError::Decryption)?,
+    );
+
+    let mut expanded = Zeroizing::new([0u8; 64]);
+    if plaintext.len() != expanded.len() {
+        return Err(GeneratorError::InvalidKeyFile(format!(
+            "expected 64 key bytes, found {}",
+            plaintext.len()
+        )));
+    }
+    expanded.copy_from_slice(&plaintext);
+    Ok(expanded)
+}
+
+/// The `name: value` lines of a key file, read in their fixed order
+pub(crate) struct KeyFileFields<'a> {
+    lines: std::str::Lines<'a>,
+}
+
+impl<'a> KeyFileFields<'a> {
+    /// Start reading after the `magic` first line
+    pub(crate) fn new(text: &'a str, magic: &str) -> Result<Self, GeneratorError> {
+        let mut lines = text.lines();
+        if lines.next() != Some(magic) {
+            return Err(GeneratorError::InvalidKeyFile(format!("not a {} file", magic)));
+        }
+        Ok(Self { lines })
+    }
+
+    /// Value of the next line, which must be the `name` field
+    pub(crate) fn next(&mut self, name: &str) -> Result<&'a str, GeneratorError> {
+        self.lines
+            .next()
+            .and_then(|line| line.strip_prefix(name)?.strip_prefix(": "))
+            .ok_or_else(|| GeneratorError::InvalidKeyFile(format!("missing {} line", name)))
+    }
+
+    /// Next field, which must have exactly the value `value`
+    pub(crate) fn expect(&mut self, name: &str, value: &str) -> Result<(), GeneratorError> {
+        if self.next(name)? != value {
+            return Err(GeneratorError::InvalidKeyFile(format!("unsupported {}", name)));
+        }
+        Ok(())
+    }
+
+    /// Next field as `N` hex-encoded bytes
+    pub(crate) fn next_hex<const N: usize>(&mut self, name: &str) -> Result<[u8; N], GeneratorError> {
+        hex::decode(self.next(name)?)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| GeneratorError::InvalidKeyFile(format!("bad {}", name)))
+    }
+
+    /// The final `ciphertext` field
+    pub(crate) fn next_ciphertext(&mut self) -> Result<Vec<u8>, GeneratorError> {
+        hex::decode(self.next("ciphertext")?).map_err(|_| GeneratorError::InvalidKeyFile("bad ciphertext".to_string()))
+    }
+}
+
+/// Cheap parameters; the defaults take far too long for tests
+#[cfg(test)]
+pub(crate) const TEST_KDF: KdfParams = KdfParams {
+    memory_kib: 64,
+    iterations: 1,
+    parallelism: 1,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let expanded: [u8; 64] = std::array::from_fn(|i| i as u8);
+        let passphrase = Passphrase::new("correct horse".to_string()).with_kdf_params(TEST_KDF);
+        let sealed = EncryptedKey::seal("example.onion", &expanded, &passphrase).unwrap();
+
+        let text = sealed.to_text();
+        assert!(text.contains("onion: example.onion\n"));
+        let parsed = EncryptedKey::from_text(&text).unwrap();
+        assert_eq!(parsed, sealed);
+        assert_eq!(*parsed.open(&passphrase).unwrap(), expanded);
+
+        let wrong = Passphrase::new("battery staple".to_string());
+        assert!(matches!(parsed.open(&wrong), Err(GeneratorError::Decryption)));
+    }
+
+    #[test]
+    fn test_header_is_authenticated() {
+        let passphrase = Passphrase::new("pass".to_string()).with_kdf_params(TEST_KDF);
+        let text = EncryptedKey::seal("first.onion", &[7; 64], &passphrase)
+            .unwrap()
+            .to_text();
+
+        let renamed = EncryptedKey::from_text(&text.replace("first.onion", "other.onion")).unwrap();
+        assert!(matches!(renamed.open(&passphrase), Err(GeneratorError::Decryption)));
+
+        let weakened = text.replace("m=64 ", "m=32 ");
+        assert!(matches!(
+            EncryptedKey::from_text(&weakened).unwrap().open(&passphrase),
+            Err(GeneratorError::Decryption)
+        ));
+
+        assert!(EncryptedKey::from_text(&text.replace("m=64 ", "m=99999999 ")).is_err());
+        assert!(EncryptedKey::from_text("== ed25519v1-secret: type0 ==").is_err());
+    }
+}
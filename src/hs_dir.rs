@@ -0,0 +1,584 @@
+//! Tor hidden service directories for found keys
+//!
+//! Every backend saves its keys through [`save_key`]. By default that is
+//! [`write_hs_dir`], which lays out a directory Tor can use as
+//! `HiddenServiceDir` as is:
+//!
+//! ```text
+//! <address without .onion>/
+//!     hostname
+//!     hs_ed25519_public_key
+//!     hs_ed25519_secret_key
+//!     authorized_clients/
+//! ```
+//!
+//! Tor stores the secret key in expanded form: the clamped scalar followed by
+//! the 32-byte nonce prefix used when signing, not the seed.
+//!
+//! The directory is assembled under a temporary name and renamed into place
+//! once complete, so Tor never sees half of one, and an existing directory is
+//! never replaced.
+//!
+//! With a [`KeyProtection`] other than plaintext, the secret key file is
+//! replaced by one encrypted under a passphrase (see [`crate::encrypted_key`])
+//! or sealed to a recipient (see [`crate::sealed_key`]), until
+//! [`unlock_hs_dir`] or [`open_hs_dir`] restores it on the machine the
+//! service is deployed to.
+//!
+//! With [`KeyFormat::Arti`], keys go to an Arti keystore instead (see
+//! [`crate::arti_keystore`]).
+
+use std::path::{Path, PathBuf};
+
+use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+use curve25519_dalek::Scalar;
+use zeroize::Zeroizing;
+
+use crate::arti_keystore::{service_dir, write_arti_key};
+use crate::backend::GeneratorError;
+use crate::encrypted_key::{EncryptedKey, Passphrase, ENCRYPTED_SECRET_KEY_FILE};
+use crate::onion::pubkey_to_onion;
+use crate::private_file::{
+    create_new, create_private_dir, create_private_dir_all, publish_dir, replace, temp_path, write_new,
+};
+use crate::sealed_key::{Identity, Recipient, SealedKey, SEALED_SECRET_KEY_FILE};
+use crate::{FILE_PREFIX, PUBKEY_PREFIX};
+
+/// File holding the onion address followed by a newline
+pub const HOSTNAME_FILE: &str = "hostname";
+
+/// File holding [`PUBKEY_PREFIX`] and the public key
+pub const PUBLIC_KEY_FILE: &str = "hs_ed25519_public_key";
+
+/// File holding [`FILE_PREFIX`] and the expanded secret key
+pub const SECRET_KEY_FILE: &str = "hs_ed25519_secret_key";
+
+/// Directory for client authorization keys, which Tor expects to exist
+pub const AUTHORIZED_CLIENTS_DIR: &str = "authorized_clients";
+
+/// How [`write_hs_dir`] stores the secret key
+#[derive(Debug, Clone, Default)]
+pub enum KeyProtection {
+    /// The plain secret key file Tor loads
+    #[default]
+    Plaintext,
+    /// Encrypted under a passphrase
+    Passphrase(Passphrase),
+    /// Sealed to a recipient; the search host cannot open it
+    Recipient(Recipient),
+}
+
+impl KeyProtection {
+    /// Name of the secret key file and its contents for `expanded`
+    fn secret_key_file(
+        &self,
+        onion: &str,
+        expanded: &[u8; 64],
+    ) -> Result<(&'static str, Zeroizing<Vec<u8>>), GeneratorError> {
+        Ok(match self {
+            KeyProtection::Plaintext => (SECRET_KEY_FILE, Zeroizing::new([FILE_PREFIX, expanded].concat())),
+            KeyProtection::Passphrase(passphrase) => (
+                ENCRYPTED_SECRET_KEY_FILE,
+                Zeroizing::new(EncryptedKey::seal(onion, expanded, passphrase)?.to_text().into_bytes()),
+            ),
+            KeyProtection::Recipient(recipient) => (
+                SEALED_SECRET_KEY_FILE,
+                Zeroizing::new(SealedKey::seal(onion, expanded, recipient).to_text().into_bytes()),
+            ),
+        })
+    }
+}
+
+/// Where [`save_key`] puts found keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// A C-tor `HiddenServiceDir` per key in the output directory
+    #[default]
+    HsDir,
+    /// An Arti keystore entry per key, the output directory being the
+    /// keystore
+    Arti,
+}
+
+impl KeyFormat {
+    /// Fail if keys cannot be saved in this format with `protection`
+    pub fn check(&self, protection: &KeyProtection) -> Result<(), GeneratorError> {
+        match (self, protection) {
+            (KeyFormat::Arti, KeyProtection::Passphrase(_) | KeyProtection::Recipient(_)) => Err(
+                GeneratorError::Unsupported("Arti keystores hold plaintext keys only; export them once unlocked".to_string()),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Directory [`save_key`] creates in `output_dir` for `onion_address`
+    pub fn key_dir(&self, output_dir: &Path, onion_address: &str) -> PathBuf {
+        let name = onion_address.trim_end_matches(".onion");
+        match self {
+            KeyFormat::HsDir => output_dir.join(name),
+            KeyFormat::Arti => service_dir(output_dir, name),
+        }
+    }
+}
+
+/// Save a found key to `output_dir` in `format`
+///
+/// Returns the directory of the key, or [`GeneratorError::KeyExists`] if the
+/// key is already there.
+pub fn save_key(
+    output_dir: &Path,
+    pubkey: &[u8; 32],
+    expanded: &[u8; 64],
+    format: KeyFormat,
+    protection: &KeyProtection,
+) -> Result<PathBuf, GeneratorError> {
+    format.check(protection)?;
+    match format {
+        KeyFormat::HsDir => write_hs_dir(output_dir, pubkey, expanded, protection),
+        KeyFormat::Arti => {
+            let onion = pubkey_to_onion(pubkey);
+            write_arti_key(output_dir, onion.trim_end_matches(".onion"), pubkey, expanded)
+        }
+    }
+}
+
+/// Tor expanded secret key derived from an ed25519 seed
+pub fn expand_seed(seed: &[u8; 32]) -> [u8; 64] {
+    // Tor expects: scalar (clamped) || nonce_prefix
+    // ed25519_dalek derives these from SHA512(seed)
+    use sha2::{Digest, Sha512};
+    let hash = Sha512::digest(seed);
+    let mut expanded = [0u8; 64];
+    expanded.copy_from_slice(&hash);
+    // Clamp the scalar part (first 32 bytes)
+    expanded[0] &= 248;
+    expanded[31] &= 127;
+    expanded[31] |= 64;
+    expanded
+}
+
+/// Tor expanded secret key for a bare scalar; no seed exists, so the nonce half is random
+pub fn expand_scalar(scalar: &[u8; 32]) -> [u8; 64] {
+    let nonce_prefix: [u8; 32] = rand::random();
+
+    let mut expanded = [0u8; 64];
+    expanded[..32].copy_from_slice(scalar);
+    expanded[32..].copy_from_slice(&nonce_prefix);
+    expanded
+}
+
+/// Create the hidden service directory for a key in `output_dir`
+///
+/// The address is derived from `pubkey` here, so the directory name, the
+/// hostname and the key files always agree. Unless `protection` is
+/// plaintext, the secret key only reaches the disk encrypted. Returns the
+/// directory, or [`GeneratorError::KeyExists`] if one for this address is
+/// already there.
+pub fn write_hs_dir(
+    output_dir: &Path,
+    pubkey: &[u8; 32],
+    expanded: &[u8; 64],
+    protection: &KeyProtection,
+) -> Result<PathBuf, GeneratorError> {
+    let onion = pubkey_to_onion(pubkey);
+    let hs_dir = output_dir.join(onion.trim_end_matches(".onion"));
+    if hs_dir.symlink_metadata().is_ok() {
+        return Err(GeneratorError::KeyExists(hs_dir));
+    }
+    create_private_dir_all(output_dir)?;
+
+    let secret_key = protection.secret_key_file(&onion, expanded)?;
+
+    let staging = temp_path(&hs_dir);
+    let written =
+        write_hs_files(&staging, &onion, pubkey, (secret_key.0, &secret_key.1)).and_then(|()| publish_dir(&staging, &hs_dir));
+    if let Err(e) = written {
+        let _ = std::fs::remove_dir_all(&staging);
+        return Err(match e.kind() {
+            std::io::ErrorKind::AlreadyExists | std::io::ErrorKind::DirectoryNotEmpty => {
+                GeneratorError::KeyExists(hs_dir)
+            }
+            _ => e.into(),
+        });
+    }
+    Ok(hs_dir)
+}
+
+/// Fill a new staging directory with the files of [`write_hs_dir`], the
+/// secret key given as file name and contents
+///
+/// Nothing else uses the directory before it is published, so the files are
+/// written in place; that also works on file systems without hard links.
+fn write_hs_files(dir: &Path, onion: &str, pubkey: &[u8; 32], secret_key: (&str, &[u8])) -> std::io::Result<()> {
+    create_private_dir(dir)?;
+    create_new(&dir.join(HOSTNAME_FILE), format!("{}\n", onion).as_bytes())?;
+    create_new(&dir.join(PUBLIC_KEY_FILE), &[PUBKEY_PREFIX, pubkey].concat())?;
+    create_new(&dir.join(secret_key.0), secret_key.1)?;
+    create_private_dir(&dir.join(AUTHORIZED_CLIENTS_DIR))
+}
+
+/// Decrypt the secret key of a directory written with a passphrase into the
+/// plain `hs_ed25519_secret_key` Tor loads
+///
+/// The decrypted key must belong to the directory's public key and address.
+/// The encrypted file is kept, and an existing plain key is never replaced.
+/// Returns the path of the written secret key.
+pub fn unlock_hs_dir(hs_dir: &Path, passphrase: &Passphrase) -> Result<PathBuf, GeneratorError> {
+    let encrypted = EncryptedKey::from_text(&std::fs::read_to_string(hs_dir.join(ENCRYPTED_SECRET_KEY_FILE))?)?;
+    restore_secret_key(hs_dir, encrypted.onion_address(), &*encrypted.open(passphrase)?)
+}
+
+/// Open the sealed secret key of a directory written for a recipient into
+/// the plain `hs_ed25519_secret_key` Tor loads, like [`unlock_hs_dir`]
+pub fn open_hs_dir(hs_dir: &Path, identity: &Identity) -> Result<PathBuf, GeneratorError> {
+    let sealed = SealedKey::from_text(&std::fs::read_to_string(hs_dir.join(SEALED_SECRET_KEY_FILE))?)?;
+    restore_secret_key(hs_dir, sealed.onion_address(), &*sealed.open(identity)?)
+}
+
+/// Write a decrypted key as the directory's plain secret key, after checking
+/// it against the public key file and the address it was stored under
+fn restore_secret_key(hs_dir: &Path, onion: &str, expanded: &[u8; 64]) -> Result<PathBuf, GeneratorError> {
+    let public_key: [u8; 32] = read_tagged(&hs_dir.join(PUBLIC_KEY_FILE), PUBKEY_PREFIX)?;
+    if public_key_of(expanded) != public_key || pubkey_to_onion(&public_key) != onion {
+        return Err(GeneratorError::InvalidKeyFile(format!(
+            "{}: the stored key does not belong to {}",
+            hs_dir.display(),
+            pubkey_to_onion(&public_key)
+        )));
+    }
+
+    let secret_path = hs_dir.join(SECRET_KEY_FILE);
+    match write_new(&secret_path, &Zeroizing::new([FILE_PREFIX, expanded].concat())) {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(GeneratorError::KeyExists(secret_path)),
+        Err(e) => Err(e.into()),
+        Ok(()) => Ok(secret_path),
+    }
+}
+
+/// Keys read back from a hidden service directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HsDirKeys {
+    /// Contents of the hostname file, without the newline
+    pub hostname: String,
+    pub public_key: [u8; 32],
+    pub expanded_secret_key: [u8; 64],
+}
+
+/// Read the key files of a hidden service directory
+///
+/// Only the file format is checked here; whether the keys belong together
+/// is up to the caller.
+pub fn read_hs_dir(hs_dir: &Path) -> std::io::Result<HsDirKeys> {
+    let hostname = std::fs::read_to_string(hs_dir.join(HOSTNAME_FILE))?;
+    let public_key = read_tagged(&hs_dir.join(PUBLIC_KEY_FILE), PUBKEY_PREFIX)?;
+    let expanded_secret_key = read_tagged(&hs_dir.join(SECRET_KEY_FILE), FILE_PREFIX)?;
+
+    Ok(HsDirKeys {
+        hostname: hostname.trim_end().to_string(),
+        public_key,
+        expanded_secret_key,
+    })
+}
+
+/// Public key of an expanded secret key, computed from its scalar half
+pub fn public_key_of(expanded: &[u8; 64]) -> [u8; 32] {
+    let scalar_bytes: [u8; 32] = expanded[..32].try_into().unwrap();
+    (Scalar::from_bytes_mod_order(scalar_bytes) * ED25519_BASEPOINT_POINT)
+        .compress()
+        .to_bytes()
+}
+
+/// Is the nonce half of the secret key a copy of the public key?
+///
+/// Older versions of the external CUDA backend wrote keys like this, which
+/// lets anyone who knows the address predict the signature nonces.
+pub fn has_placeholder_nonce(keys: &HsDirKeys) -> bool {
+    keys.expanded_secret_key[32..] == keys.public_key
+}
+
+/// Hidden service directories at `path`: `path` itself if it is one,
+/// otherwise those directly inside it, sorted by name
+///
+/// Hidden entries are skipped, as those are unfinished writes.
+pub fn find_hs_dirs(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let is_hs_dir = |dir: &Path| {
+        [SECRET_KEY_FILE, ENCRYPTED_SECRET_KEY_FILE, SEALED_SECRET_KEY_FILE]
+            .iter()
+            .any(|file| dir.join(file).is_file())
+    };
+    if is_hs_dir(path) {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut hs_dirs = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let dir = entry.path();
+        if !entry.file_name().to_string_lossy().starts_with('.') && is_hs_dir(&dir) {
+            hs_dirs.push(dir);
+        }
+    }
+    hs_dirs.sort();
+    Ok(hs_dirs)
+}
+
+/// What [`repair_hs_dir`] did to a directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repair {
+    /// The nonce half was fine and nothing was written
+    Unaffected,
+    /// The placeholder nonce half was replaced with a random one
+    Repaired,
+}
+
+/// Does the directory hold a plain secret key with a placeholder nonce half
+/// (see [`has_placeholder_nonce`]) that [`repair_hs_dir`] would replace?
+///
+/// Directories with only an encrypted or sealed secret key are unaffected,
+/// as those were never written with a placeholder. An affected key whose
+/// scalar does not produce the stored public key is an error.
+pub fn needs_repair(hs_dir: &Path) -> Result<bool, GeneratorError> {
+    if !hs_dir.join(SECRET_KEY_FILE).exists() {
+        return Ok(false);
+    }
+    let keys = read_hs_dir(hs_dir)?;
+    if !has_placeholder_nonce(&keys) {
+        return Ok(false);
+    }
+    if public_key_of(&keys.expanded_secret_key) != keys.public_key {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "secret key does not match the public key",
+        )
+        .into());
+    }
+    Ok(true)
+}
+
+/// Replace a placeholder nonce half (see [`has_placeholder_nonce`]) with a
+/// random one
+///
+/// This only makes keys that were never deployed safe to use. A service
+/// that signed anything with the placeholder has leaked its scalar, and
+/// the key has to be retired instead.
+///
+/// The scalar must produce the stored public key, so a directory whose
+/// files don't belong together is left alone. The new secret key file is
+/// synced and then renamed over the old one, so an interruption leaves
+/// either the old or the new key behind, never a partial one.
+pub fn repair_hs_dir(hs_dir: &Path) -> Result<Repair, GeneratorError> {
+    if !needs_repair(hs_dir)? {
+        return Ok(Repair::Unaffected);
+    }
+
+    let keys = read_hs_dir(hs_dir)?;
+    let scalar: [u8; 32] = keys.expanded_secret_key[..32].try_into().unwrap();
+    let expanded = expand_scalar(&scalar);
+
+    replace(&hs_dir.join(SECRET_KEY_FILE), &[FILE_PREFIX, &expanded].concat())?;
+
+    Ok(Repair::Repaired)
+}
+
+/// Contents of a key file after its 32-byte tag
+fn read_tagged<const N: usize>(path: &Path, tag: &[u8]) -> std::io::Result<[u8; N]> {
+    let invalid = |reason: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), reason),
+        )
+    };
+
+    let contents = std::fs::read(path)?;
+    let body = contents
+        .strip_prefix(tag)
+        .ok_or_else(|| invalid("unexpected file header"))?;
+    body.try_into()
+        .map_err(|_| invalid(&format!("expected {} key bytes, found {}", N, body.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::hazmat::ExpandedSecretKey;
+    use ed25519_dalek::{SigningKey, VerifyingKey};
+
+    fn pubkey_of_seed(seed: &[u8; 32]) -> [u8; 32] {
+        SigningKey::from_bytes(seed).verifying_key().to_bytes()
+    }
+
+    /// Re-derive everything from the secret key file alone
+    fn assert_loadable(hs_dir: &Path) {
+        let keys = read_hs_dir(hs_dir).unwrap();
+        let esk = ExpandedSecretKey::from_bytes(&keys.expanded_secret_key);
+        let pubkey = VerifyingKey::from(&esk).to_bytes();
+
+        assert_eq!(pubkey, keys.public_key);
+        assert_eq!(pubkey_to_onion(&pubkey), keys.hostname);
+        assert_eq!(hs_dir.file_name().unwrap().to_str().unwrap(), keys.hostname.trim_end_matches(".onion"));
+        assert!(hs_dir.join(AUTHORIZED_CLIENTS_DIR).is_dir());
+    }
+
+    #[test]
+    fn test_seed_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let seed: [u8; 32] = rand::random();
+        let pubkey = SigningKey::from_bytes(&seed).verifying_key().to_bytes();
+
+        let hs_dir = write_hs_dir(dir.path(), &pubkey, &expand_seed(&seed), &KeyProtection::Plaintext).unwrap();
+        assert_loadable(&hs_dir);
+
+        // Signatures made from the seed and from the expanded key agree
+        let esk = ExpandedSecretKey::from_bytes(&read_hs_dir(&hs_dir).unwrap().expanded_secret_key);
+        let verifying_key = VerifyingKey::from(&esk);
+        let message = b"round trip";
+        let signature = ed25519_dalek::hazmat::raw_sign::<sha2::Sha512>(&esk, message, &verifying_key);
+        assert_eq!(signature, ed25519_dalek::Signer::sign(&SigningKey::from_bytes(&seed), message));
+    }
+
+    #[test]
+    fn test_scalar_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut scalar: [u8; 32] = rand::random();
+        scalar[0] &= 248;
+        scalar[31] &= 127;
+        scalar[31] |= 64;
+        let expanded = expand_scalar(&scalar);
+        let pubkey = VerifyingKey::from(&ExpandedSecretKey::from_bytes(&expanded)).to_bytes();
+
+        let hs_dir = write_hs_dir(dir.path(), &pubkey, &expanded, &KeyProtection::Plaintext).unwrap();
+        assert_loadable(&hs_dir);
+    }
+
+    #[test]
+    fn test_repair_placeholder_nonce() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut expanded = expand_scalar(&rand::random());
+        let pubkey = public_key_of(&expanded);
+        expanded[32..].copy_from_slice(&pubkey);
+        let hs_dir = write_hs_dir(dir.path(), &pubkey, &expanded, &KeyProtection::Plaintext).unwrap();
+        let healthy = write_hs_dir(
+            dir.path(),
+            &pubkey_of_seed(&[7; 32]),
+            &expand_seed(&[7; 32]),
+            &KeyProtection::Plaintext,
+        )
+        .unwrap();
+        assert_eq!(find_hs_dirs(dir.path()).unwrap().len(), 2);
+
+        assert!(!needs_repair(&healthy).unwrap());
+        assert!(needs_repair(&hs_dir).unwrap());
+        assert_eq!(repair_hs_dir(&healthy).unwrap(), Repair::Unaffected);
+        assert_eq!(repair_hs_dir(&hs_dir).unwrap(), Repair::Repaired);
+        assert!(!needs_repair(&hs_dir).unwrap());
+        assert_eq!(repair_hs_dir(&hs_dir).unwrap(), Repair::Unaffected);
+
+        let keys = read_hs_dir(&hs_dir).unwrap();
+        assert!(!has_placeholder_nonce(&keys));
+        assert_eq!(keys.expanded_secret_key[..32], expanded[..32]);
+        assert_eq!(public_key_of(&keys.expanded_secret_key), pubkey);
+        assert_eq!(std::fs::read_dir(&hs_dir).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn test_refuses_to_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let seed = [3; 32];
+        let hs_dir = write_hs_dir(
+            dir.path(),
+            &pubkey_of_seed(&seed),
+            &expand_seed(&seed),
+            &KeyProtection::Plaintext,
+        )
+        .unwrap();
+
+        let other = expand_scalar(&[5; 32]);
+        match write_hs_dir(dir.path(), &pubkey_of_seed(&seed), &other, &KeyProtection::Plaintext) {
+            Err(GeneratorError::KeyExists(path)) => assert_eq!(path, hs_dir),
+            other => panic!("expected KeyExists, got {:?}", other),
+        }
+        assert_loadable(&hs_dir);
+        assert_eq!(read_hs_dir(&hs_dir).unwrap().expanded_secret_key, expand_seed(&seed));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_repair_refuses_mismatched_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let pubkey = pubkey_of_seed(&[1; 32]);
+        let mut expanded = expand_seed(&[2; 32]);
+        expanded[32..].copy_from_slice(&pubkey);
+        let hs_dir = write_hs_dir(dir.path(), &pubkey, &expanded, &KeyProtection::Plaintext).unwrap();
+
+        assert!(needs_repair(&hs_dir).is_err());
+        assert!(repair_hs_dir(&hs_dir).is_err());
+        assert_eq!(read_hs_dir(&hs_dir).unwrap().expanded_secret_key, expanded);
+    }
+
+    #[test]
+    fn test_encrypted_dir_unlocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let seed: [u8; 32] = rand::random();
+        let passphrase = Passphrase::new("hunter2".to_string()).with_kdf_params(crate::encrypted_key::TEST_KDF);
+        let protection = KeyProtection::Passphrase(passphrase.clone());
+        let hs_dir = write_hs_dir(dir.path(), &pubkey_of_seed(&seed), &expand_seed(&seed), &protection).unwrap();
+
+        // Nothing secret is readable, but the address is
+        assert!(!hs_dir.join(SECRET_KEY_FILE).exists());
+        let text = std::fs::read_to_string(hs_dir.join(ENCRYPTED_SECRET_KEY_FILE)).unwrap();
+        assert_eq!(EncryptedKey::from_text(&text).unwrap().onion_address(), pubkey_to_onion(&pubkey_of_seed(&seed)));
+        assert_eq!(find_hs_dirs(dir.path()).unwrap(), std::slice::from_ref(&hs_dir));
+
+        let wrong = Passphrase::new("hunter3".to_string());
+        assert!(matches!(unlock_hs_dir(&hs_dir, &wrong), Err(GeneratorError::Decryption)));
+        assert!(!hs_dir.join(SECRET_KEY_FILE).exists());
+
+        unlock_hs_dir(&hs_dir, &passphrase).unwrap();
+        assert_loadable(&hs_dir);
+        assert!(matches!(
+            unlock_hs_dir(&hs_dir, &passphrase),
+            Err(GeneratorError::KeyExists(_))
+        ));
+    }
+
+    #[test]
+    fn test_sealed_dir_opens() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = Identity::generate();
+        let expanded = expand_scalar(&[11; 32]);
+        let pubkey = public_key_of(&expanded);
+        let protection = KeyProtection::Recipient(identity.recipient());
+        let hs_dir = write_hs_dir(dir.path(), &pubkey, &expanded, &protection).unwrap();
+
+        assert!(!hs_dir.join(SECRET_KEY_FILE).exists());
+        assert_eq!(find_hs_dirs(dir.path()).unwrap().len(), 1);
+        assert!(!needs_repair(&hs_dir).unwrap());
+        assert!(matches!(
+            open_hs_dir(&hs_dir, &Identity::generate()),
+            Err(GeneratorError::Decryption)
+        ));
+
+        open_hs_dir(&hs_dir, &identity).unwrap();
+        assert_eq!(read_hs_dir(&hs_dir).unwrap().expanded_secret_key, expanded);
+    }
+
+    #[test]
+    fn test_old_keypair_output_does_not_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let seed: [u8; 32] = rand::random();
+        let signing_key = SigningKey::from_bytes(&seed);
+        let pubkey = signing_key.verifying_key().to_bytes();
+        let hs_dir = write_hs_dir(dir.path(), &pubkey, &expand_seed(&seed), &KeyProtection::Plaintext).unwrap();
+
+        // seed || public key has the right size but is no expanded key
+        let mut keypair = FILE_PREFIX.to_vec();
+        keypair.extend_from_slice(&signing_key.to_keypair_bytes());
+        std::fs::write(hs_dir.join(SECRET_KEY_FILE), keypair).unwrap();
+        let keys = read_hs_dir(&hs_dir).unwrap();
+        let esk = ExpandedSecretKey::from_bytes(&keys.expanded_secret_key);
+        assert_ne!(VerifyingKey::from(&esk).to_bytes(), keys.public_key);
+
+        std::fs::write(hs_dir.join(PUBLIC_KEY_FILE), [0u8; 64]).unwrap();
+        assert!(read_hs_dir(&hs_dir).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+//! Consistency checks of existing hidden service directories
+//!
+//! [`inspect_hs_dir`] reads the key files and hostname of a directory without
+//! trusting any of them: the public key and address are derived from the
+//! secret key again and compared with what the directory claims. Every
+//! problem found is reported instead of stopping at the first one.
+//!
+//! The ed25519 seed is never available from a directory: Tor only keeps the
+//! expanded key, which is all signing needs.
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::encrypted_key::ENCRYPTED_SECRET_KEY_FILE;
+use crate::hs_dir::{public_key_of, HOSTNAME_FILE, PUBLIC_KEY_FILE, SECRET_KEY_FILE};
+use crate::onion::pubkey_to_onion;
+use crate::sealed_key::SEALED_SECRET_KEY_FILE;
+use crate::{FILE_PREFIX, PUBKEY_PREFIX};
+
+/// Length of the header of Tor's key files
+const HEADER_LEN: usize = 32;
+
+/// What a hidden service directory holds and whether it agrees with itself
+#[derive(Debug, Clone, Serialize)]
+pub struct Inspection {
+    pub path: PathBuf,
+    /// Address of the secret key, if it could be read
+    pub onion_address: Option<String>,
+    /// True if no problems were found
+    pub consistent: bool,
+    /// Header of the secret key file, e.g. `ed25519v1-secret: type0`
+    pub secret_key_header: Option<String>,
+    /// Header of the public key file, e.g. `ed25519v1-public: type0`
+    pub public_key_header: Option<String>,
+    /// Public key derived from the secret key, in hex
+    pub derived_public_key: Option<String>,
+    /// Public key stored in the directory, in hex
+    pub public_key: Option<String>,
+    /// Address in the hostname file
+    pub hostname: Option<String>,
+    /// Whether the ed25519 seed of the key is known. Always false: Tor
+    /// hs_ed25519 directories store only the expanded key
+    pub seed_available: bool,
+    /// Whether the nonce half is the public key, as written by older
+    /// versions of the external CUDA backend (see `t3v repair`)
+    pub placeholder_nonce: bool,
+    pub problems: Vec<String>,
+}
+
+/// Check the hidden service directory `path`, or the directory of the key
+/// file `path`
+pub fn inspect_hs_dir(path: &Path) -> Inspection {
+    let dir = if path.is_file() {
+        path.parent().unwrap_or(Path::new("."))
+    } else {
+        path
+    };
+    let mut problems = Vec::new();
+
+    let (secret_key_header, expanded) = read_key_file::<64>(&dir.join(SECRET_KEY_FILE), FILE_PREFIX, &mut problems);
+    let (public_key_header, public_key) = read_key_file::<32>(&dir.join(PUBLIC_KEY_FILE), PUBKEY_PREFIX, &mut problems);
+    let hostname = match std::fs::read_to_string(dir.join(HOSTNAME_FILE)) {
+        Ok(hostname) => Some(hostname.trim_end().to_string()),
+        Err(e) => {
+            problems.push(format!("{}: {}", HOSTNAME_FILE, e));
+            None
+        }
+    };
+    if expanded.is_none() {
+        for protected in [ENCRYPTED_SECRET_KEY_FILE, SEALED_SECRET_KEY_FILE] {
+            if dir.join(protected).is_file() {
+                problems.push(format!("the secret key is only in {}; unlock or open it to check it", protected));
+            }
+        }
+    }
+
+    let derived = expanded.as_ref().map(public_key_of);
+    let onion_address = derived.as_ref().map(pubkey_to_onion);
+    if let (Some(derived), Some(public_key)) = (derived, public_key) {
+        if derived != public_key {
+            problems.push(format!(
+                "{} does not belong to {}: it is the key of {}",
+                PUBLIC_KEY_FILE,
+                SECRET_KEY_FILE,
+                pubkey_to_onion(&public_key)
+            ));
+        }
+    }
+    // Without a secret key, the public key is the best source of the address
+    if let (Some(expected), Some(hostname)) = (
+        onion_address.clone().or(public_key.as_ref().map(pubkey_to_onion)),
+        &hostname,
+    ) {
+        if &expected != hostname {
+            problems.push(format!("{} is {}, but the key is for {}", HOSTNAME_FILE, hostname, expected));
+        }
+    }
+
+    let placeholder_nonce = match (expanded, derived) {
+        (Some(expanded), Some(derived)) => expanded[32..] == derived,
+        _ => false,
+    };
+    if placeholder_nonce {
+        problems.push("the nonce half is the public key; run `t3v repair` on this directory".to_string());
+    }
+
+    Inspection {
+        path: dir.to_path_buf(),
+        onion_address,
+        consistent: problems.is_empty(),
+        secret_key_header,
+        public_key_header,
+        derived_public_key: derived.map(hex::encode),
+        public_key: public_key.map(hex::encode),
+        hostname,
+        seed_available: false,
+        placeholder_nonce,
+        problems,
+    }
+}
+
+/// Header text and key of a Tor key file, recording what is wrong with it
+///
+/// The key is only returned if the header is `expected_header` and the body
+/// has `N` bytes.
+fn read_key_file<const N: usize>(
+    path: &Path,
+    expected_header: &[u8],
+    problems: &mut Vec<String>,
+) -> (Option<String>, Option<[u8; N]>) {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            problems.push(format!("{}: {}", name, e));
+            return (None, None);
+        }
+    };
+    if contents.len() < HEADER_LEN {
+        problems.push(format!("{}: too short for a key file ({} bytes)", name, contents.len()));
+        return (None, None);
+    }
+
+    let (header, body) = contents.split_at(HEADER_LEN);
+    let header_text = header_text(header);
+    if header != expected_header {
+        problems.push(format!(
+            "{}: unexpected header '{}', expected '{}'",
+            name,
+            header_text,
+            self::header_text(expected_header)
+        ));
+        return (Some(header_text), None);
+    }
+    match body.try_into() {
+        Ok(key) => (Some(header_text), Some(key)),
+        Err(_) => {
+            problems.push(format!("{}: expected {} key bytes, found {}", name, N, body.len()));
+            (Some(header_text), None)
+        }
+    }
+}
+
+/// Readable part of a key file header: `== <text> ==` padded with NULs
+fn header_text(header: &[u8]) -> String {
+    let text = String::from_utf8_lossy(header);
+    let text = text.trim_end_matches('\0');
+    text.strip_prefix("== ")
+        .and_then(|text| text.strip_suffix(" =="))
+        .unwrap_or(text)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hs_dir::{expand_seed, write_hs_dir, KeyProtection};
+    use ed25519_dalek::SigningKey;
+
+    fn write_dir(dir: &Path, seed: [u8; 32]) -> PathBuf {
+        let pubkey = SigningKey::from_bytes(&seed).verifying_key().to_bytes();
+        write_hs_dir(dir, &pubkey, &expand_seed(&seed), &KeyProtection::Plaintext).unwrap()
+    }
+
+    #[test]
+    fn test_consistent_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let hs_dir = write_dir(dir.path(), [1; 32]);
+
+        // The secret key file stands for its directory
+        let inspection = inspect_hs_dir(&hs_dir.join(SECRET_KEY_FILE));
+        assert!(inspection.consistent, "{:?}", inspection.problems);
+        assert_eq!(inspection.path, hs_dir);
+        assert_eq!(inspection.secret_key_header.as_deref(), Some("ed25519v1-secret: type0"));
+        assert_eq!(inspection.public_key_header.as_deref(), Some("ed25519v1-public: type0"));
+        assert_eq!(inspection.onion_address, inspection.hostname);
+        assert_eq!(inspection.derived_public_key, inspection.public_key);
+        assert!(!inspection.seed_available);
+
+        let json: serde_json::Value = serde_json::to_value(&inspection).unwrap();
+        assert_eq!(json["consistent"], true);
+        assert_eq!(json["onion_address"], inspection.onion_address.unwrap());
+    }
+
+    #[test]
+    fn test_mismatches_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let hs_dir = write_dir(dir.path(), [1; 32]);
+        let other = write_dir(dir.path(), [2; 32]);
+        std::fs::copy(other.join(PUBLIC_KEY_FILE), hs_dir.join(PUBLIC_KEY_FILE)).unwrap();
+        std::fs::write(hs_dir.join(HOSTNAME_FILE), "wrong.onion\n").unwrap();
+
+        let inspection = inspect_hs_dir(&hs_dir);
+        assert!(!inspection.consistent);
+        assert_eq!(inspection.problems.len(), 2, "{:?}", inspection.problems);
+        assert_ne!(inspection.derived_public_key, inspection.public_key);
+
+        // A secret key file with a public key header is not read as a key
+        std::fs::copy(hs_dir.join(PUBLIC_KEY_FILE), hs_dir.join(SECRET_KEY_FILE)).unwrap();
+        let inspection = inspect_hs_dir(&hs_dir);
+        assert_eq!(inspection.secret_key_header.as_deref(), Some("ed25519v1-public: type0"));
+        assert_eq!(inspection.onion_address, None);
+        assert!(inspection.problems.iter().any(|problem| problem.contains("unexpected header")));
+    }
+}
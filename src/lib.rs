@@ -3,14 +3,26 @@
 //! This library provides functionality to generate Tor v3 vanity addresses
 //! with both CUDA GPU acceleration and CPU fallback support.
 
+pub mod arti_keystore;
 pub mod backend;
+pub mod control_port;
+pub mod encrypted_key;
+pub mod hs_dir;
+pub mod inspect;
+pub mod manifest;
 pub mod onion;
+pub mod pattern;
+pub mod private_file;
+pub mod sealed_key;
+pub mod wordlist;
 
 pub use backend::{
-    select_backend, select_backend_with_mode, select_backend_with_config,
-    Backend, BackendInfo, BackendMode, GeneratorError, FoundKey, Progress, SearchFilter, format_speed,
+    select_backend, select_backend_with_mode, select_backend_with_config, select_backend_with_cpu,
+    BackendInfo, BackendKind, BackendMode, BackendSelector, Capabilities, CpuSearchMode, GeneratorError, FoundKey, Progress, Quota, SearchBackend,
+    SearchChannels, SearchEvent, SearchFilter, SearchOutcome, format_speed,
 };
-pub use onion::pubkey_to_onion;
+pub use onion::{onion_to_pubkey, pubkey_to_onion, OnionAddress, OnionAddressError};
+pub use pattern::BytePattern;
 
 /// File prefix for Tor ed25519 secret key files
 pub const FILE_PREFIX: &[u8] = b"== ed25519v1-secret: type0 ==\0\0\0";
@@ -2,22 +2,54 @@
 //!
 //! A high-performance vanity address generator with GPU acceleration and CPU fallback.
 
-use clap::{Parser, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use crossbeam_channel::unbounded;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
 
-use tor_v3_vanity::backend::{select_backend_with_mode, select_backend_with_config, BackendMode, Progress, SearchFilter};
+use tor_v3_vanity::arti_keystore::{export_hs_dir, read_arti_key};
+use tor_v3_vanity::backend::{
+    format_speed, BackendKind, BackendMode, BackendSelector, CpuBackend, CpuSearchMode, FoundKey, GeneratorError,
+    Quota, SearchEvent, SearchFilter, SearchSession, SearchState, SessionState,
+};
+use tor_v3_vanity::control_port::{add_onion_key, ControlAuth, ControlPort, PortMapping};
+use tor_v3_vanity::encrypted_key::{Passphrase, ENCRYPTED_SECRET_KEY_FILE};
+use tor_v3_vanity::hs_dir::{
+    find_hs_dirs, needs_repair, open_hs_dir, read_hs_dir, repair_hs_dir, unlock_hs_dir, KeyFormat, KeyProtection, Repair,
+    SECRET_KEY_FILE,
+};
+use tor_v3_vanity::inspect::{inspect_hs_dir, Inspection};
+use tor_v3_vanity::pattern::{BytePattern, Pattern, RegexPattern};
+use tor_v3_vanity::private_file::write_new;
+use tor_v3_vanity::sealed_key::{Identity, Recipient, SEALED_SECRET_KEY_FILE};
+use tor_v3_vanity::wordlist::{format_ranking, Leaderboard, Wordlist, RANKING_FILE};
 
 #[derive(Parser)]
 #[command(name = "t3v")]
 #[command(about = "Tor V3 vanity address generator with GPU acceleration")]
 #[command(version)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    /// Desired prefixes (comma-separated)
-    #[arg(required = true, value_delimiter = ',')]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Desired patterns (comma-separated): `shop` matches at the start,
+    /// `*shop` at the end and `??shop*` at a fixed offset (`?` = any character)
+    #[arg(required_unless_present_any = ["regex", "wordlist"], value_delimiter = ',')]
     prefixes: Vec<String>,
 
+    /// Regular expression over the address (a-z, 2-7, `.`, `[]`, `()`, `|`,
+    /// quantifiers, `^`/`$`); can be given multiple times. Runs on the CPU
+    #[arg(short = 'r', long = "regex", value_name = "REGEX")]
+    regex: Vec<String>,
+
+    /// Let letters of the patterns also match their digit look-alikes
+    /// (a/4, e/3, s/5, t/7, z/2), so `tor` also finds `7or`
+    #[arg(short = 'l', long)]
+    lookalikes: bool,
+
     /// Output directory for generated keys
     #[arg(short, long, default_value = ".")]
     dst: PathBuf,
@@ -26,6 +58,16 @@ struct Cli {
     #[arg(short, long, value_enum, default_value = "auto")]
     mode: Mode,
 
+    /// Fail if the backend the mode asks for is unavailable, instead of
+    /// falling back to another one (e.g. the CPU)
+    #[arg(long)]
+    strict: bool,
+
+    /// Backends to try, in order (comma-separated); the first available one
+    /// is used and the mode is ignored
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "BACKENDS", conflicts_with = "mode")]
+    backend_order: Vec<Candidate>,
+
     /// Number of CPU threads (only used in cpu and hybrid modes)
     #[arg(short = 't', long, default_value_t = num_cpus::get())]
     threads: usize,
@@ -34,6 +76,218 @@ struct Cli {
     /// Can be specified multiple times: --contains block --contains chain
     #[arg(short = 'c', long = "contains", value_name = "WORD")]
     contains: Vec<String>,
+
+    /// Number of keys to find per pattern
+    #[arg(short = 'n', long, default_value_t = 1)]
+    count: usize,
+
+    /// Keep finding keys for every pattern until stopped (Ctrl+C)
+    #[arg(long, conflicts_with = "count")]
+    keep_going: bool,
+
+    /// Instead of exact patterns, rank addresses by the words of this
+    /// dictionary (one per line) found anywhere in them; runs until stopped
+    #[arg(short = 'w', long, value_name = "FILE", conflicts_with_all = ["prefixes", "regex", "contains"])]
+    wordlist: Option<PathBuf>,
+
+    /// Number of best addresses to keep in wordlist mode
+    #[arg(long, default_value_t = 10, requires = "wordlist")]
+    top: usize,
+
+    /// How CPU workers derive candidate keys
+    #[arg(long, value_enum, default_value = "batched")]
+    cpu_search: CpuSearch,
+
+    /// Save secret keys encrypted under a passphrase (asked for at start);
+    /// `t3v unlock` restores them where the service runs
+    #[arg(long)]
+    encrypt: bool,
+
+    /// Read the passphrase from the first line of this file instead of
+    /// asking; implies --encrypt
+    #[arg(long, value_name = "FILE")]
+    passphrase_file: Option<PathBuf>,
+
+    /// Seal secret keys to this X25519 public key (64 hex digits, see `t3v
+    /// keygen`) as soon as they are found; only its identity can open them
+    #[arg(long, value_name = "KEY", conflicts_with_all = ["encrypt", "passphrase_file"])]
+    recipient: Option<String>,
+
+    /// How to save found keys
+    #[arg(long, value_enum, default_value = "hs-dir")]
+    format: Format,
+
+    /// Append a JSON record of every found key to this file, one per line
+    #[arg(long, value_name = "FILE", conflicts_with = "wordlist")]
+    manifest: Option<PathBuf>,
+
+    #[command(flatten)]
+    control: ControlArgs,
+}
+
+/// Where and how to register keys with a running Tor
+#[derive(Args)]
+struct ControlArgs {
+    /// Tor ControlPort to add every key to as an onion service, e.g.
+    /// 127.0.0.1:9051; services last until Tor restarts
+    #[arg(long, value_name = "ADDR")]
+    control_port: Option<String>,
+
+    /// Authenticate with this cookie file (default: the one Tor reports)
+    #[arg(long, value_name = "FILE")]
+    control_cookie: Option<PathBuf>,
+
+    /// Authenticate with the password in the first line of this file
+    #[arg(long, value_name = "FILE", conflicts_with = "control_cookie")]
+    control_password_file: Option<PathBuf>,
+
+    /// Port of the added services, as VIRTPORT[,TARGET] like Tor's
+    /// HiddenServicePort (e.g. 80,127.0.0.1:8080); can be given multiple times
+    #[arg(long = "onion-port", value_name = "MAPPING")]
+    onion_ports: Vec<PortMapping>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List keys saved with the public key as their nonce half (by older
+    /// versions of the external CUDA backend), and give keys that were never
+    /// deployed a random nonce half
+    ///
+    /// A deployed key of this kind is compromised and cannot be repaired:
+    /// retire it and move the service to a new address.
+    Repair {
+        /// Hidden service directories, or directories containing them
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Only list the affected keys, which is also done without
+        /// --never-deployed
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Repair the affected keys, confirming none of them was ever used by
+        /// a running service
+        #[arg(long, conflicts_with = "dry_run")]
+        never_deployed: bool,
+    },
+
+    /// Decrypt the secret keys of directories saved with --encrypt into the
+    /// hs_ed25519_secret_key Tor loads
+    #[command(alias = "decrypt")]
+    Unlock {
+        /// Hidden service directories, or directories containing them
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Read the passphrase from the first line of this file instead of
+        /// asking
+        #[arg(long, value_name = "FILE")]
+        passphrase_file: Option<PathBuf>,
+    },
+
+    /// Create an identity file to open keys sealed with --recipient, and
+    /// print the recipient key to search with
+    Keygen {
+        /// Where to save the identity; keep it off the search machines
+        identity: PathBuf,
+    },
+
+    /// Open the secret keys of directories saved with --recipient into the
+    /// hs_ed25519_secret_key Tor loads
+    Open {
+        /// Identity file from `t3v keygen`
+        #[arg(short, long, value_name = "FILE")]
+        identity: PathBuf,
+
+        /// Hidden service directories, or directories containing them
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Copy the keys of hidden service directories into an Arti keystore
+    ExportArti {
+        /// Keystore directory, e.g. ~/.local/share/arti/keystore
+        #[arg(short, long, value_name = "DIR")]
+        keystore: PathBuf,
+
+        /// Service nickname for a single key (default: its address)
+        #[arg(long)]
+        nickname: Option<String>,
+
+        /// Hidden service directories, or directories containing them
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Check that the keys and hostname of hidden service directories belong
+    /// together, and show what they hold
+    Inspect {
+        /// Hidden service directories, directories containing them or secret
+        /// key files
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Print one JSON object per directory
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Print the ED25519-V3:<base64> key argument of Tor's ADD_ONION command
+    /// for hidden service directories, after their address
+    ExportAddOnion {
+        /// Hidden service directories, or directories containing them
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Add the keys of hidden service directories to a running Tor through
+    /// its ControlPort
+    AddOnion {
+        #[command(flatten)]
+        control: ControlArgs,
+
+        /// Hidden service directories, or directories containing them
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum CpuSearch {
+    /// Incremental walk with batched point compression (fastest)
+    Batched,
+    /// One point addition and one point compression per key
+    Incremental,
+    /// Fresh random seed per key (keeps the ed25519 seed, slower)
+    Seed,
+}
+
+impl From<CpuSearch> for CpuSearchMode {
+    fn from(search: CpuSearch) -> Self {
+        match search {
+            CpuSearch::Batched => CpuSearchMode::Batched,
+            CpuSearch::Incremental => CpuSearchMode::Incremental,
+            CpuSearch::Seed => CpuSearchMode::Seed,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// A Tor HiddenServiceDir per key in the output directory
+    HsDir,
+    /// An Arti keystore entry per key (hss/<address>/), the output directory
+    /// being the keystore
+    Arti,
+}
+
+impl From<Format> for KeyFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::HsDir => KeyFormat::HsDir,
+            Format::Arti => KeyFormat::Arti,
+        }
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
@@ -50,6 +304,28 @@ enum Mode {
     Hybrid,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Candidate {
+    /// CPU + built-in CUDA
+    Hybrid,
+    /// Built-in CUDA
+    Cuda,
+    /// The external vanity_torv3_cuda executable
+    ExternalCuda,
+    Cpu,
+}
+
+impl From<Candidate> for BackendKind {
+    fn from(candidate: Candidate) -> Self {
+        match candidate {
+            Candidate::Hybrid => BackendKind::Hybrid,
+            Candidate::Cuda => BackendKind::Cuda,
+            Candidate::ExternalCuda => BackendKind::ExternalCuda,
+            Candidate::Cpu => BackendKind::Cpu,
+        }
+    }
+}
+
 impl From<Mode> for BackendMode {
     fn from(mode: Mode) -> Self {
         match mode {
@@ -90,158 +366,836 @@ impl std::fmt::Display for PrettyDur {
 fn main() {
     let cli = Cli::parse();
 
+    if let Some(command) = &cli.command {
+        match command {
+            Command::Repair {
+                paths,
+                dry_run,
+                never_deployed,
+            } => run_repair(paths, *dry_run || !*never_deployed),
+            Command::Unlock { paths, passphrase_file } => {
+                let passphrase = read_passphrase(passphrase_file.as_deref(), false);
+                restore_keys(paths, ENCRYPTED_SECRET_KEY_FILE, |hs_dir| {
+                    unlock_hs_dir(hs_dir, &passphrase)
+                })
+            }
+            Command::Keygen { identity } => run_keygen(identity),
+            Command::Open { identity, paths } => {
+                let identity = read_identity(identity);
+                restore_keys(paths, SEALED_SECRET_KEY_FILE, |hs_dir| open_hs_dir(hs_dir, &identity))
+            }
+            Command::ExportArti { keystore, nickname, paths } => run_export_arti(keystore, nickname.as_deref(), paths),
+            Command::Inspect { paths, json } => run_inspect(paths, *json),
+            Command::ExportAddOnion { paths } => run_export_add_onion(paths),
+            Command::AddOnion { control, paths } => match connect_control(control) {
+                Some(mut tor) => run_add_onion(&mut tor, &control.onion_ports, paths),
+                None => {
+                    eprintln!("Error: --control-port is required");
+                    std::process::exit(1);
+                }
+            },
+        }
+        return;
+    }
+
     // Validate output directory
     if !cli.dst.is_dir() {
         eprintln!("Error: '{}' is not a directory", cli.dst.display());
         std::process::exit(1);
     }
 
-    // Validate prefixes
-    let max_len = cli.prefixes.iter().map(|p| p.len()).max().unwrap_or(0);
+    // Arti cannot load protected keys; say so before asking for a passphrase
+    let format = KeyFormat::from(cli.format);
+    if format == KeyFormat::Arti && (cli.encrypt || cli.passphrase_file.is_some() || cli.recipient.is_some()) {
+        eprintln!("Error: --format arti saves plaintext keys only; use `t3v export-arti` once keys are unlocked");
+        std::process::exit(1);
+    }
+
+    let protection = if let Some(recipient) = &cli.recipient {
+        match Recipient::from_hex(recipient) {
+            Ok(recipient) => KeyProtection::Recipient(recipient),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if cli.encrypt || cli.passphrase_file.is_some() {
+        KeyProtection::Passphrase(read_passphrase(cli.passphrase_file.as_deref(), true))
+    } else {
+        KeyProtection::Plaintext
+    };
+
+    if cli.control.control_port.is_some() && (cli.wordlist.is_some() || !matches!(protection, KeyProtection::Plaintext)) {
+        eprintln!("Error: --control-port needs plaintext keys and cannot be used with --wordlist");
+        std::process::exit(1);
+    }
+    let mut tor = connect_control(&cli.control);
+
+    if let Some(path) = &cli.wordlist {
+        run_wordlist(&cli, path, format, protection);
+        return;
+    }
+
+    if cli.count == 0 {
+        eprintln!("Error: --count must be at least 1");
+        std::process::exit(1);
+    }
+    let quota = if cli.keep_going {
+        Quota::Unlimited
+    } else {
+        Quota::Count(cli.count)
+    };
+
+    // Validate patterns; the rarest one sets the expected amount of work
+    let mut min_probability = 1.0_f64;
     for prefix in &cli.prefixes {
         if prefix.is_empty() {
             eprintln!("Error: Empty prefix not allowed");
             std::process::exit(1);
         }
-        // Check if valid base32
-        if base32::decode(
-            base32::Alphabet::Rfc4648Lower { padding: false },
-            &format!("{}aa", prefix),
-        )
-        .is_none()
-        {
-            eprintln!("Error: '{}' is not a valid base32 prefix", prefix);
-            std::process::exit(1);
+        let pattern = if cli.lookalikes {
+            Pattern::with_lookalikes(prefix)
+        } else {
+            BytePattern::new(prefix).map(Pattern::Bytes)
+        };
+        match pattern {
+            Ok(pattern) => min_probability = min_probability.min(pattern.match_probability()),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    for regex in &cli.regex {
+        match RegexPattern::new(regex) {
+            Ok(pattern) => min_probability = min_probability.min(pattern.match_probability()),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
         }
     }
 
     println!("=== Tor V3 Vanity Generator ===");
-    println!("Prefixes: {:?}", cli.prefixes);
+    println!("Patterns: {:?}", cli.prefixes);
+    if cli.lookalikes {
+        println!("Look-alikes: a/4 e/3 s/5 t/7 z/2");
+    }
+    if !cli.regex.is_empty() {
+        println!("Regex: {:?}", cli.regex);
+    }
     if !cli.contains.is_empty() {
         println!("Must contain: {:?}", cli.contains);
     }
-    println!("Output: {}", cli.dst.display());
+    match quota {
+        Quota::Count(1) => {}
+        Quota::Count(count) => println!("Keys per pattern: {}", count),
+        Quota::Unlimited => println!("Keys per pattern: unlimited (until Ctrl+C)"),
+    }
+    print_output(&cli.dst, format);
+    print_protection(&protection);
+    if let Some(manifest) = &cli.manifest {
+        println!("Manifest: {}", manifest.display());
+    }
     println!("CPU threads: {}", cli.threads);
+    println!("CPU search: {}", CpuSearchMode::from(cli.cpu_search).as_str());
     println!();
 
+    // Regular expressions only run on the CPU, so don't pick a GPU for them
+    let mode = if !cli.regex.is_empty() && cli.mode == Mode::Auto {
+        println!("Regex patterns run on the CPU backend");
+        Mode::Cpu
+    } else {
+        cli.mode
+    };
+
+    // Set up channels
+    let (event_tx, event_rx) = unbounded::<SearchEvent>();
+    let (stop_tx, stop_rx) = crossbeam_channel::bounded(1);
+
     // Select backend
-    let backend = select_backend_with_mode(cli.mode.into());
-    let info = backend.info();
+    let cpu = CpuBackend::with_threads(cli.threads).with_search_mode(cli.cpu_search.into());
+    let mut selector = if cli.strict {
+        BackendSelector::strict(mode.into())
+    } else {
+        BackendSelector::new(mode.into())
+    };
+    if !cli.backend_order.is_empty() {
+        selector = selector.with_order(cli.backend_order.iter().map(|&candidate| candidate.into()).collect());
+    }
+    let selection = match selector.with_cpu(cpu).select() {
+        Ok(selection) => selection,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    selection.report(&event_tx);
+    event_rx.try_iter().for_each(|event| print_event(&event));
+    let backend = selection.backend;
 
     println!();
     println!("Starting generation...");
     println!();
 
-    // Set up channels
-    let (progress_tx, progress_rx) = unbounded::<Progress>();
-    let (result_tx, result_rx) = unbounded();
-    let (stop_tx, stop_rx) = crossbeam_channel::bounded(1);
-
     // Handle Ctrl+C
-    let stop_tx_clone = stop_tx.clone();
     ctrlc::set_handler(move || {
         eprintln!("\nStopping...");
-        let _ = stop_tx_clone.send(());
+        let _ = stop_tx.send(());
     })
     .ok();
 
-    // Clone values for threads
-    let prefixes = cli.prefixes.clone();
-    let dst = cli.dst.clone();
     let filter = SearchFilter {
         contains: cli.contains.clone(),
+        regex: cli.regex.clone(),
+        lookalikes: cli.lookalikes,
+        quota,
+        protection,
+        format,
+        manifest: cli.manifest.clone(),
     };
 
-    // Spawn generation thread
-    let gen_handle = std::thread::spawn(move || {
-        backend.generate_with_filter(prefixes, dst, progress_tx, result_tx, stop_rx, filter)
-    });
+    // Start generation in the background
+    let session = match SearchSession::start(backend, cli.prefixes.clone(), cli.dst.clone(), filter, event_tx) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     // Progress display thread
     let start_time = Instant::now();
-    let expected = 2_f64.powi(5 * max_len as i32);
+    let names: Vec<&str> = cli.prefixes.iter().chain(&cli.regex).map(String::as_str).collect();
+    let mut found = vec![0; names.len()];
+    // The rarest pattern has to be found `count` times
+    let expected = match quota {
+        Quota::Count(count) => Some(count as f64 / min_probability),
+        Quota::Unlimited => None,
+    };
     let mut last_log = Instant::now();
-    let mut found_count = 0;
-    let total_prefixes = cli.prefixes.len();
+
+    let mut report = |result: FoundKey| {
+        let index = names.iter().position(|&name| name == result.prefix).unwrap_or(0);
+        found[index] += 1;
+        let spelling = if result.variant != result.prefix {
+            format!("{} (as {})", result.prefix, result.variant)
+        } else {
+            result.prefix.clone()
+        };
+        println!(
+            "FOUND #{} [{}]: {} -> {}",
+            result.sequence,
+            quota_fill(found[index], quota),
+            spelling,
+            result.onion_address
+        );
+        println!("  Saved to: {}", result.key_path.display());
+        if !result.has_seed {
+            println!("  (scalar only: no ed25519 seed exists for this key)");
+        }
+        if let Some(tor) = tor.as_mut() {
+            let added = read_found_key(&result.key_path, format)
+                .and_then(|expanded| tor.add_onion(&expanded, &cli.control.onion_ports, true));
+            match added {
+                Ok(onion) => println!("  Added to Tor: {}", onion),
+                Err(e) => eprintln!("  Could not add to Tor: {}", e),
+            }
+        }
+    };
 
     loop {
-        // Check for results
-        while let Ok(result) = result_rx.try_recv() {
-            found_count += 1;
-            println!(
-                "FOUND [{}/{}]: {} -> {}",
-                found_count, total_prefixes, result.prefix, result.onion_address
-            );
-            println!("  Saved to: {}", result.key_path.display());
+        // Check for results, keeping the latest progress
+        let mut progress = None;
+        while let Ok(event) = event_rx.try_recv() {
+            match event {
+                SearchEvent::KeyFound(result) => report(result),
+                SearchEvent::Progress(update) => progress = Some(update),
+                event => print_event(&event),
+            }
         }
 
         // Check for progress
-        if let Ok(progress) = progress_rx.try_recv() {
+        if let Some(progress) = progress {
             if last_log.elapsed() > Duration::from_secs(10) {
                 let dur = progress.elapsed_secs;
                 let dur_pretty = PrettyDur(
                     chrono::Duration::from_std(Duration::from_secs_f64(dur)).unwrap_or(chrono::Duration::zero()),
                 );
 
-                let progress_pct = progress.keys_checked as f64 / expected;
-                let expected_dur = if progress_pct > 0.0 {
-                    dur / progress_pct
-                } else {
-                    0.0
-                };
-                let expected_dur_pretty = PrettyDur(
-                    chrono::Duration::from_std(Duration::from_secs_f64(expected_dur))
-                        .unwrap_or(chrono::Duration::zero()),
-                );
-
                 println!();
-                println!(
-                    "Progress: {:.2e} / {:.2e} keys ({:.4}%)",
-                    progress.keys_checked as f64,
-                    expected,
-                    progress_pct * 100.0
-                );
+                if let Some(expected) = expected {
+                    let progress_pct = progress.keys_checked as f64 / expected;
+                    let expected_dur = if progress_pct > 0.0 {
+                        dur / progress_pct
+                    } else {
+                        0.0
+                    };
+                    let expected_dur_pretty = PrettyDur(
+                        chrono::Duration::from_std(Duration::from_secs_f64(expected_dur))
+                            .unwrap_or(chrono::Duration::zero()),
+                    );
+
+                    println!(
+                        "Progress: {:.2e} / {:.2e} keys ({:.4}%)",
+                        progress.keys_checked as f64,
+                        expected,
+                        progress_pct * 100.0
+                    );
+                    println!("Elapsed: {} / Est. total: {}", dur_pretty, expected_dur_pretty);
+                } else {
+                    println!("Checked: {:.2e} keys", progress.keys_checked as f64);
+                    println!("Elapsed: {}", dur_pretty);
+                }
                 println!(
                     "Speed: {:.2} M keys/sec",
                     progress.keys_per_sec / 1_000_000.0
                 );
-                println!("Elapsed: {} / Est. total: {}", dur_pretty, expected_dur_pretty);
-                println!("Found: {}/{} prefixes", found_count, total_prefixes);
+                let fill: Vec<String> = names
+                    .iter()
+                    .zip(&progress.found)
+                    .map(|(name, &hits)| format!("{} {}", name, quota_fill(hits, quota)))
+                    .collect();
+                println!("Found: {}", fill.join(", "));
                 println!();
 
                 last_log = Instant::now();
             }
         }
 
-        // Check if generation is done
-        if found_count >= total_prefixes {
-            break;
-        }
-
         // Small sleep to prevent busy loop
         std::thread::sleep(Duration::from_millis(50));
 
-        // Check if generator thread has finished
-        if gen_handle.is_finished() {
+        if stop_rx.try_recv().is_ok() {
+            session.stop();
+        }
+        // Check if the search has finished
+        if session.state() == SessionState::Finished {
             break;
         }
     }
 
-    // Wait for generator
-    match gen_handle.join() {
-        Ok(Ok(())) => {
+    // Wait for generator, then print what it found last
+    let outcome = session.wait();
+    for event in event_rx.try_iter() {
+        match event {
+            SearchEvent::KeyFound(result) => report(result),
+            event => print_event(&event),
+        }
+    }
+
+    let total: usize = found.iter().sum();
+    let elapsed = PrettyDur(chrono::Duration::from_std(start_time.elapsed()).unwrap_or(chrono::Duration::zero()));
+    match outcome {
+        Ok(()) => {
             println!();
             println!("=== Complete! ===");
-            println!("Found all {} prefixes in {}", total_prefixes,
-                PrettyDur(chrono::Duration::from_std(start_time.elapsed()).unwrap_or(chrono::Duration::zero())));
+            println!("Found {} keys for {} patterns in {}", total, names.len(), elapsed);
         }
-        Ok(Err(e)) => {
+        Err(GeneratorError::Stopped) if quota == Quota::Unlimited => {
+            println!();
+            println!("=== Stopped ===");
+            println!("Found {} keys for {} patterns in {}", total, names.len(), elapsed);
+        }
+        Err(e @ GeneratorError::Stopped) => {
             eprintln!();
             eprintln!("Generation stopped: {}", e);
         }
-        Err(_) => {
+        // Scripts only see the exit status, so a key that was not written
+        // must not look like a stopped search
+        Err(e) => {
             eprintln!();
-            eprintln!("Generation thread panicked");
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Report backend selection and worker errors
+fn print_event(event: &SearchEvent) {
+    match event {
+        SearchEvent::BackendChosen(info) => {
+            eprintln!("Backend: {}", info.name);
+            eprintln!("Estimated speed: ~{} keys/sec", format_speed(info.estimated_speed));
+        }
+        SearchEvent::Fallback { backend, reason } => eprintln!("{} not available ({})", backend, reason),
+        SearchEvent::WorkerError { worker, error } => eprintln!("{} error: {}", worker, error),
+        SearchEvent::Progress(_) | SearchEvent::KeyFound(_) | SearchEvent::Finished(_) => {}
+    }
+}
+
+/// Hits of one pattern, against its quota if it has one
+fn quota_fill(hits: usize, quota: Quota) -> String {
+    match quota {
+        Quota::Count(count) => format!("{}/{}", hits, count),
+        Quota::Unlimited => hits.to_string(),
+    }
+}
+
+/// Rank addresses by dictionary words until interrupted
+fn run_wordlist(cli: &Cli, path: &Path, format: KeyFormat, protection: KeyProtection) {
+    let wordlist = match Wordlist::load(path) {
+        Ok(wordlist) => Arc::new(wordlist),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    println!("=== Tor V3 Vanity Generator ===");
+    println!("Wordlist: {} ({} usable words)", path.display(), wordlist.len());
+    println!("Keeping the best {} addresses", cli.top);
+    print_output(&cli.dst, format);
+    print_protection(&protection);
+    println!("CPU threads: {}", cli.threads);
+    println!("CPU search: {}", CpuSearchMode::from(cli.cpu_search).as_str());
+    if cli.mode != Mode::Auto && cli.mode != Mode::Cpu {
+        println!("Wordlist search scores every candidate, so it runs on the CPU");
+    }
+    println!();
+    println!("Searching until stopped (Ctrl+C)...");
+    println!();
+
+    let backend = CpuBackend::with_threads(cli.threads).with_search_mode(cli.cpu_search.into());
+    let leaderboard = Arc::new(Leaderboard::new(cli.top));
+    // No patterns; the state only carries how keys are saved and pauses or
+    // stops the workers
+    let filter = SearchFilter {
+        format,
+        protection,
+        ..SearchFilter::default()
+    };
+    let state = match SearchState::with_filter(&[], &filter) {
+        Ok(state) => Arc::new(state.with_backend(backend.info().name)),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Set up channels
+    let (event_tx, event_rx) = unbounded::<SearchEvent>();
+    let (stop_tx, stop_rx) = crossbeam_channel::bounded(1);
+
+    // Handle Ctrl+C
+    ctrlc::set_handler(move || {
+        eprintln!("\nStopping...");
+        let _ = stop_tx.send(());
+    })
+    .ok();
+
+    let gen_wordlist = wordlist.clone();
+    let gen_leaderboard = leaderboard.clone();
+    let gen_state = state.clone();
+    let dst = cli.dst.clone();
+    let gen_handle = std::thread::spawn(move || {
+        backend.generate_wordlist(&gen_wordlist, &gen_leaderboard, &gen_state, dst, event_tx, stop_rx)
+    });
+
+    let mut last_log = Instant::now();
+    loop {
+        let mut progress = None;
+        while let Ok(event) = event_rx.try_recv() {
+            match event {
+                SearchEvent::KeyFound(result) => {
+                    println!("NEW TOP {}: {} ({})", cli.top, result.onion_address, result.prefix);
+                }
+                SearchEvent::Progress(update) => progress = Some(update),
+                event => print_event(&event),
+            }
+        }
+
+        if let Some(progress) = progress {
+            if last_log.elapsed() > Duration::from_secs(10) {
+                println!();
+                println!(
+                    "Checked: {:.2e} keys at {:.2} M keys/sec",
+                    progress.keys_checked as f64,
+                    progress.keys_per_sec / 1_000_000.0
+                );
+                println!("Score to beat: {}", leaderboard.threshold());
+                println!();
+                last_log = Instant::now();
+            }
+        }
+
+        std::thread::sleep(Duration::from_millis(50));
+
+        if gen_handle.is_finished() {
+            break;
+        }
+    }
+
+    let outcome = gen_handle.join().expect("wordlist search panicked");
+    println!();
+    println!("=== Best addresses (also in {}) ===", RANKING_FILE);
+    print!("{}", format_ranking(&leaderboard.entries()));
+
+    // The search only ends by being stopped, or by failing
+    match outcome {
+        Ok(()) | Err(GeneratorError::Stopped) => {}
+        Err(e) => {
+            eprintln!();
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Replace placeholder nonce halves in every hidden service directory found,
+/// or only list the affected ones with `dry_run`
+fn run_repair(paths: &[PathBuf], dry_run: bool) {
+    let mut failed = false;
+    let mut affected = 0;
+    for path in paths {
+        let hs_dirs = match find_hs_dirs(path) {
+            Ok(hs_dirs) => hs_dirs,
+            Err(e) => {
+                eprintln!("Error: {}: {}", path.display(), e);
+                failed = true;
+                continue;
+            }
+        };
+
+        for hs_dir in hs_dirs {
+            let outcome = if dry_run {
+                needs_repair(&hs_dir).map(|affected| if affected { Repair::Repaired } else { Repair::Unaffected })
+            } else {
+                repair_hs_dir(&hs_dir)
+            };
+            match outcome {
+                Ok(Repair::Unaffected) => {}
+                Ok(Repair::Repaired) => {
+                    affected += 1;
+                    let action = if dry_run { "Affected" } else { "Repaired" };
+                    println!("{}: {}", action, hs_dir.display());
+                }
+                Err(e) => {
+                    eprintln!("Error: {}: {}", hs_dir.display(), e);
+                    failed = true;
+                }
+            }
+        }
+    }
+
+    if dry_run {
+        println!("{} keys need repair", affected);
+    } else {
+        println!("Repaired {} keys", affected);
+    }
+    if affected > 0 {
+        // Anyone can recompute the nonce from the address, so a single
+        // signature gives away the scalar, and Tor signs its descriptors
+        // with blinded keys whose nonces derive from the same half
+        eprintln!();
+        eprintln!("Warning: a service that ever ran with one of these keys has leaked its secret key.");
+        eprintln!("Repairing does not help it: retire the key and move the service to a new address.");
+        if dry_run {
+            eprintln!("Only keys that were never deployed can be repaired, with --never-deployed.");
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Passphrase from the first line of `file`, or asked for on the terminal
+/// (twice when `confirm` is set, as a typo would lock the keys away)
+fn read_passphrase(file: Option<&Path>, confirm: bool) -> Passphrase {
+    let passphrase = match file {
+        Some(file) => match std::fs::read_to_string(file) {
+            Ok(contents) => contents.lines().next().unwrap_or_default().to_string(),
+            Err(e) => {
+                eprintln!("Error: {}: {}", file.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            let ask = |prompt: &str| {
+                rpassword::prompt_password(prompt).unwrap_or_else(|e| {
+                    eprintln!("Error: could not read passphrase: {}", e);
+                    std::process::exit(1);
+                })
+            };
+            let passphrase = ask("Passphrase: ");
+            if confirm && ask("Repeat passphrase: ") != passphrase {
+                eprintln!("Error: passphrases do not match");
+                std::process::exit(1);
+            }
+            passphrase
+        }
+    };
+
+    if passphrase.is_empty() {
+        eprintln!("Error: empty passphrase");
+        std::process::exit(1);
+    }
+    Passphrase::new(passphrase)
+}
+
+/// Where keys are saved
+fn print_output(dst: &Path, format: KeyFormat) {
+    match format {
+        KeyFormat::HsDir => println!("Output: {}", dst.display()),
+        KeyFormat::Arti => println!("Output: {} (Arti keystore)", dst.display()),
+    }
+}
+
+/// How secret keys are saved, if not in plaintext
+fn print_protection(protection: &KeyProtection) {
+    match protection {
+        KeyProtection::Plaintext => {}
+        KeyProtection::Passphrase(_) => println!("Secret keys: encrypted under passphrase"),
+        KeyProtection::Recipient(recipient) => {
+            println!("Secret keys: sealed to {}", recipient.to_hex())
+        }
+    }
+}
+
+/// Write a new identity file and print its recipient key
+fn run_keygen(path: &Path) {
+    let identity = Identity::generate();
+    match write_new(path, identity.to_text().as_bytes()) {
+        Ok(()) => {
+            println!("Identity saved to {}", path.display());
+            println!("Recipient: {}", identity.recipient().to_hex());
+        }
+        Err(e) => {
+            eprintln!("Error: {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Load an identity file or exit
+fn read_identity(path: &Path) -> Identity {
+    let identity = std::fs::read_to_string(path)
+        .map_err(GeneratorError::from)
+        .and_then(|text| Identity::from_text(&text));
+    identity.unwrap_or_else(|e| {
+        eprintln!("Error: {}: {}", path.display(), e);
+        std::process::exit(1);
+    })
+}
+
+/// Write the plain secret key of every hidden service directory holding
+/// `key_file`, using `restore`
+fn restore_keys<F>(paths: &[PathBuf], key_file: &str, restore: F)
+where
+    F: Fn(&Path) -> Result<PathBuf, GeneratorError>,
+{
+    let mut failed = false;
+    let mut restored = 0;
+    for path in paths {
+        let hs_dirs = match find_hs_dirs(path) {
+            Ok(hs_dirs) => hs_dirs,
+            Err(e) => {
+                eprintln!("Error: {}: {}", path.display(), e);
+                failed = true;
+                continue;
+            }
+        };
+
+        for hs_dir in hs_dirs.iter().filter(|dir| dir.join(key_file).is_file()) {
+            match restore(hs_dir) {
+                Ok(secret_key) => {
+                    restored += 1;
+                    println!("Restored: {}", secret_key.display());
+                }
+                Err(e) => {
+                    eprintln!("Error: {}: {}", hs_dir.display(), e);
+                    failed = true;
+                }
+            }
+        }
+    }
+
+    println!("Restored {} keys", restored);
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Copy the plain keys of every hidden service directory found into an Arti
+/// keystore
+fn run_export_arti(keystore: &Path, nickname: Option<&str>, paths: &[PathBuf]) {
+    let mut hs_dirs = Vec::new();
+    for path in paths {
+        match find_hs_dirs(path) {
+            Ok(found) => hs_dirs.extend(found),
+            Err(e) => {
+                eprintln!("Error: {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if nickname.is_some() && hs_dirs.len() > 1 {
+        eprintln!("Error: --nickname needs exactly one hidden service directory, found {}", hs_dirs.len());
+        std::process::exit(1);
+    }
+
+    let mut failed = false;
+    let mut exported = 0;
+    for hs_dir in &hs_dirs {
+        match export_hs_dir(hs_dir, keystore, nickname) {
+            Ok(service) => {
+                exported += 1;
+                println!("Exported: {} -> {}", hs_dir.display(), service.display());
+            }
+            Err(e) => {
+                eprintln!("Error: {}: {}", hs_dir.display(), e);
+                failed = true;
+            }
+        }
+    }
+
+    println!("Exported {} keys", exported);
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Connect and authenticate to the control port of `args`, if it has one
+fn connect_control(args: &ControlArgs) -> Option<ControlPort> {
+    let addr = args.control_port.as_ref()?;
+    if args.onion_ports.is_empty() {
+        eprintln!("Error: --onion-port is required with --control-port");
+        std::process::exit(1);
+    }
+
+    let auth = match &args.control_password_file {
+        Some(file) => match std::fs::read_to_string(file) {
+            Ok(contents) => ControlAuth::Password(Zeroizing::new(contents.lines().next().unwrap_or_default().to_string())),
+            Err(e) => {
+                eprintln!("Error: {}: {}", file.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => ControlAuth::Cookie(args.control_cookie.clone()),
+    };
+    let connected = ControlPort::connect(addr.as_str()).and_then(|mut tor| {
+        tor.authenticate(&auth)?;
+        Ok(tor)
+    });
+    match connected {
+        Ok(tor) => Some(tor),
+        Err(e) => {
+            eprintln!("Error: {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Expanded secret key of a key saved by a search in `format`
+fn read_found_key(key_path: &Path, format: KeyFormat) -> Result<Zeroizing<[u8; 64]>, GeneratorError> {
+    match format {
+        KeyFormat::HsDir => Ok(Zeroizing::new(read_hs_dir(key_path)?.expanded_secret_key)),
+        KeyFormat::Arti => Ok(read_arti_key(key_path)?.expanded_secret_key),
+    }
+}
+
+/// Hidden service directories with a plain secret key below `paths`
+fn find_plain_hs_dirs(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut hs_dirs = Vec::new();
+    for path in paths {
+        match find_hs_dirs(path) {
+            Ok(found) => hs_dirs.extend(found.into_iter().filter(|dir| dir.join(SECRET_KEY_FILE).is_file())),
+            Err(e) => {
+                eprintln!("Error: {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    hs_dirs
+}
+
+/// Print the ADD_ONION key of every plain hidden service directory found
+fn run_export_add_onion(paths: &[PathBuf]) {
+    let mut failed = false;
+    for hs_dir in find_plain_hs_dirs(paths) {
+        match read_hs_dir(&hs_dir) {
+            Ok(keys) => println!("{} {}", keys.hostname, add_onion_key(&keys.expanded_secret_key).as_str()),
+            Err(e) => {
+                eprintln!("Error: {}: {}", hs_dir.display(), e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Add every plain hidden service directory found to Tor
+fn run_add_onion(tor: &mut ControlPort, ports: &[PortMapping], paths: &[PathBuf]) {
+    let mut failed = false;
+    let mut added = 0;
+    for hs_dir in find_plain_hs_dirs(paths) {
+        let outcome = read_hs_dir(&hs_dir)
+            .map_err(GeneratorError::from)
+            .and_then(|keys| tor.add_onion(&keys.expanded_secret_key, ports, true));
+        match outcome {
+            Ok(onion) => {
+                added += 1;
+                println!("Added: {}", onion);
+            }
+            Err(e) => {
+                eprintln!("Error: {}: {}", hs_dir.display(), e);
+                failed = true;
+            }
+        }
+    }
+
+    println!("Added {} services", added);
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Inspect every hidden service directory found, or the paths themselves
+fn run_inspect(paths: &[PathBuf], json: bool) {
+    let mut consistent = true;
+    for path in paths {
+        let hs_dirs = match find_hs_dirs(path) {
+            Ok(hs_dirs) if !hs_dirs.is_empty() => hs_dirs,
+            _ => vec![path.clone()],
+        };
+        for hs_dir in hs_dirs {
+            let inspection = inspect_hs_dir(&hs_dir);
+            consistent &= inspection.consistent;
+            if json {
+                match serde_json::to_string(&inspection) {
+                    Ok(line) => println!("{}", line),
+                    Err(e) => eprintln!("Error: {}", e),
+                }
+            } else {
+                print_inspection(&inspection);
+            }
+        }
+    }
+    if !json {
+        // Not a finding about any one directory: no Tor directory keeps the seed
+        println!("Seeds are never stored: Tor directories only keep the expanded key, which is all signing needs");
+    }
+    if !consistent {
+        std::process::exit(1);
+    }
+}
+
+/// Human-readable report of one directory
+fn print_inspection(inspection: &Inspection) {
+    let or_none = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
+    println!("{}", inspection.path.display());
+    println!("  Address:     {}", or_none(&inspection.onion_address));
+    println!("  Secret key:  {}", or_none(&inspection.secret_key_header));
+    println!("  Public key:  {}", or_none(&inspection.public_key_header));
+    println!("  Hostname:    {}", or_none(&inspection.hostname));
+    if inspection.consistent {
+        println!("  Status:      consistent");
+    } else {
+        println!("  Status:      inconsistent");
+        for problem in &inspection.problems {
+            println!("    - {}", problem);
         }
     }
 }
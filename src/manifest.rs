@@ -0,0 +1,167 @@
+//! Machine-readable log of found keys
+//!
+//! A search with a manifest appends one JSON object per line (NDJSON) for
+//! every key it saves, once the key is on disk and before it is reported.
+//! Each record is written with a single append, so a reader following the
+//! file never sees half a record, and several searches can share one
+//! manifest. Records are only ever added; the file is never rewritten.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::backend::{FoundKey, GeneratorError};
+use crate::private_file::append;
+
+/// One found key, as written to the manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestRecord {
+    /// Pattern the key was found for
+    pub pattern: String,
+    /// Spelling of the pattern found in the address; differs from it only
+    /// for look-alike matches
+    pub variant: String,
+    pub onion_address: String,
+    /// Public key in hex
+    pub public_key: String,
+    /// Where the key was saved
+    pub path: PathBuf,
+    /// Name of the backend that found the key
+    pub backend: String,
+    /// Keys the search had checked when the key was found, as of the last
+    /// progress update of the backend
+    pub keys_checked: u64,
+    /// Position of the key among all keys the search found, starting at 1
+    pub sequence: u64,
+    pub has_seed: bool,
+    /// When the search started, RFC 3339 in UTC
+    pub search_started_at: String,
+    /// When the key was found, RFC 3339 in UTC
+    pub found_at: String,
+}
+
+impl ManifestRecord {
+    /// Record of `found`, found just now by `backend` in a search that
+    /// started at `search_started_at`
+    pub fn new(found: &FoundKey, backend: &str, search_started_at: DateTime<Utc>) -> Self {
+        Self {
+            pattern: found.prefix.clone(),
+            variant: found.variant.clone(),
+            onion_address: found.onion_address.clone(),
+            public_key: hex::encode(found.public_key),
+            path: found.key_path.clone(),
+            backend: backend.to_string(),
+            keys_checked: found.keys_checked,
+            sequence: found.sequence,
+            has_seed: found.has_seed,
+            search_started_at: timestamp(search_started_at),
+            found_at: timestamp(Utc::now()),
+        }
+    }
+}
+
+/// Manifest file of one search
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    path: PathBuf,
+    started_at: DateTime<Utc>,
+}
+
+impl Manifest {
+    /// Manifest at `path` for a search starting now; the file is created
+    /// with the first record
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            started_at: Utc::now(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append the record of `found`, found by `backend`
+    pub fn append(&self, found: &FoundKey, backend: &str) -> Result<(), GeneratorError> {
+        let record = ManifestRecord::new(found, backend, self.started_at);
+        let mut line = serde_json::to_string(&record).map_err(|e| std::io::Error::other(e.to_string()))?;
+        line.push('\n');
+        append(&self.path, line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// All records of the manifest at `path`
+pub fn read_manifest(path: &Path) -> Result<Vec<ManifestRecord>, GeneratorError> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str(line).map_err(|e| {
+                GeneratorError::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), e),
+                ))
+            })
+        })
+        .collect()
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onion::pubkey_to_onion;
+
+    fn found(sequence: u64) -> FoundKey {
+        let public_key = [sequence as u8; 32];
+        FoundKey {
+            prefix: "ab".to_string(),
+            variant: "4b".to_string(),
+            onion_address: pubkey_to_onion(&public_key),
+            public_key,
+            key_path: PathBuf::from(format!("out/{}", sequence)),
+            sequence,
+            keys_checked: 1000 * sequence,
+            has_seed: true,
+        }
+    }
+
+    #[test]
+    fn test_records_are_appended() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.ndjson");
+        let manifest = Manifest::new(&path);
+        manifest.append(&found(1), "CPU (4 threads)").unwrap();
+        manifest.append(&found(2), "CPU (4 threads)").unwrap();
+
+        let records = read_manifest(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+        let record = &records[1];
+        assert_eq!(record.pattern, "ab");
+        assert_eq!(record.variant, "4b");
+        assert_eq!(record.onion_address, pubkey_to_onion(&[2; 32]));
+        assert_eq!(record.public_key, "02".repeat(32));
+        assert_eq!(record.path, PathBuf::from("out/2"));
+        assert_eq!(record.backend, "CPU (4 threads)");
+        assert_eq!(record.keys_checked, 2000);
+        assert_eq!(record.sequence, 2);
+        assert_eq!(record.search_started_at, records[0].search_started_at);
+        let time = |text: &str| DateTime::parse_from_rfc3339(text).unwrap();
+        assert!(time(&record.found_at) >= time(&record.search_started_at));
+    }
+
+    #[test]
+    fn test_damaged_manifest_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.ndjson");
+        std::fs::write(&path, "{\"pattern\":\n").unwrap();
+        assert!(matches!(read_manifest(&path), Err(GeneratorError::Io(_))));
+    }
+}
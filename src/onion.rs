@@ -1,6 +1,39 @@
-//! Tor v3 onion address generation utilities
+//! Tor v3 onion address generation and parsing utilities
 
+use std::fmt;
+use std::str::FromStr;
+
+use curve25519_dalek::edwards::CompressedEdwardsY;
 use sha3::{Digest, Sha3_256};
+use thiserror::Error;
+
+/// Version byte of v3 addresses
+const VERSION: u8 = 3;
+
+/// Base32 characters of a v3 address, without `.onion`
+const ADDRESS_LEN: usize = 56;
+
+/// Why a string is not a valid v3 onion address
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum OnionAddressError {
+    #[error("not an onion address: '{0}' does not end in .onion")]
+    NotOnion(String),
+
+    #[error("expected {ADDRESS_LEN} base32 characters before .onion, found {0}")]
+    InvalidLength(usize),
+
+    #[error("invalid base32: only a-z and 2-7 can appear in an address")]
+    InvalidBase32,
+
+    #[error("unsupported onion address version {0}, expected 3")]
+    UnsupportedVersion(u8),
+
+    #[error("checksum mismatch: the address is mistyped or damaged")]
+    InvalidChecksum,
+
+    #[error("the address does not encode a valid ed25519 public key")]
+    InvalidPublicKey,
+}
 
 /// Convert an Ed25519 public key to a Tor v3 onion address
 ///
@@ -8,15 +41,10 @@ use sha3::{Digest, Sha3_256};
 /// where checksum = SHA3-256(".onion checksum" || pubkey || version)[0..2]
 /// and version = 0x03
 pub fn pubkey_to_onion(pubkey: &[u8; 32]) -> String {
-    let mut hasher = Sha3_256::new();
-    hasher.update(b".onion checksum");
-    hasher.update(pubkey);
-    hasher.update(&[3u8]);
-
     let mut onion = [0u8; 35];
     onion[..32].copy_from_slice(pubkey);
-    onion[32..34].copy_from_slice(&hasher.finalize()[..2]);
-    onion[34] = 3;
+    onion[32..34].copy_from_slice(&onion_checksum(pubkey));
+    onion[34] = VERSION;
 
     format!(
         "{}.onion",
@@ -24,6 +52,94 @@ pub fn pubkey_to_onion(pubkey: &[u8; 32]) -> String {
     )
 }
 
+/// Public key of a v3 onion address
+///
+/// Accepts anything naming an onion service: `<address>.onion`, in any case,
+/// with subdomains (`www.<address>.onion`), or a URL such as
+/// `http://<address>.onion:8080/path`. The version byte, the checksum and the
+/// public key itself are all checked.
+pub fn onion_to_pubkey(input: &str) -> Result<[u8; 32], OnionAddressError> {
+    let host = host_of(input);
+    let not_onion = || OnionAddressError::NotOnion(input.trim().to_string());
+    let labels = host.strip_suffix(".onion").ok_or_else(not_onion)?;
+    // Subdomains are for the service to interpret; the key is the last label
+    let address = labels.rsplit('.').next().filter(|label| !label.is_empty()).ok_or_else(not_onion)?;
+    if address.len() != ADDRESS_LEN {
+        return Err(OnionAddressError::InvalidLength(address.len()));
+    }
+
+    let bytes = base32::decode(base32::Alphabet::Rfc4648Lower { padding: false }, address)
+        .ok_or(OnionAddressError::InvalidBase32)?;
+    let mut pubkey = [0u8; 32];
+    pubkey.copy_from_slice(&bytes[..32]);
+    if bytes[34] != VERSION {
+        return Err(OnionAddressError::UnsupportedVersion(bytes[34]));
+    }
+    if bytes[32..34] != onion_checksum(&pubkey) {
+        return Err(OnionAddressError::InvalidChecksum);
+    }
+    if CompressedEdwardsY(pubkey).decompress().is_none() {
+        return Err(OnionAddressError::InvalidPublicKey);
+    }
+    Ok(pubkey)
+}
+
+/// Lowercase host name of an address or URL: without scheme, user, port,
+/// path and trailing dot
+fn host_of(input: &str) -> String {
+    let input = input.trim();
+    let rest = input.split_once("://").map_or(input, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => host,
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// A valid v3 onion address
+///
+/// Parses like [`onion_to_pubkey`] and displays in canonical form, so
+/// addresses compare equal however they were written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OnionAddress([u8; 32]);
+
+impl OnionAddress {
+    pub fn from_pubkey(pubkey: [u8; 32]) -> Self {
+        Self(pubkey)
+    }
+
+    pub fn pubkey(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl FromStr for OnionAddress {
+    type Err = OnionAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        onion_to_pubkey(s).map(Self)
+    }
+}
+
+impl fmt::Display for OnionAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&pubkey_to_onion(&self.0))
+    }
+}
+
+/// Two-byte address checksum: SHA3-256(".onion checksum" || pubkey || version)[0..2]
+pub(crate) fn onion_checksum(pubkey: &[u8; 32]) -> [u8; 2] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(pubkey);
+    hasher.update([VERSION]);
+
+    let hash = hasher.finalize();
+    [hash[0], hash[1]]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -33,6 +149,61 @@ mod tests {
         let pubkey = [0u8; 32];
         let onion = pubkey_to_onion(&pubkey);
         assert!(onion.ends_with(".onion"));
-        assert_eq!(onion.len(), 62 + 6); // 56 base32 chars + ".onion"
+        assert_eq!(onion.len(), 56 + 6); // 56 base32 chars + ".onion"
+    }
+
+    fn example() -> ([u8; 32], String) {
+        let pubkey = ed25519_dalek::SigningKey::from_bytes(&[4; 32]).verifying_key().to_bytes();
+        (pubkey, pubkey_to_onion(&pubkey))
+    }
+
+    #[test]
+    fn test_parse_forms() {
+        let (pubkey, onion) = example();
+        let upper = onion.to_uppercase();
+        for input in [
+            onion.clone(),
+            upper.clone(),
+            format!("  {}\n", onion),
+            format!("{}.", onion),
+            format!("www.blog.{}", onion),
+            format!("http://{}", onion),
+            format!("https://user@{}:8443/path/to?page=1#top", upper),
+            format!("{}/index.html", onion),
+        ] {
+            assert_eq!(onion_to_pubkey(&input), Ok(pubkey), "{}", input);
+        }
+        let address: OnionAddress = upper.parse().unwrap();
+        assert_eq!(address.to_string(), onion);
+        assert_eq!(address.pubkey(), &pubkey);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let (_, onion) = example();
+        let stem = onion.trim_end_matches(".onion");
+        let flip = |index: usize, to: char| {
+            let mut chars: Vec<char> = stem.chars().collect();
+            chars[index] = if chars[index] == to { 'a' } else { to };
+            format!("{}.onion", chars.into_iter().collect::<String>())
+        };
+
+        let err = |input: &str| onion_to_pubkey(input).unwrap_err();
+        assert!(matches!(err(stem), OnionAddressError::NotOnion(_)));
+        assert!(matches!(err("http://example.com/x.onion"), OnionAddressError::NotOnion(_)));
+        assert_eq!(err(".onion"), OnionAddressError::NotOnion(".onion".to_string()));
+        // v2 addresses are 16 characters
+        assert_eq!(err("expyuzz4wqqyqhjn.onion"), OnionAddressError::InvalidLength(16));
+        assert_eq!(err(&format!("{}1.onion", &stem[..55])), OnionAddressError::InvalidBase32);
+        assert_eq!(err(&flip(0, 'b')), OnionAddressError::InvalidChecksum);
+        // The last character holds the low bits of the version byte
+        assert_eq!(err(&flip(55, 'e')), OnionAddressError::UnsupportedVersion(4));
+
+        // A correct checksum over bytes that are no curve point
+        let not_a_point = (0..=255u8)
+            .map(|byte| [byte; 32])
+            .find(|bytes| CompressedEdwardsY(*bytes).decompress().is_none())
+            .unwrap();
+        assert_eq!(err(&pubkey_to_onion(&not_a_point)), OnionAddressError::InvalidPublicKey);
     }
 }
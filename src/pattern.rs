@@ -0,0 +1,439 @@
+//! Byte-level matching of onion address patterns against raw public keys
+//!
+//! An onion address is `base32(pubkey || checksum || version)`, so every
+//! base32 character covers five fixed bits of those 35 bytes. A pattern can
+//! therefore be compiled once into an expected value and a bit mask and
+//! checked directly against a candidate public key, without building the
+//! address string. Only candidates that match ever need to be encoded.
+//!
+//! Patterns are anchored at a fixed position within the first 52 characters:
+//!
+//! - `shop` or `shop*` matches at the start of the address
+//! - `*shop` matches at the end of the 52 selectable characters
+//! - `?` matches any single character, so `??cafe*` matches `cafe` at offset 2
+//!
+//! Optionally, letters also match their digit look-alikes (`tor` matches
+//! `7or`), see [`LookalikePattern`].
+
+use crate::backend::GeneratorError;
+use crate::onion::onion_checksum;
+
+mod dfa;
+mod lookalike;
+
+pub use dfa::RegexPattern;
+pub use lookalike::{lookalike, LookalikePattern, LOOKALIKES, MAX_VARIANTS};
+
+/// RFC 4648 base32 alphabet used by onion addresses
+const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Length of `pubkey || checksum || version`
+const ADDRESS_BYTES: usize = 35;
+
+/// Number of leading address characters a pattern can cover: characters 53-56
+/// are derived from the checksum and version only (the 52nd still carries one
+/// public key bit next to four checksum bits)
+pub const MAX_PATTERN_LEN: usize = 52;
+
+/// 5-bit value of a base32 character
+pub(crate) fn symbol_value(c: u8) -> Option<u8> {
+    ALPHABET.iter().position(|&a| a == c).map(|v| v as u8)
+}
+
+/// 5-bit symbol `i < 51` of the address, read from the public key bits
+#[inline]
+pub(crate) fn symbol_at(pubkey: &[u8; 32], i: usize) -> u8 {
+    let bit = 5 * i;
+    let byte = bit / 8;
+    let hi = pubkey[byte] as u16;
+    let lo = pubkey.get(byte + 1).copied().unwrap_or(0) as u16;
+    (((hi << 8) | lo) >> (11 - bit % 8)) as u8 & 0x1f
+}
+
+/// The 52nd symbol, made of the last public key bit and four checksum bits
+#[inline]
+pub(crate) fn last_symbol(pubkey: &[u8; 32]) -> u8 {
+    ((pubkey[31] & 1) << 4) | (onion_checksum(pubkey)[0] >> 4)
+}
+
+/// A compiled search pattern
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// Characters at a fixed position, see [`BytePattern`]
+    Bytes(BytePattern),
+    /// Characters that also accept their look-alikes, see [`LookalikePattern`]
+    Lookalike(LookalikePattern),
+    /// Regular expression, see [`RegexPattern`]
+    Regex(RegexPattern),
+}
+
+impl Pattern {
+    /// Compile a pattern whose letters also match their digit look-alikes;
+    /// one without any such letter stays a plain [`BytePattern`]
+    pub fn with_lookalikes(pattern: &str) -> Result<Self, GeneratorError> {
+        if pattern.bytes().any(|c| lookalike(c).is_some()) {
+            LookalikePattern::new(pattern).map(Pattern::Lookalike)
+        } else {
+            BytePattern::new(pattern).map(Pattern::Bytes)
+        }
+    }
+
+    /// The text this pattern was compiled from
+    pub fn pattern(&self) -> &str {
+        match self {
+            Pattern::Bytes(p) => p.pattern(),
+            Pattern::Lookalike(p) => p.pattern(),
+            Pattern::Regex(p) => p.pattern(),
+        }
+    }
+
+    /// Does the onion address of `pubkey` match this pattern?
+    #[inline]
+    pub fn matches(&self, pubkey: &[u8; 32]) -> bool {
+        match self {
+            Pattern::Bytes(p) => p.matches(pubkey),
+            Pattern::Lookalike(p) => p.matches(pubkey),
+            Pattern::Regex(p) => p.matches(pubkey),
+        }
+    }
+
+    /// Chance that a random key matches
+    pub fn match_probability(&self) -> f64 {
+        match self {
+            Pattern::Bytes(p) => p.match_probability(),
+            Pattern::Lookalike(p) => p.match_probability(),
+            Pattern::Regex(p) => p.match_probability(),
+        }
+    }
+
+    /// The spelling of this pattern found in the address of a matching
+    /// `pubkey`; only look-alike patterns can differ from [`Pattern::pattern`]
+    pub fn matched_variant(&self, pubkey: &[u8; 32]) -> String {
+        match self {
+            Pattern::Lookalike(p) => p.matched_variant(pubkey),
+            other => other.pattern().to_string(),
+        }
+    }
+
+    /// The byte-level form, if this is a plain pattern
+    pub fn as_bytes(&self) -> Option<&BytePattern> {
+        match self {
+            Pattern::Bytes(p) => Some(p),
+            Pattern::Lookalike(_) | Pattern::Regex(_) => None,
+        }
+    }
+
+    /// Byte-level patterns that together match exactly what this pattern
+    /// matches, which is all GPU kernels can evaluate
+    pub fn byte_variants(&self) -> Result<Vec<BytePattern>, GeneratorError> {
+        match self {
+            Pattern::Bytes(p) => Ok(vec![p.clone()]),
+            Pattern::Lookalike(p) => p.variants(),
+            Pattern::Regex(p) => Err(GeneratorError::InvalidPattern {
+                pattern: p.pattern().to_string(),
+                reason: "regular expressions can only be evaluated by the CPU backend".to_string(),
+            }),
+        }
+    }
+}
+
+/// Onion address pattern compiled to a bit mask over the address bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BytePattern {
+    pattern: String,
+    /// Character position the pattern is anchored at
+    offset: usize,
+    /// Number of characters that are not wildcards
+    literal_len: usize,
+    /// Expected bits of `pubkey || checksum || version`, zero outside `mask`
+    value: [u8; ADDRESS_BYTES],
+    mask: [u8; ADDRESS_BYTES],
+    /// Byte range holding every mask bit
+    start: usize,
+    end: usize,
+}
+
+impl BytePattern {
+    /// Compile a lowercase base32 pattern such as `shop`, `*shop` or `??cafe*`
+    pub fn new(pattern: &str) -> Result<Self, GeneratorError> {
+        let invalid = |reason: String| GeneratorError::InvalidPattern {
+            pattern: pattern.to_string(),
+            reason,
+        };
+
+        // A leading '*' anchors at the end, otherwise the start is anchored and
+        // a trailing '*' is merely explicit
+        let (body, anchored_end) = match pattern.strip_prefix('*') {
+            Some(body) if body.ends_with('*') => {
+                return Err(invalid(
+                    "'*' can be used at the start or the end, not both".to_string(),
+                ))
+            }
+            Some(body) => (body, true),
+            None => (pattern.strip_suffix('*').unwrap_or(pattern), false),
+        };
+
+        if body.contains('*') {
+            return Err(invalid(
+                "'*' is only allowed as the first or last character".to_string(),
+            ));
+        }
+        if body.bytes().all(|c| c == b'?') {
+            return Err(invalid("there are no characters to match".to_string()));
+        }
+        if body.len() > MAX_PATTERN_LEN {
+            return Err(invalid(format!(
+                "it spans {} characters, but only the first {} can be chosen; \
+                 characters 53-56 are the checksum and version and follow from the key",
+                body.len(),
+                MAX_PATTERN_LEN
+            )));
+        }
+
+        let offset = if anchored_end {
+            MAX_PATTERN_LEN - body.len()
+        } else {
+            0
+        };
+
+        let mut value = [0u8; ADDRESS_BYTES];
+        let mut mask = [0u8; ADDRESS_BYTES];
+
+        for (i, c) in body.bytes().enumerate() {
+            if c == b'?' {
+                continue;
+            }
+            let symbol = symbol_value(c).ok_or_else(|| {
+                invalid(format!(
+                    "'{}' is not a lowercase base32 character (a-z, 2-7)",
+                    c as char
+                ))
+            })?;
+
+            // Character i covers bits 5i..5i+5, most significant bit first
+            for bit in 0..5 {
+                let pos = 5 * (offset + i) + bit;
+                let shift = 7 - (pos % 8);
+                mask[pos / 8] |= 1 << shift;
+                value[pos / 8] |= ((symbol >> (4 - bit)) & 1) << shift;
+            }
+        }
+
+        // Every selectable character covers at least one bit, so the range
+        // is never empty
+        let start = mask.iter().position(|&m| m != 0).unwrap_or(0);
+        let end = mask.iter().rposition(|&m| m != 0).map_or(0, |i| i + 1);
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            offset,
+            literal_len: body.bytes().filter(|&c| c != b'?').count(),
+            value,
+            mask,
+            start,
+            end,
+        })
+    }
+
+    /// The pattern this matcher was compiled from
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Character position the pattern is anchored at
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Number of fixed characters, i.e. the difficulty in base32 digits
+    pub fn literal_len(&self) -> usize {
+        self.literal_len
+    }
+
+    /// Chance that a random key matches
+    pub fn match_probability(&self) -> f64 {
+        32f64.powi(-(self.literal_len as i32))
+    }
+
+    /// The pattern as a plain prefix, if it is one (no wildcards, offset 0)
+    pub fn plain_prefix(&self) -> Option<&str> {
+        let body = self.pattern.strip_prefix('*').unwrap_or(&self.pattern);
+        let body = body.strip_suffix('*').unwrap_or(body);
+        (self.offset == 0 && !body.contains('?')).then_some(body)
+    }
+
+    /// Does the onion address of `pubkey` match this pattern?
+    #[inline]
+    pub fn matches(&self, pubkey: &[u8; 32]) -> bool {
+        let pubkey_end = self.end.min(32);
+        let bytes = pubkey[self.start..pubkey_end]
+            .iter()
+            .zip(&self.mask[self.start..])
+            .zip(&self.value[self.start..]);
+        for ((byte, mask), value) in bytes {
+            if byte & mask != *value {
+                return false;
+            }
+        }
+
+        // Only patterns covering the 52nd character reach into the checksum
+        if self.end > 32 {
+            let checksum = onion_checksum(pubkey);
+            for (i, byte) in checksum.iter().enumerate().take(self.end - 32) {
+                if byte & self.mask[32 + i] != self.value[32 + i] {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Index of the first public key byte the pattern constrains
+    pub fn pubkey_offset(&self) -> usize {
+        self.start
+    }
+
+    /// Expected public key bytes from [`BytePattern::pubkey_offset`] on,
+    /// masked; the checksum part of a pattern covering the 52nd character is
+    /// left out, so GPU-side checks using this must be re-verified with
+    /// [`BytePattern::matches`]
+    pub fn pubkey_value(&self) -> &[u8] {
+        &self.value[self.start..self.end.min(32)]
+    }
+
+    /// Mask for [`BytePattern::pubkey_value`]
+    pub fn pubkey_mask(&self) -> &[u8] {
+        &self.mask[self.start..self.end.min(32)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onion::pubkey_to_onion;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_rejects_invalid_patterns() {
+        assert!(BytePattern::new("").is_err());
+        assert!(BytePattern::new("*").is_err());
+        assert!(BytePattern::new("??*").is_err());
+        assert!(BytePattern::new("abc1").is_err());
+        assert!(BytePattern::new("ABC").is_err());
+        assert!(BytePattern::new("*abc*").is_err());
+        assert!(BytePattern::new("ab*cd").is_err());
+        assert!(BytePattern::new(&"a".repeat(MAX_PATTERN_LEN + 1)).is_err());
+        assert!(BytePattern::new(&"a".repeat(MAX_PATTERN_LEN)).is_ok());
+        assert!(BytePattern::new(&format!("*{}", "a".repeat(MAX_PATTERN_LEN))).is_ok());
+    }
+
+    #[test]
+    fn test_checksum_characters_are_explained() {
+        let pattern = format!("{}abc*", "?".repeat(50));
+        match BytePattern::new(&pattern) {
+            Err(GeneratorError::InvalidPattern { reason, .. }) => {
+                assert!(reason.contains("53-56"), "{}", reason);
+                assert!(reason.contains("checksum"), "{}", reason);
+            }
+            other => panic!("expected InvalidPattern, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_anchoring() {
+        let suffix = BytePattern::new("*shop").unwrap();
+        assert_eq!(suffix.offset(), 48);
+        assert_eq!(suffix.literal_len(), 4);
+        assert_eq!(suffix.plain_prefix(), None);
+
+        let positional = BytePattern::new("??cafe*").unwrap();
+        assert_eq!(positional.offset(), 0);
+        assert_eq!(positional.literal_len(), 4);
+        assert_eq!(positional.pubkey_offset(), 1);
+        assert_eq!(positional.plain_prefix(), None);
+
+        assert_eq!(BytePattern::new("cafe*").unwrap().plain_prefix(), Some("cafe"));
+        assert_eq!(BytePattern::new("cafe").unwrap().plain_prefix(), Some("cafe"));
+    }
+
+    #[test]
+    fn test_symbols_match_encoding() {
+        let pubkey: [u8; 32] = rand::random();
+        let onion = pubkey_to_onion(&pubkey);
+        for (i, c) in onion.bytes().take(MAX_PATTERN_LEN - 1).enumerate() {
+            assert_eq!(ALPHABET[symbol_at(&pubkey, i) as usize], c);
+        }
+        assert_eq!(ALPHABET[last_symbol(&pubkey) as usize], onion.as_bytes()[MAX_PATTERN_LEN - 1]);
+    }
+
+    #[test]
+    fn test_pubkey_view_of_byte_aligned_prefix() {
+        // 8 characters = exactly 5 bytes, so the last byte is fully masked
+        let prefix = BytePattern::new("aaaaaaaa").unwrap();
+        assert_eq!(prefix.pubkey_offset(), 0);
+        assert_eq!(prefix.pubkey_value(), &[0u8; 5]);
+        assert_eq!(prefix.pubkey_mask(), &[0xff; 5]);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_own_prefix_always_matches(pubkey in any::<[u8; 32]>()) {
+            let onion = pubkey_to_onion(&pubkey);
+            for len in 1..=MAX_PATTERN_LEN {
+                let prefix = BytePattern::new(&onion[..len]).unwrap();
+                prop_assert!(prefix.matches(&pubkey));
+            }
+        }
+
+        #[test]
+        fn prop_own_suffix_always_matches(pubkey in any::<[u8; 32]>()) {
+            let onion = pubkey_to_onion(&pubkey);
+            for len in 1..=MAX_PATTERN_LEN {
+                let suffix = format!("*{}", &onion[MAX_PATTERN_LEN - len..MAX_PATTERN_LEN]);
+                prop_assert!(BytePattern::new(&suffix).unwrap().matches(&pubkey));
+            }
+        }
+
+        #[test]
+        fn prop_agrees_with_string_matcher(
+            pubkey in any::<[u8; 32]>(),
+            flip_pos in any::<prop::sample::Index>(),
+            flip_symbol in 0..32usize,
+        ) {
+            // Start from the real prefix and change one character, so both
+            // matching and non-matching cases are exercised at every length
+            let onion = pubkey_to_onion(&pubkey);
+            for len in 1..=MAX_PATTERN_LEN {
+                let mut candidate = onion.as_bytes()[..len].to_vec();
+                candidate[flip_pos.index(len)] = ALPHABET[flip_symbol];
+                let candidate = String::from_utf8(candidate).unwrap();
+
+                let prefix = BytePattern::new(&candidate).unwrap();
+                prop_assert_eq!(prefix.matches(&pubkey), onion.starts_with(&candidate));
+            }
+        }
+
+        #[test]
+        fn prop_positional_agrees_with_string_matcher(
+            pubkey in any::<[u8; 32]>(),
+            offset in 0..MAX_PATTERN_LEN,
+            len in 1..=8usize,
+            flip_symbol in 0..32usize,
+        ) {
+            let onion = pubkey_to_onion(&pubkey);
+            let len = len.min(MAX_PATTERN_LEN - offset);
+            let mut word = onion.as_bytes()[offset..offset + len].to_vec();
+            word[0] = ALPHABET[flip_symbol];
+            let word = String::from_utf8(word).unwrap();
+
+            let expected = onion[offset..offset + len] == word;
+
+            let pattern = BytePattern::new(&format!("{}{}*", "?".repeat(offset), word)).unwrap();
+            prop_assert_eq!(pattern.matches(&pubkey), expected);
+
+            let suffix = BytePattern::new(&format!("*{}{}", word, "?".repeat(MAX_PATTERN_LEN - offset - len))).unwrap();
+            prop_assert_eq!(suffix.matches(&pubkey), expected);
+        }
+    }
+}
@@ -0,0 +1,606 @@
+//! Regular expressions over the base32 alphabet, compiled to a DFA
+//!
+//! The automaton steps over the 5-bit symbols of the address, which are read
+//! straight out of the public key bits, so evaluating a candidate needs no
+//! onion string and no allocation. Only the 52nd symbol needs the checksum.
+//!
+//! Supported syntax: literals `a-z2-7`, `.`, classes (`[a-f]`, `[^2-7]`),
+//! groups, `|`, `*`, `+`, `?`, `{m}`, `{m,}`, `{m,n}`, and `^`/`$` anchors at
+//! the very start/end. The expression is searched for anywhere in the 52
+//! selectable characters; `$` anchors at the end of those, not at the
+//! checksum characters.
+
+use std::collections::HashMap;
+
+use crate::backend::GeneratorError;
+
+use super::{last_symbol, symbol_at, symbol_value, ALPHABET, MAX_PATTERN_LEN};
+
+/// Symbol set matching every base32 character
+const ALL_SYMBOLS: u32 = u32::MAX;
+
+/// Upper bound on automaton size, to keep pathological expressions in check
+const MAX_NFA_STATES: usize = 10_000;
+const MAX_DFA_STATES: usize = 4_096;
+
+/// DFA state from which no match is possible; always index 0
+const DEAD: u32 = 0;
+
+/// Regular expression compiled to a DFA over 5-bit symbols
+#[derive(Debug, Clone)]
+pub struct RegexPattern {
+    source: String,
+    /// Next state for each of the 32 symbols, per state
+    table: Vec<[u32; 32]>,
+    accepting: Vec<bool>,
+    /// States from which some 52nd symbol leads to a match; the checksum is
+    /// only computed for candidates in one of these
+    can_finish: Vec<bool>,
+    start: u32,
+    anchored_end: bool,
+}
+
+impl RegexPattern {
+    /// Compile a regular expression over the lowercase base32 alphabet
+    pub fn new(source: &str) -> Result<Self, GeneratorError> {
+        let invalid = |reason: &str| GeneratorError::InvalidPattern {
+            pattern: source.to_string(),
+            reason: reason.to_string(),
+        };
+
+        let (body, anchored_start) = match source.strip_prefix('^') {
+            Some(body) => (body, true),
+            None => (source, false),
+        };
+        let (body, anchored_end) = match body.strip_suffix('$') {
+            Some(body) => (body, true),
+            None => (body, false),
+        };
+
+        let mut node = Parser::new(body).parse().map_err(|reason| invalid(&reason))?;
+        if !anchored_start {
+            // Search anywhere: let the match start after any number of symbols
+            node = Node::Concat(vec![
+                Node::Repeat(Box::new(Node::Set(ALL_SYMBOLS)), 0, None),
+                node,
+            ]);
+        }
+
+        let mut nfa = Nfa::default();
+        let (nfa_start, nfa_end) = nfa.build(&node).map_err(|reason| invalid(&reason))?;
+        let (mut table, accepting, start) =
+            determinize(&nfa, nfa_start, nfa_end).map_err(|reason| invalid(&reason))?;
+
+        if accepting[start as usize] {
+            return Err(invalid("it matches the empty string, i.e. every address"));
+        }
+
+        // Without `$` a match anywhere settles it, so accepting states absorb
+        if !anchored_end {
+            for (state, row) in table.iter_mut().enumerate() {
+                if accepting[state] {
+                    *row = [state as u32; 32];
+                }
+            }
+        }
+
+        prune_dead_states(&mut table, &accepting);
+        if table[start as usize] == [DEAD; 32] {
+            return Err(invalid("it can never match"));
+        }
+
+        let can_finish = table
+            .iter()
+            .map(|row| row.iter().any(|&to| accepting[to as usize]))
+            .collect();
+
+        Ok(Self {
+            source: source.to_string(),
+            table,
+            accepting,
+            can_finish,
+            start,
+            anchored_end,
+        })
+    }
+
+    /// The expression this automaton was compiled from
+    pub fn pattern(&self) -> &str {
+        &self.source
+    }
+
+    /// Number of DFA states, including the dead state
+    pub fn state_count(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Does the onion address of `pubkey` match this expression?
+    #[inline]
+    pub fn matches(&self, pubkey: &[u8; 32]) -> bool {
+        let mut state = self.start;
+        for i in 0..MAX_PATTERN_LEN - 1 {
+            state = self.table[state as usize][symbol_at(pubkey, i) as usize];
+            if state == DEAD {
+                return false;
+            }
+            if !self.anchored_end && self.accepting[state as usize] {
+                return true;
+            }
+        }
+
+        // The 52nd character holds the last public key bit and four checksum bits
+        if !self.can_finish[state as usize] {
+            return false;
+        }
+        state = self.table[state as usize][last_symbol(pubkey) as usize];
+        self.accepting[state as usize]
+    }
+
+    /// Chance that a random key matches, assuming uniform symbols
+    pub fn match_probability(&self) -> f64 {
+        let mut dist = vec![0.0; self.table.len()];
+        dist[self.start as usize] = 1.0;
+        for _ in 0..MAX_PATTERN_LEN {
+            let mut next = vec![0.0; self.table.len()];
+            for (state, p) in dist.iter().enumerate().filter(|(_, p)| **p > 0.0) {
+                for &to in &self.table[state] {
+                    next[to as usize] += p / 32.0;
+                }
+            }
+            dist = next;
+        }
+        dist.iter()
+            .zip(&self.accepting)
+            .filter(|(_, accepting)| **accepting)
+            .map(|(p, _)| p)
+            .sum()
+    }
+}
+
+/// Parsed expression
+#[derive(Debug, Clone)]
+enum Node {
+    /// One symbol out of a set, as a bit mask over symbol values
+    Set(u32),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    /// Node repeated between `min` and `max` (unbounded if `None`) times
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            bytes: source.as_bytes(),
+            pos: 0,
+        }
+    }
+
+    fn parse(mut self) -> Result<Node, String> {
+        let node = self.parse_alt()?;
+        match self.peek() {
+            None => Ok(node),
+            Some(b')') => Err("unmatched ')'".to_string()),
+            Some(c) => Err(format!("unexpected '{}'", c as char)),
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn parse_alt(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.parse_concat()?];
+        while self.peek() == Some(b'|') {
+            self.pos += 1;
+            branches.push(self.parse_concat()?);
+        }
+        Ok(if branches.len() == 1 {
+            branches.pop().unwrap()
+        } else {
+            Node::Alt(branches)
+        })
+    }
+
+    fn parse_concat(&mut self) -> Result<Node, String> {
+        let mut items = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == b'|' || c == b')' {
+                break;
+            }
+            items.push(self.parse_repeat()?);
+        }
+        Ok(Node::Concat(items))
+    }
+
+    fn parse_repeat(&mut self) -> Result<Node, String> {
+        let mut node = self.parse_atom()?;
+        loop {
+            let (min, max) = match self.peek() {
+                Some(b'*') => (0, None),
+                Some(b'+') => (1, None),
+                Some(b'?') => (0, Some(1)),
+                Some(b'{') => {
+                    self.pos += 1;
+                    node = self.parse_counts(node)?;
+                    continue;
+                }
+                _ => return Ok(node),
+            };
+            self.pos += 1;
+            node = Node::Repeat(Box::new(node), min, max);
+        }
+    }
+
+    /// Parse `m}`, `m,}` or `m,n}` after an opening brace and apply it to `node`
+    fn parse_counts(&mut self, node: Node) -> Result<Node, String> {
+        let min = self.parse_number()?.ok_or("expected a number after '{'")?;
+        let max = if self.peek() == Some(b',') {
+            self.pos += 1;
+            self.parse_number()?
+        } else {
+            Some(min)
+        };
+        if self.next() != Some(b'}') {
+            return Err("unterminated '{'".to_string());
+        }
+        if let Some(max) = max.filter(|&max| max < min) {
+            return Err(format!("invalid repetition {{{},{}}}", min, max));
+        }
+        Ok(Node::Repeat(Box::new(node), min, max))
+    }
+
+    fn parse_number(&mut self) -> Result<Option<usize>, String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Ok(None);
+        }
+        let digits = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap();
+        match digits.parse::<usize>() {
+            Ok(n) if n <= MAX_PATTERN_LEN => Ok(Some(n)),
+            _ => Err(format!(
+                "repetition count {} exceeds the {} selectable characters",
+                digits, MAX_PATTERN_LEN
+            )),
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(b'(') => {
+                // Non-capturing groups are accepted for familiarity
+                if self.bytes[self.pos..].starts_with(b"?:") {
+                    self.pos += 2;
+                }
+                let node = self.parse_alt()?;
+                if self.next() != Some(b')') {
+                    return Err("unclosed '('".to_string());
+                }
+                Ok(node)
+            }
+            Some(b'.') => Ok(Node::Set(ALL_SYMBOLS)),
+            Some(b'[') => self.parse_class(),
+            Some(b'^' | b'$') => {
+                Err("'^' and '$' are only supported at the start and end".to_string())
+            }
+            Some(c @ (b'*' | b'+' | b'?' | b'{')) => {
+                Err(format!("'{}' has nothing to repeat", c as char))
+            }
+            Some(c) => Ok(Node::Set(1 << literal(c)?)),
+            None => unreachable!("parse_concat stops at the end"),
+        }
+    }
+
+    fn parse_class(&mut self) -> Result<Node, String> {
+        let negate = self.peek() == Some(b'^');
+        if negate {
+            self.pos += 1;
+        }
+
+        let mut set = 0u32;
+        loop {
+            let c = self.next().ok_or("unclosed '['")?;
+            if c == b']' {
+                break;
+            }
+            let from = literal(c)?;
+            if self.peek() == Some(b'-') && self.bytes.get(self.pos + 1) != Some(&b']') {
+                self.pos += 1;
+                let to_char = self.next().ok_or("unclosed '['")?;
+                literal(to_char)?;
+                if to_char < c {
+                    return Err(format!("invalid range {}-{}", c as char, to_char as char));
+                }
+                // Ranges follow ASCII order, restricted to the alphabet
+                for (value, &a) in ALPHABET.iter().enumerate() {
+                    if (c..=to_char).contains(&a) {
+                        set |= 1 << value;
+                    }
+                }
+            } else {
+                set |= 1 << from;
+            }
+        }
+
+        let set = if negate { !set } else { set };
+        if set == 0 {
+            return Err("empty character class".to_string());
+        }
+        Ok(Node::Set(set))
+    }
+}
+
+fn literal(c: u8) -> Result<u8, String> {
+    symbol_value(c).ok_or_else(|| {
+        format!(
+            "'{}' is not a lowercase base32 character (a-z, 2-7)",
+            c as char
+        )
+    })
+}
+
+/// Thompson NFA: each state either consumes one symbol out of `set` and
+/// moves to `next`, or has only epsilon moves
+#[derive(Default)]
+struct Nfa {
+    states: Vec<NfaState>,
+}
+
+#[derive(Default)]
+struct NfaState {
+    set: u32,
+    next: usize,
+    epsilon: Vec<usize>,
+}
+
+impl Nfa {
+    fn add(&mut self) -> Result<usize, String> {
+        if self.states.len() >= MAX_NFA_STATES {
+            return Err("the expression is too large".to_string());
+        }
+        self.states.push(NfaState::default());
+        Ok(self.states.len() - 1)
+    }
+
+    /// Build `node`, returning its entry and exit states
+    fn build(&mut self, node: &Node) -> Result<(usize, usize), String> {
+        match node {
+            Node::Set(set) => {
+                let start = self.add()?;
+                let end = self.add()?;
+                self.states[start].set = *set;
+                self.states[start].next = end;
+                Ok((start, end))
+            }
+            Node::Concat(items) => {
+                let start = self.add()?;
+                let mut end = start;
+                for item in items {
+                    let (item_start, item_end) = self.build(item)?;
+                    self.states[end].epsilon.push(item_start);
+                    end = item_end;
+                }
+                Ok((start, end))
+            }
+            Node::Alt(branches) => {
+                let start = self.add()?;
+                let end = self.add()?;
+                for branch in branches {
+                    let (branch_start, branch_end) = self.build(branch)?;
+                    self.states[start].epsilon.push(branch_start);
+                    self.states[branch_end].epsilon.push(end);
+                }
+                Ok((start, end))
+            }
+            Node::Repeat(inner, min, max) => {
+                let start = self.add()?;
+                let mut end = start;
+                for _ in 0..*min {
+                    let (copy_start, copy_end) = self.build(inner)?;
+                    self.states[end].epsilon.push(copy_start);
+                    end = copy_end;
+                }
+                match max {
+                    None => {
+                        // Kleene star of one more copy
+                        let (copy_start, copy_end) = self.build(inner)?;
+                        let exit = self.add()?;
+                        self.states[end].epsilon.extend([copy_start, exit]);
+                        self.states[copy_end].epsilon.extend([copy_start, exit]);
+                        end = exit;
+                    }
+                    Some(max) => {
+                        // Each optional copy may skip straight to the exit
+                        let exit = self.add()?;
+                        for _ in *min..*max {
+                            let (copy_start, copy_end) = self.build(inner)?;
+                            self.states[end].epsilon.extend([copy_start, exit]);
+                            end = copy_end;
+                        }
+                        self.states[end].epsilon.push(exit);
+                        end = exit;
+                    }
+                }
+                Ok((start, end))
+            }
+        }
+    }
+
+    /// Sorted epsilon closure of `states`
+    fn closure(&self, states: impl IntoIterator<Item = usize>) -> Vec<usize> {
+        let mut seen = vec![false; self.states.len()];
+        let mut stack: Vec<usize> = states.into_iter().collect();
+        let mut closure = Vec::new();
+        while let Some(state) = stack.pop() {
+            if std::mem::replace(&mut seen[state], true) {
+                continue;
+            }
+            closure.push(state);
+            stack.extend(&self.states[state].epsilon);
+        }
+        closure.sort_unstable();
+        closure
+    }
+}
+
+type Dfa = (Vec<[u32; 32]>, Vec<bool>, u32);
+
+/// Subset construction; state 0 is the dead (empty) state
+fn determinize(nfa: &Nfa, start: usize, end: usize) -> Result<Dfa, String> {
+    let mut ids: HashMap<Vec<usize>, u32> = HashMap::new();
+    let mut sets: Vec<Vec<usize>> = Vec::new();
+    let mut table: Vec<[u32; 32]> = Vec::new();
+
+    ids.insert(Vec::new(), DEAD);
+    sets.push(Vec::new());
+    table.push([DEAD; 32]);
+
+    let start_set = nfa.closure([start]);
+    let start_id = sets.len() as u32;
+    ids.insert(start_set.clone(), start_id);
+    sets.push(start_set);
+    table.push([DEAD; 32]);
+
+    let mut todo = start_id as usize;
+    while todo < sets.len() {
+        for symbol in 0..32 {
+            let moved = sets[todo]
+                .iter()
+                .map(|&s| &nfa.states[s])
+                .filter(|s| s.set & (1 << symbol) != 0)
+                .map(|s| s.next);
+            let target = nfa.closure(moved.collect::<Vec<_>>());
+
+            let id = match ids.get(&target) {
+                Some(&id) => id,
+                None => {
+                    if sets.len() >= MAX_DFA_STATES {
+                        return Err("the expression is too complex".to_string());
+                    }
+                    let id = sets.len() as u32;
+                    ids.insert(target.clone(), id);
+                    sets.push(target);
+                    table.push([DEAD; 32]);
+                    id
+                }
+            };
+            table[todo][symbol] = id;
+        }
+        todo += 1;
+    }
+
+    let accepting = sets.iter().map(|set| set.binary_search(&end).is_ok()).collect();
+    Ok((table, accepting, start_id))
+}
+
+/// Redirect every transition into a state that cannot reach a match to
+/// [`DEAD`], so the matcher can give up on a candidate early
+fn prune_dead_states(table: &mut [[u32; 32]], accepting: &[bool]) {
+    let mut live = accepting.to_vec();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for state in 0..table.len() {
+            if !live[state] && table[state].iter().any(|&to| live[to as usize]) {
+                live[state] = true;
+                changed = true;
+            }
+        }
+    }
+
+    for row in table.iter_mut() {
+        for to in row.iter_mut() {
+            if !live[*to as usize] {
+                *to = DEAD;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onion::pubkey_to_onion;
+    use crate::pattern::BytePattern;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_rejects_invalid_expressions() {
+        for source in [
+            "", "a*", "(a|)", "ab(", "ab)", "[a", "[]", "a{3,1}", "a{99}", "*a", "ab1", "A", "a^b",
+            "[^a-z2-7]",
+        ] {
+            assert!(RegexPattern::new(source).is_err(), "{:?} should be rejected", source);
+        }
+    }
+
+    #[test]
+    fn test_product_variants() {
+        let regex = RegexPattern::new("^(my|our)shop[2-7]").unwrap();
+        let mut pubkey = [0u8; 32];
+        assert!(!regex.matches(&pubkey));
+
+        // Set the leading key bits to spell out a couple of expansions
+        for expansion in ["myshop2", "ourshop7"] {
+            let prefix = BytePattern::new(expansion).unwrap();
+            pubkey[..prefix.pubkey_value().len()].copy_from_slice(prefix.pubkey_value());
+            assert!(regex.matches(&pubkey), "{}", pubkey_to_onion(&pubkey));
+        }
+    }
+
+    fn regex_strategy() -> impl Strategy<Value = String> {
+        // A tiny alphabet keeps random expressions likely to match
+        let leaf = prop_oneof![
+            Just("a".to_string()),
+            Just("b".to_string()),
+            Just("2".to_string()),
+            Just(".".to_string()),
+            Just("[ab]".to_string()),
+            Just("[^a]".to_string()),
+        ];
+        let expr = leaf.prop_recursive(4, 16, 3, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 2..4).prop_map(|items| items.concat()),
+                (inner.clone(), inner.clone()).prop_map(|(a, b)| format!("({}|{})", a, b)),
+                inner.clone().prop_map(|a| format!("({})*", a)),
+                inner.clone().prop_map(|a| format!("({})+", a)),
+                inner.clone().prop_map(|a| format!("({})?", a)),
+                inner.prop_map(|a| format!("({}){{1,3}}", a)),
+            ]
+        });
+        (any::<bool>(), expr, any::<bool>()).prop_map(|(start, expr, end)| {
+            format!("{}{}{}", if start { "^" } else { "" }, expr, if end { "$" } else { "" })
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn prop_agrees_with_regex_crate(source in regex_strategy(), pubkey in any::<[u8; 32]>()) {
+            let onion = pubkey_to_onion(&pubkey);
+            let reference = regex::Regex::new(&source).unwrap();
+
+            // Expressions matching the empty string are rejected by design
+            match RegexPattern::new(&source) {
+                Ok(compiled) => prop_assert_eq!(
+                    compiled.matches(&pubkey),
+                    reference.is_match(&onion[..MAX_PATTERN_LEN])
+                ),
+                Err(_) => prop_assert!(reference.is_match("")),
+            }
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Look-alike spellings of a pattern
+//!
+//! Base32 has no `0`, `1`, `8` or `9`, but its digits `2`-`7` can stand in
+//! for some letters. In look-alike mode each such character of a pattern
+//! accepts its partner as well, so `tor` also matches `7or` and `*shop` also
+//! matches `*5hop`. Every character position holds a set of accepted
+//! symbols, which the CPU checks directly; GPU kernels only compare fixed
+//! bytes and get every spelling as its own [`BytePattern`].
+
+use crate::backend::GeneratorError;
+
+use super::{last_symbol, symbol_at, symbol_value, BytePattern, ALPHABET, MAX_PATTERN_LEN};
+
+/// Characters that read alike, in both directions
+pub const LOOKALIKES: [(u8, u8); 5] = [(b'a', b'4'), (b'e', b'3'), (b's', b'5'), (b't', b'7'), (b'z', b'2')];
+
+/// Most spellings a pattern may expand to for the GPU kernels, which test
+/// every spelling of every pattern against each key
+pub const MAX_VARIANTS: usize = 64;
+
+/// The partner of a look-alike character
+pub fn lookalike(c: u8) -> Option<u8> {
+    LOOKALIKES.iter().find_map(|&(a, b)| {
+        if c == a {
+            Some(b)
+        } else if c == b {
+            Some(a)
+        } else {
+            None
+        }
+    })
+}
+
+/// Pattern whose characters also match their look-alikes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LookalikePattern {
+    pattern: String,
+    anchored_end: bool,
+    /// Character position the pattern is anchored at
+    offset: usize,
+    /// Pattern characters between the anchors, `?` for wildcards
+    body: Vec<u8>,
+    /// Accepted symbols per fixed character position, in position order
+    classes: Vec<(usize, u32)>,
+}
+
+impl LookalikePattern {
+    /// Compile a pattern in the syntax of [`BytePattern::new`]
+    pub fn new(pattern: &str) -> Result<Self, GeneratorError> {
+        // Same syntax and limits as a plain pattern
+        let plain = BytePattern::new(pattern)?;
+
+        let anchored_end = pattern.starts_with('*');
+        let body = pattern.trim_matches('*').as_bytes().to_vec();
+        let classes = body
+            .iter()
+            .enumerate()
+            .filter(|(_, &c)| c != b'?')
+            .map(|(i, &c)| {
+                let set = std::iter::once(c)
+                    .chain(lookalike(c))
+                    .filter_map(symbol_value)
+                    .fold(0u32, |set, symbol| set | 1 << symbol);
+                (plain.offset() + i, set)
+            })
+            .collect();
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            anchored_end,
+            offset: plain.offset(),
+            body,
+            classes,
+        })
+    }
+
+    /// The pattern this matcher was compiled from
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Number of spellings this pattern accepts
+    pub fn variant_count(&self) -> usize {
+        self.classes
+            .iter()
+            .map(|(_, set)| set.count_ones() as usize)
+            .product()
+    }
+
+    /// Chance that a random key matches
+    pub fn match_probability(&self) -> f64 {
+        self.classes
+            .iter()
+            .map(|(_, set)| set.count_ones() as f64 / 32.0)
+            .product()
+    }
+
+    /// Does the onion address of `pubkey` match any spelling?
+    #[inline]
+    pub fn matches(&self, pubkey: &[u8; 32]) -> bool {
+        // Positions are in order, so the checksum is only computed for
+        // candidates that match everything before the 52nd character
+        self.classes.iter().all(|&(pos, set)| {
+            let symbol = if pos < MAX_PATTERN_LEN - 1 {
+                symbol_at(pubkey, pos)
+            } else {
+                last_symbol(pubkey)
+            };
+            set >> symbol & 1 != 0
+        })
+    }
+
+    /// The spelling found in the address of a matching `pubkey`
+    pub fn matched_variant(&self, pubkey: &[u8; 32]) -> String {
+        let spelled = self.classes.iter().fold(self.body.clone(), |mut body, &(pos, _)| {
+            let symbol = if pos < MAX_PATTERN_LEN - 1 {
+                symbol_at(pubkey, pos)
+            } else {
+                last_symbol(pubkey)
+            };
+            body[pos - self.offset] = ALPHABET[symbol as usize];
+            body
+        });
+        self.decorate(&spelled)
+    }
+
+    /// Every spelling as a plain pattern, for backends that only compare bytes
+    pub fn variants(&self) -> Result<Vec<BytePattern>, GeneratorError> {
+        let count = self.variant_count();
+        if count > MAX_VARIANTS {
+            return Err(GeneratorError::InvalidPattern {
+                pattern: self.pattern.clone(),
+                reason: format!(
+                    "it has {} look-alike spellings, but GPU backends take at most {}; \
+                     use the CPU backend or a shorter pattern",
+                    count, MAX_VARIANTS
+                ),
+            });
+        }
+
+        let mut spellings = vec![self.body.clone()];
+        for &(pos, set) in &self.classes {
+            spellings = spellings
+                .into_iter()
+                .flat_map(|spelling| {
+                    (0..32).filter(move |symbol| set >> symbol & 1 != 0).map(move |symbol| {
+                        let mut spelling = spelling.clone();
+                        spelling[pos - self.offset] = ALPHABET[symbol];
+                        spelling
+                    })
+                })
+                .collect();
+        }
+        spellings
+            .iter()
+            .map(|spelling| BytePattern::new(&self.decorate(spelling)))
+            .collect()
+    }
+
+    /// Put a spelling of the body back between the original anchors
+    fn decorate(&self, body: &[u8]) -> String {
+        let body = String::from_utf8_lossy(body);
+        if self.anchored_end {
+            format!("*{}", body)
+        } else if self.pattern.ends_with('*') {
+            format!("{}*", body)
+        } else {
+            body.into_owned()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onion::pubkey_to_onion;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_variants() {
+        let pattern = LookalikePattern::new("*t?st").unwrap();
+        assert_eq!(pattern.variant_count(), 8);
+
+        let mut variants: Vec<String> = pattern
+            .variants()
+            .unwrap()
+            .iter()
+            .map(|v| v.pattern().to_string())
+            .collect();
+        variants.sort();
+        assert_eq!(
+            variants,
+            ["*7?57", "*7?5t", "*7?s7", "*7?st", "*t?57", "*t?5t", "*t?s7", "*t?st"]
+        );
+
+        assert!(LookalikePattern::new(&"e".repeat(7)).unwrap().variants().is_err());
+        assert!(LookalikePattern::new("tor*").unwrap().variants().is_ok());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_agrees_with_variants(
+            pubkey in any::<[u8; 32]>(),
+            offset in 0..MAX_PATTERN_LEN,
+            len in 1..=4usize,
+            flips in prop::collection::vec(any::<bool>(), 4),
+        ) {
+            // Take the address's own characters and swap some of them for
+            // their look-alikes, so both matches and misses occur
+            let onion = pubkey_to_onion(&pubkey);
+            let len = len.min(MAX_PATTERN_LEN - offset);
+            let word: String = onion.bytes()
+                .skip(offset)
+                .take(len)
+                .zip(&flips)
+                .map(|(c, &flip)| if flip { lookalike(c).unwrap_or(b'b') } else { c } as char)
+                .collect();
+
+            let text = format!("*{}{}", word, "?".repeat(MAX_PATTERN_LEN - offset - len));
+            let pattern = LookalikePattern::new(&text).unwrap();
+            let matching: Vec<BytePattern> = pattern
+                .variants()
+                .unwrap()
+                .into_iter()
+                .filter(|variant| variant.matches(&pubkey))
+                .collect();
+
+            prop_assert!(matching.len() <= 1);
+            prop_assert_eq!(pattern.matches(&pubkey), matching.len() == 1);
+            if let Some(variant) = matching.first() {
+                prop_assert_eq!(pattern.matched_variant(&pubkey), variant.pattern());
+            }
+        }
+    }
+}
@@ -0,0 +1,280 @@
+//! Crash-safe writes of private files
+//!
+//! Secrets are never written in place where others can see them. Contents go
+//! to a temporary file next to the target, are synced to disk and only then
+//! take the target's name, so a crash or a full disk leaves either nothing or
+//! the complete file behind. A new directory is filled under a temporary name
+//! with [`create_new`] and published whole with [`publish_dir`]. On Unix,
+//! files are created 0600 and directories 0700 regardless of the umask.
+//!
+//! Record logs are the exception: [`append`] adds to the end of a file in
+//! place.
+
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+/// Create a directory and any missing parents, readable by the owner only
+pub fn create_private_dir_all(path: &Path) -> std::io::Result<()> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(0o700);
+    builder.create(path)
+}
+
+/// Create a directory readable by the owner only; fails if it exists
+pub fn create_private_dir(path: &Path) -> std::io::Result<()> {
+    let mut builder = DirBuilder::new();
+    #[cfg(unix)]
+    builder.mode(0o700);
+    builder.create(path)
+}
+
+/// Write `contents` to a file that must not exist yet
+///
+/// Fails with [`std::io::ErrorKind::AlreadyExists`] if `path` exists, also
+/// when another process creates it concurrently. The complete file is linked
+/// into place, as unlike a rename a hard link never replaces its target. On
+/// file systems without hard links (FAT, exFAT, many SMB shares) it is
+/// renamed into place where the platform can refuse to replace the target,
+/// and otherwise written in place, so a crash may leave a partial file.
+pub fn write_new(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let temp = write_temp(path, contents)?;
+    let moved = match std::fs::hard_link(&temp, path) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => rename_noreplace(&temp, path),
+        linked => linked.map(|()| true),
+    };
+    let _ = std::fs::remove_file(&temp);
+    if !moved? {
+        create_new(path, contents)?;
+    }
+    sync_parent(path)
+}
+
+/// Create a file that must not exist yet and write `contents` to it in place
+///
+/// Not crash-safe on its own: meant for filling a fresh private directory
+/// that nobody uses before [`publish_dir`] gives it its final name.
+pub fn create_new(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Replace the contents of `path`, or create it
+pub fn replace(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let temp = write_temp(path, contents)?;
+    if let Err(e) = std::fs::rename(&temp, path) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e);
+    }
+    sync_parent(path)
+}
+
+/// Append `contents` to `path` with a single `write` call, creating it if
+/// needed
+///
+/// Only meant for logs of whole records such as the manifest: each write to
+/// a file opened for appending lands at its end in one piece, so concurrent
+/// writers never interleave partial records. A short write would leave part
+/// of a record for the next one to continue, so it fails with
+/// [`std::io::ErrorKind::WriteZero`] instead of writing the rest.
+pub fn append(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path)?;
+    let written = loop {
+        match file.write(contents) {
+            // Interrupted before anything was written
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            written => break written?,
+        }
+    };
+    if written < contents.len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::WriteZero,
+            format!("{}: only {} of {} bytes appended", path.display(), written, contents.len()),
+        ));
+    }
+    file.sync_data()
+}
+
+/// Give a fully written directory its final name, unless that name is taken
+///
+/// `from` must be a sibling of `to`, so the rename is atomic. Fails with
+/// [`std::io::ErrorKind::AlreadyExists`] or
+/// [`std::io::ErrorKind::DirectoryNotEmpty`] if `to` exists, also when it is
+/// created concurrently. Where the rename itself cannot refuse to replace
+/// `to`, an empty `to` is created first, which a rename only replaces for
+/// whoever created it.
+pub fn publish_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    File::open(from)?.sync_all()?;
+    if !rename_noreplace(from, to)? {
+        #[cfg(unix)]
+        {
+            create_private_dir(to)?;
+            if let Err(e) = std::fs::rename(from, to) {
+                let _ = std::fs::remove_dir(to);
+                return Err(e);
+            }
+        }
+        // Windows never renames a directory over an existing one
+        #[cfg(not(unix))]
+        {
+            if to.symlink_metadata().is_ok() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", to.display()),
+                ));
+            }
+            std::fs::rename(from, to)?;
+        }
+    }
+    sync_parent(to)
+}
+
+/// Rename `from` to `to` unless `to` exists, in one step
+///
+/// Returns `false` without touching either if the platform or the file
+/// system cannot refuse to replace `to` in the rename itself.
+#[cfg(target_os = "linux")]
+fn rename_noreplace(from: &Path, to: &Path) -> std::io::Result<bool> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = |path: &Path| CString::new(path.as_os_str().as_bytes()).map_err(std::io::Error::from);
+    let (from, to) = (c_path(from)?, c_path(to)?);
+    // Called directly, as older C libraries lack a renameat2 wrapper
+    let renamed = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if renamed == 0 {
+        return Ok(true);
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        // The kernel or the file system does not support the flag
+        Some(libc::EINVAL | libc::ENOSYS) => Ok(false),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rename_noreplace(_from: &Path, _to: &Path) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+/// Hidden sibling of `path` to stage a write in
+pub fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}.{:016x}.tmp", name, rand::random::<u64>()))
+}
+
+/// Write and sync a fresh private temporary file next to `path`
+fn write_temp(path: &Path, contents: &[u8]) -> std::io::Result<PathBuf> {
+    let temp = temp_path(path);
+    if let Err(e) = create_new(&temp, contents) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e);
+    }
+    Ok(temp)
+}
+
+/// Make a new or renamed directory entry durable
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+    // Windows cannot open directories as files; renames are durable there
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_new_refuses_to_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+
+        write_new(&path, b"first").unwrap();
+        let err = write_new(&path, b"second").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"first");
+
+        replace(&path, b"third").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"third");
+        // No temporary files are left behind
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_publish_dir_never_replaces() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("service");
+        let stage = |name: &str| {
+            let staging = temp_path(&target);
+            create_private_dir(&staging).unwrap();
+            create_new(&staging.join("hostname"), name.as_bytes()).unwrap();
+            staging
+        };
+
+        publish_dir(&stage("first"), &target).unwrap();
+        assert_eq!(std::fs::read(target.join("hostname")).unwrap(), b"first");
+
+        // Also not an empty directory, which a plain rename would replace
+        let empty = dir.path().join("empty");
+        create_private_dir(&empty).unwrap();
+        for taken in [&target, &empty] {
+            let staging = stage("second");
+            let err = publish_dir(&staging, taken).unwrap_err();
+            assert!(
+                matches!(err.kind(), std::io::ErrorKind::AlreadyExists | std::io::ErrorKind::DirectoryNotEmpty),
+                "{:?}",
+                err
+            );
+            assert!(staging.is_dir());
+        }
+        assert_eq!(std::fs::read(target.join("hostname")).unwrap(), b"first");
+        assert_eq!(std::fs::read_dir(&empty).unwrap().count(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let private = dir.path().join("a/b");
+        create_private_dir_all(&private).unwrap();
+        write_new(&private.join("key"), b"secret").unwrap();
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir.path().join("a")), 0o700);
+        assert_eq!(mode(&private), 0o700);
+        assert_eq!(mode(&private.join("key")), 0o600);
+    }
+}
@@ -0,0 +1,258 @@
+//! Secret keys sealed to an X25519 recipient
+//!
+//! For searches on machines that should never hold a usable key, each found
+//! key is encrypted to a recipient public key in memory, before anything is
+//! written. Only the matching [`Identity`] can open it again, and the search
+//! host never sees that. The hidden service directory then holds
+//! [`SEALED_SECRET_KEY_FILE`] instead of a secret key:
+//!
+//! ```text
+//! t3v sealed key v1
+//! onion: <address>.onion
+//! recipient: <64 hex digits>
+//! ephemeral: <64 hex digits>
+//! cipher: xchacha20poly1305
+//! nonce: <48 hex digits>
+//! ciphertext: <hex>
+//! ```
+//!
+//! Every file uses a fresh ephemeral X25519 key. The cipher key is derived
+//! from the shared secret with HKDF-SHA256 over both public keys, and as with
+//! passphrase-encrypted keys every line above the ciphertext is
+//! authenticated.
+
+use chacha20poly1305::aead::KeyInit;
+use chacha20poly1305::{Key, XChaCha20Poly1305};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::backend::GeneratorError;
+use crate::encrypted_key::{decrypt_expanded, encrypt_expanded, KeyFileFields, CIPHER};
+
+/// File holding the sealed secret key, in place of `hs_ed25519_secret_key`
+pub const SEALED_SECRET_KEY_FILE: &str = "hs_ed25519_secret_key.sealed";
+
+/// First line of every sealed key file
+const MAGIC: &str = "t3v sealed key v1";
+
+/// First line of an identity file
+const IDENTITY_MAGIC: &str = "t3v identity v1";
+
+/// HKDF info string, binding derived keys to this format
+const KDF_INFO: &[u8] = b"t3v sealed key v1";
+
+/// Public key that found keys are sealed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+impl Recipient {
+    /// Parse a recipient from its 64 hex digits
+    pub fn from_hex(text: &str) -> Result<Self, GeneratorError> {
+        let bytes: [u8; 32] = hex::decode(text.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| GeneratorError::InvalidRecipient("expected 64 hex digits".to_string()))?;
+        let public = PublicKey::from(bytes);
+        // A low-order point would make every shared secret all zeros
+        if !EphemeralSecret::random().diffie_hellman(&public).was_contributory() {
+            return Err(GeneratorError::InvalidRecipient("not a usable X25519 key".to_string()));
+        }
+        Ok(Self(public))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0.as_bytes())
+    }
+}
+
+/// Private key opening keys sealed to its [`Recipient`]
+#[derive(Clone)]
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    /// Create a new random identity
+    pub fn generate() -> Self {
+        Self(StaticSecret::random())
+    }
+
+    /// The recipient to seal keys to for this identity
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+
+    /// Identity file contents: the secret in hex, with the recipient as a
+    /// reminder of which key it is
+    pub fn to_text(&self) -> Zeroizing<String> {
+        Zeroizing::new(format!(
+            "{}\nrecipient: {}\nsecret: {}\n",
+            IDENTITY_MAGIC,
+            self.recipient().to_hex(),
+            hex::encode(self.0.as_bytes())
+        ))
+    }
+
+    /// Parse an identity file
+    pub fn from_text(text: &str) -> Result<Self, GeneratorError> {
+        let mut fields = KeyFileFields::new(text, IDENTITY_MAGIC)?;
+        let recipient = fields.next("recipient")?;
+        let secret: Zeroizing<[u8; 32]> = Zeroizing::new(fields.next_hex("secret")?);
+        let identity = Self(StaticSecret::from(*secret));
+        if identity.recipient().to_hex() != recipient {
+            return Err(GeneratorError::InvalidKeyFile(
+                "identity secret does not match its recipient".to_string(),
+            ));
+        }
+        Ok(identity)
+    }
+}
+
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Identity").field(&self.recipient()).finish()
+    }
+}
+
+/// An expanded secret key sealed to a recipient
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealedKey {
+    onion_address: String,
+    recipient: [u8; 32],
+    ephemeral: [u8; 32],
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
+impl SealedKey {
+    /// Encrypt the expanded secret key of `onion_address` to `recipient`
+    pub fn seal(onion_address: &str, expanded: &[u8; 64], recipient: &Recipient) -> Self {
+        let ephemeral = EphemeralSecret::random();
+        let mut key = Self {
+            onion_address: onion_address.to_string(),
+            recipient: recipient.0.to_bytes(),
+            ephemeral: PublicKey::from(&ephemeral).to_bytes(),
+            nonce: rand::random(),
+            ciphertext: Vec::new(),
+        };
+        let shared = Zeroizing::new(ephemeral.diffie_hellman(&recipient.0).to_bytes());
+        key.ciphertext = encrypt_expanded(&key.cipher(&shared), &key.nonce, expanded, &key.header());
+        key
+    }
+
+    /// Address of the sealed key, as recorded in the clear
+    pub fn onion_address(&self) -> &str {
+        &self.onion_address
+    }
+
+    /// Hex recipient the key was sealed to
+    pub fn recipient(&self) -> String {
+        hex::encode(self.recipient)
+    }
+
+    /// Decrypt the expanded secret key
+    ///
+    /// Fails with [`GeneratorError::Decryption`] if the key was sealed to a
+    /// different recipient or the file was modified.
+    pub fn open(&self, identity: &Identity) -> Result<Zeroizing<[u8; 64]>, GeneratorError> {
+        if identity.recipient().0.to_bytes() != self.recipient {
+            return Err(GeneratorError::Decryption);
+        }
+        let shared = Zeroizing::new(identity.0.diffie_hellman(&PublicKey::from(self.ephemeral)).to_bytes());
+        decrypt_expanded(&self.cipher(&shared), &self.nonce, &self.ciphertext, &self.header())
+    }
+
+    /// The file contents
+    pub fn to_text(&self) -> String {
+        format!("{}ciphertext: {}\n", self.header(), hex::encode(&self.ciphertext))
+    }
+
+    /// Parse the file contents
+    pub fn from_text(text: &str) -> Result<Self, GeneratorError> {
+        let mut fields = KeyFileFields::new(text, MAGIC)?;
+        let onion_address = fields.next("onion")?.to_string();
+        let recipient = fields.next_hex("recipient")?;
+        let ephemeral = fields.next_hex("ephemeral")?;
+        fields.expect("cipher", CIPHER)?;
+        let nonce = fields.next_hex("nonce")?;
+        let ciphertext = fields.next_ciphertext()?;
+
+        Ok(Self {
+            onion_address,
+            recipient,
+            ephemeral,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Every line before the ciphertext, which is authenticated with it
+    fn header(&self) -> String {
+        format!(
+            "{}\nonion: {}\nrecipient: {}\nephemeral: {}\ncipher: {}\nnonce: {}\n",
+            MAGIC,
+            self.onion_address,
+            hex::encode(self.recipient),
+            hex::encode(self.ephemeral),
+            CIPHER,
+            hex::encode(self.nonce),
+        )
+    }
+
+    /// Cipher keyed from the X25519 shared secret
+    fn cipher(&self, shared: &[u8; 32]) -> XChaCha20Poly1305 {
+        let salt = [self.ephemeral, self.recipient].concat();
+        let mut key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(Some(&salt), shared)
+            .expand(KDF_INFO, key.as_mut())
+            .expect("32 bytes are a valid HKDF-SHA256 output length");
+        XChaCha20Poly1305::new(Key::from_slice(key.as_ref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let identity = Identity::generate();
+        let expanded: [u8; 64] = std::array::from_fn(|i| 255 - i as u8);
+        let sealed = SealedKey::seal("example.onion", &expanded, &identity.recipient());
+
+        let text = sealed.to_text();
+        assert!(text.contains("onion: example.onion\n"));
+        let parsed = SealedKey::from_text(&text).unwrap();
+        assert_eq!(parsed, sealed);
+        assert_eq!(*parsed.open(&identity).unwrap(), expanded);
+
+        // Identities survive their file format
+        let reloaded = Identity::from_text(&identity.to_text()).unwrap();
+        assert_eq!(*parsed.open(&reloaded).unwrap(), expanded);
+
+        let other = Identity::generate();
+        assert!(matches!(parsed.open(&other), Err(GeneratorError::Decryption)));
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let identity = Identity::generate();
+        let text = SealedKey::seal("first.onion", &[9; 64], &identity.recipient()).to_text();
+
+        let renamed = SealedKey::from_text(&text.replace("first.onion", "other.onion")).unwrap();
+        assert!(matches!(renamed.open(&identity), Err(GeneratorError::Decryption)));
+
+        let mut flipped = SealedKey::from_text(&text).unwrap();
+        flipped.ciphertext[0] ^= 1;
+        assert!(matches!(flipped.open(&identity), Err(GeneratorError::Decryption)));
+    }
+
+    #[test]
+    fn test_recipient_parsing() {
+        let recipient = Identity::generate().recipient();
+        assert_eq!(Recipient::from_hex(&recipient.to_hex()).unwrap(), recipient);
+        assert!(Recipient::from_hex("abcd").is_err());
+        // The identity point is of low order
+        assert!(Recipient::from_hex(&hex::encode([0u8; 32])).is_err());
+    }
+}
@@ -0,0 +1,352 @@
+//! Dictionary search: rank addresses by the words they contain
+//!
+//! Instead of committing to one exact pattern, every candidate is scored by
+//! the dictionary words found anywhere in its first 52 characters and the best
+//! few addresses are kept. The words are compiled into an Aho-Corasick
+//! automaton over 5-bit symbols, so scoring reads the public key bits directly
+//! and never builds the address string.
+//!
+//! A word of length `n` is worth `n²`, so one long word beats several short
+//! ones. A word at the very start counts three times and a word ending the 52
+//! selectable characters twice, as those are the parts people read.
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::backend::GeneratorError;
+use crate::pattern::{last_symbol, symbol_at, symbol_value, MAX_PATTERN_LEN};
+
+/// File in the output directory listing the current ranking
+pub const RANKING_FILE: &str = "wordlist_ranking.txt";
+
+/// Shorter words match almost every address and are ignored
+pub const MIN_WORD_LEN: usize = 3;
+
+/// Multiplier for a word starting the address
+const PREFIX_WEIGHT: u64 = 3;
+
+/// Multiplier for a word ending the selectable characters
+const SUFFIX_WEIGHT: u64 = 2;
+
+/// Dictionary compiled to an Aho-Corasick DFA over base32 symbols
+#[derive(Debug, Clone)]
+pub struct Wordlist {
+    words: Vec<String>,
+    /// Next state for each of the 32 symbols, per state; state 0 is the root
+    table: Vec<[u32; 32]>,
+    /// Summed weight of every word ending in this state
+    weight: Vec<u64>,
+    /// Weight of the word spelled by the path to this state, if it is one
+    own_weight: Vec<u64>,
+    depth: Vec<u8>,
+    /// Most the 52nd symbol can add from each state
+    max_last_gain: Vec<u64>,
+}
+
+impl Wordlist {
+    /// Compile a dictionary; words that are too short, too long or not
+    /// base32 are skipped
+    pub fn new<I, S>(words: I) -> Result<Self, GeneratorError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut words: Vec<String> = words
+            .into_iter()
+            .map(|word| word.as_ref().trim().to_lowercase())
+            .filter(|word| (MIN_WORD_LEN..=MAX_PATTERN_LEN).contains(&word.len()))
+            .filter(|word| word.bytes().all(|c| symbol_value(c).is_some()))
+            .collect();
+        words.sort();
+        words.dedup();
+
+        if words.is_empty() {
+            return Err(GeneratorError::InvalidWordlist(format!(
+                "no usable words (base32 only, {} to {} characters)",
+                MIN_WORD_LEN, MAX_PATTERN_LEN
+            )));
+        }
+
+        // Trie of all words; 0 doubles as "no edge" since nothing points at the root
+        let mut goto = vec![[0u32; 32]];
+        let mut own_weight = vec![0u64];
+        let mut depth = vec![0u8];
+        for word in &words {
+            let mut state = 0;
+            for c in word.bytes() {
+                let symbol = symbol_value(c).unwrap() as usize;
+                if goto[state][symbol] == 0 {
+                    goto[state][symbol] = goto.len() as u32;
+                    goto.push([0; 32]);
+                    own_weight.push(0);
+                    depth.push(depth[state] + 1);
+                }
+                state = goto[state][symbol] as usize;
+            }
+            own_weight[state] = (word.len() * word.len()) as u64;
+        }
+
+        // Breadth-first failure links turn the trie into a full DFA
+        let mut table = goto.clone();
+        let mut fail = vec![0usize; goto.len()];
+        let mut weight = vec![0u64; goto.len()];
+        let mut queue: VecDeque<usize> = goto[0].iter().filter(|&&t| t != 0).map(|&t| t as usize).collect();
+        while let Some(state) = queue.pop_front() {
+            weight[state] = own_weight[state] + weight[fail[state]];
+            for symbol in 0..32 {
+                let next = goto[state][symbol] as usize;
+                if next != 0 {
+                    fail[next] = table[fail[state]][symbol] as usize;
+                    queue.push_back(next);
+                } else {
+                    table[state][symbol] = table[fail[state]][symbol];
+                }
+            }
+        }
+
+        let mut wordlist = Self {
+            words,
+            table,
+            weight,
+            own_weight,
+            depth,
+            max_last_gain: Vec::new(),
+        };
+        wordlist.max_last_gain = (0..wordlist.table.len())
+            .map(|state| {
+                wordlist.table[state]
+                    .iter()
+                    .map(|&next| wordlist.gain(next as usize, MAX_PATTERN_LEN - 1))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        Ok(wordlist)
+    }
+
+    /// Load a dictionary file with one word per line; `#` starts a comment line
+    pub fn load(path: &Path) -> Result<Self, GeneratorError> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::new(contents.lines().filter(|line| !line.trim_start().starts_with('#')))
+    }
+
+    /// Number of usable words
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    /// Is the dictionary empty? Never true for a successfully built one
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Score of `pubkey`'s address, if it beats `threshold`
+    ///
+    /// Candidates that cannot beat the threshold are dropped before the
+    /// checksum for the 52nd character is computed.
+    #[inline]
+    pub fn score(&self, pubkey: &[u8; 32], threshold: u64) -> Option<u64> {
+        let mut state = 0;
+        let mut score = 0;
+        for i in 0..MAX_PATTERN_LEN - 1 {
+            state = self.table[state][symbol_at(pubkey, i) as usize] as usize;
+            score += self.gain(state, i);
+        }
+
+        if score + self.max_last_gain[state] <= threshold {
+            return None;
+        }
+        state = self.table[state][last_symbol(pubkey) as usize] as usize;
+        score += self.gain(state, MAX_PATTERN_LEN - 1);
+        (score > threshold).then_some(score)
+    }
+
+    /// Score gained on entering `state` after reading symbol `i`
+    #[inline]
+    fn gain(&self, state: usize, i: usize) -> u64 {
+        let mut gain = self.weight[state];
+        // Only the word spelled by the whole path so far starts at position 0
+        if self.depth[state] as usize == i + 1 {
+            gain += (PREFIX_WEIGHT - 1) * self.own_weight[state];
+        }
+        if i == MAX_PATTERN_LEN - 1 {
+            gain += (SUFFIX_WEIGHT - 1) * self.weight[state];
+        }
+        gain
+    }
+
+    /// Dictionary words in `address`, in order of position
+    pub fn words_in(&self, address: &str) -> Vec<String> {
+        let selectable = &address[..MAX_PATTERN_LEN.min(address.len())];
+        let mut found: Vec<(usize, &String)> = self
+            .words
+            .iter()
+            .filter_map(|word| selectable.find(word.as_str()).map(|pos| (pos, word)))
+            .collect();
+        found.sort();
+        found.into_iter().map(|(_, word)| word.clone()).collect()
+    }
+}
+
+/// An address kept in the ranking
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RankedAddress {
+    pub score: u64,
+    pub onion_address: String,
+    pub words: Vec<String>,
+}
+
+/// What [`Leaderboard::offer`] did with an entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Offer {
+    /// The entry did not beat the ranking, or is ranked already
+    Rejected,
+    /// The entry is ranked now, pushing out the one given, if any
+    Admitted(Option<RankedAddress>),
+}
+
+/// The best addresses found so far, best first
+///
+/// Workers check [`Leaderboard::threshold`] without locking; the lock is only
+/// taken for a candidate that beats it.
+#[derive(Debug)]
+pub struct Leaderboard {
+    capacity: usize,
+    entries: Mutex<Vec<RankedAddress>>,
+    threshold: AtomicU64,
+}
+
+impl Leaderboard {
+    /// Keep the `capacity` best addresses
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: Mutex::new(Vec::new()),
+            threshold: AtomicU64::new(0),
+        }
+    }
+
+    /// Score a candidate has to exceed to get in
+    #[inline]
+    pub fn threshold(&self) -> u64 {
+        self.threshold.load(Ordering::Relaxed)
+    }
+
+    /// Insert `entry` if it still makes the cut
+    ///
+    /// Only the ranking is updated under the lock. Callers save an entry's
+    /// files before offering it and delete those of the entry pushed out
+    /// afterwards.
+    pub fn offer(&self, entry: RankedAddress) -> Offer {
+        let mut entries = self.entries.lock().unwrap();
+        let full = entries.len() >= self.capacity;
+        if full && entry.score <= entries[entries.len() - 1].score {
+            return Offer::Rejected;
+        }
+        if entries.iter().any(|e| e.onion_address == entry.onion_address) {
+            return Offer::Rejected;
+        }
+
+        let pos = entries.partition_point(|e| e.score >= entry.score);
+        entries.insert(pos, entry);
+        let evicted = if entries.len() > self.capacity {
+            entries.pop()
+        } else {
+            None
+        };
+        if entries.len() >= self.capacity {
+            self.threshold.store(entries[entries.len() - 1].score, Ordering::Relaxed);
+        }
+        Offer::Admitted(evicted)
+    }
+
+    /// Current ranking, best first
+    pub fn entries(&self) -> Vec<RankedAddress> {
+        self.entries.lock().unwrap().clone()
+    }
+}
+
+/// Human-readable ranking, one `rank score address words` line per entry
+pub fn format_ranking(entries: &[RankedAddress]) -> String {
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, e)| format!("{}\t{}\t{}\t{}\n", i + 1, e.score, e.onion_address, e.words.join(",")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onion::pubkey_to_onion;
+    use proptest::prelude::*;
+
+    /// Straightforward string version of the scoring rules
+    fn reference_score(words: &[&str], address: &str) -> u64 {
+        let selectable = &address[..MAX_PATTERN_LEN];
+        let mut score = 0;
+        for word in words {
+            let weight = (word.len() * word.len()) as u64;
+            for pos in 0..=selectable.len() - word.len() {
+                if selectable[pos..].starts_with(word) {
+                    score += weight;
+                    if pos == 0 {
+                        score += (PREFIX_WEIGHT - 1) * weight;
+                    }
+                    if pos + word.len() == MAX_PATTERN_LEN {
+                        score += (SUFFIX_WEIGHT - 1) * weight;
+                    }
+                }
+            }
+        }
+        score
+    }
+
+    #[test]
+    fn test_skips_unusable_words() {
+        let wordlist = Wordlist::new(["cafe", "ab", "b1rd", "Shop", "shop", ""]).unwrap();
+        assert_eq!(wordlist.len(), 2);
+        assert!(Wordlist::new(["x", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_leaderboard_keeps_best() {
+        let board = Leaderboard::new(2);
+        let entry = |score: u64| RankedAddress {
+            score,
+            onion_address: format!("{}.onion", score),
+            words: Vec::new(),
+        };
+
+        assert_eq!(board.offer(entry(5)), Offer::Admitted(None));
+        assert_eq!(board.threshold(), 0);
+        assert_eq!(board.offer(entry(9)), Offer::Admitted(None));
+        assert_eq!(board.threshold(), 5);
+        assert_eq!(board.offer(entry(5)), Offer::Rejected, "tie must not get in");
+        assert_eq!(board.offer(entry(7)), Offer::Admitted(Some(entry(5))));
+        assert_eq!(board.entries().iter().map(|e| e.score).collect::<Vec<_>>(), [9, 7]);
+        assert_eq!(board.threshold(), 7);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_agrees_with_reference_score(pubkey in any::<[u8; 32]>(), cut in 0..44usize) {
+            // Mix words taken from the address itself with some that overlap
+            // each other and rarely occur
+            let onion = pubkey_to_onion(&pubkey);
+            let own: Vec<&str> = vec![&onion[..4], &onion[cut..cut + 3], &onion[cut + 1..cut + 8], &onion[48..52]];
+            let words: Vec<&str> = own.into_iter().chain(["aaa", "abab", "bab", "zzz"]).collect();
+
+            let mut unique = words.clone();
+            unique.sort();
+            unique.dedup();
+
+            let wordlist = Wordlist::new(&unique).unwrap();
+            let expected = reference_score(&unique, &onion);
+            prop_assert_eq!(wordlist.score(&pubkey, 0), (expected > 0).then_some(expected));
+            prop_assert_eq!(wordlist.score(&pubkey, expected), None);
+        }
+    }
+}